@@ -3,13 +3,211 @@ use chrono::{DateTime, Utc, TimeZone};
 use uuid::Uuid;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Read, Write};
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use crate::mesh::signature_manager::SignatureStatus;
+
+pub const DEFAULT_TTL: u8 = 7;
+pub const EMERGENCY_TTL: u8 = 15;
+
+const PACKET_FLAG_HAS_ROUTE: u8 = 0x01;
+const PACKET_FLAG_HAS_RECIPIENT: u8 = 0x02;
+const PACKET_FLAG_HAS_SIGNATURE: u8 = 0x04;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Envelope every packet travels in. `ttl`, `hop_count` and `route` are
+/// rewritten by each relay; everything else is fixed by the original sender.
+#[derive(Debug, Clone)]
+pub struct BitchatPacket {
+    pub message_type: u8,
+    pub ttl: u8,
+    pub hop_count: u8,
+    pub sender_id: String,
+    pub recipient_id: Option<String>,
+    pub route: Option<Vec<String>>,
+    pub payload: Vec<u8>,
+    /// Ed25519 signature over `signable_bytes`, appended after the payload.
+    pub signature: Option<Vec<u8>>,
+}
+
+impl BitchatPacket {
+    pub fn new(message_type: u8, sender_id: String, payload: Vec<u8>) -> Self {
+        BitchatPacket {
+            message_type,
+            ttl: DEFAULT_TTL,
+            hop_count: 0,
+            sender_id,
+            recipient_id: None,
+            route: None,
+            payload,
+            signature: None,
+        }
+    }
+
+    pub fn with_recipient(mut self, recipient_id: String) -> Self {
+        self.recipient_id = Some(recipient_id);
+        self
+    }
+
+    /// Asks every relay to append its peer ID, traceroute style.
+    pub fn with_route_tracing(mut self) -> Self {
+        self.route = Some(Vec::new());
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns the copy of this packet a relay should forward, or `None` once
+    /// the TTL is exhausted.
+    pub fn relayed(&self, relay_id: &str) -> Option<Self> {
+        if self.ttl <= 1 {
+            return None;
+        }
+        let mut packet = self.clone();
+        packet.ttl -= 1;
+        packet.hop_count = packet.hop_count.saturating_add(1);
+        if let Some(route) = &mut packet.route {
+            route.push(relay_id.to_string());
+        }
+        Some(packet)
+    }
+
+    /// The fields a signature covers: everything relays are not allowed to touch.
+    pub fn signable_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 64);
+        bytes.push(self.message_type);
+        bytes.push(self.sender_id.len() as u8);
+        bytes.extend_from_slice(self.sender_id.as_bytes());
+        match &self.recipient_id {
+            Some(recipient_id) => {
+                bytes.push(recipient_id.len() as u8);
+                bytes.extend_from_slice(recipient_id.as_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(self.payload.len() + 64);
+        let mut flags: u8 = 0;
+
+        if self.route.is_some() { flags |= PACKET_FLAG_HAS_ROUTE; }
+        if self.recipient_id.is_some() { flags |= PACKET_FLAG_HAS_RECIPIENT; }
+        if self.signature.is_some() { flags |= PACKET_FLAG_HAS_SIGNATURE; }
+
+        buffer.write_u8(self.message_type)?;
+        buffer.write_u8(self.ttl)?;
+        buffer.write_u8(self.hop_count)?;
+        buffer.write_u8(flags)?;
+
+        let sender_bytes = self.sender_id.as_bytes();
+        buffer.write_u8(sender_bytes.len() as u8)?;
+        buffer.write_all(sender_bytes)?;
+
+        if let Some(recipient_id) = &self.recipient_id {
+            let bytes = recipient_id.as_bytes();
+            buffer.write_u8(bytes.len() as u8)?;
+            buffer.write_all(bytes)?;
+        }
+
+        if let Some(route) = &self.route {
+            buffer.write_u8(route.len() as u8)?;
+            for hop in route {
+                let bytes = hop.as_bytes();
+                buffer.write_u8(bytes.len() as u8)?;
+                buffer.write_all(bytes)?;
+            }
+        }
+
+        buffer.write_u16::<BigEndian>(self.payload.len() as u16)?;
+        buffer.write_all(&self.payload)?;
+
+        if let Some(signature) = &self.signature {
+            if signature.len() != SIGNATURE_LEN {
+                bail!("Bad signature length");
+            }
+            buffer.write_all(signature)?;
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let message_type = cursor.read_u8()?;
+        let ttl = cursor.read_u8()?;
+        let hop_count = cursor.read_u8()?;
+        let flags = cursor.read_u8()?;
+        let has_route = (flags & PACKET_FLAG_HAS_ROUTE) != 0;
+        let has_recipient = (flags & PACKET_FLAG_HAS_RECIPIENT) != 0;
+        let has_signature = (flags & PACKET_FLAG_HAS_SIGNATURE) != 0;
+
+        let sender_len = cursor.read_u8()? as usize;
+        let mut sender_bytes = vec![0; sender_len];
+        cursor.read_exact(&mut sender_bytes)?;
+        let sender_id = String::from_utf8(sender_bytes)?;
+
+        let recipient_id = if has_recipient {
+            let len = cursor.read_u8()? as usize;
+            let mut bytes = vec![0; len];
+            cursor.read_exact(&mut bytes)?;
+            Some(String::from_utf8(bytes)?)
+        } else {
+            None
+        };
+
+        let route = if has_route {
+            let count = cursor.read_u8()? as usize;
+            let mut hops = Vec::with_capacity(count);
+            for _ in 0..count {
+                let len = cursor.read_u8()? as usize;
+                let mut bytes = vec![0; len];
+                cursor.read_exact(&mut bytes)?;
+                hops.push(String::from_utf8(bytes)?);
+            }
+            Some(hops)
+        } else {
+            None
+        };
+
+        let payload_len = cursor.read_u16::<BigEndian>()? as usize;
+        let mut payload = vec![0; payload_len];
+        cursor.read_exact(&mut payload)?;
+
+        let signature = if has_signature {
+            let mut bytes = vec![0; SIGNATURE_LEN];
+            cursor.read_exact(&mut bytes)?;
+            Some(bytes)
+        } else {
+            None
+        };
+
+        if cursor.position() as usize != data.len() {
+            bail!("Trailing bytes after packet payload");
+        }
+
+        Ok(BitchatPacket {
+            message_type,
+            ttl,
+            hop_count,
+            sender_id,
+            recipient_id,
+            route,
+            payload,
+            signature,
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DeliveryStatus {
     Sending,
     Sent,
-    Delivered { to: String, at: DateTime<Utc> },
+    Delivered { to: String, at: DateTime<Utc>, hop_count: u8 },
     Read { by: String, at: DateTime<Utc> },
     Failed { reason: String },
     PartiallyDelivered { reached: u32, total: u32 },
@@ -20,7 +218,9 @@ impl DeliveryStatus {
         match self {
             DeliveryStatus::Sending => "Sending...".to_string(),
             DeliveryStatus::Sent => "Sent".to_string(),
-            DeliveryStatus::Delivered { to, at: _ } => format!("Delivered to {}", to),
+            DeliveryStatus::Delivered { to, at: _, hop_count } => {
+                format!("Delivered to {} ({} hops)", to, hop_count)
+            }
             DeliveryStatus::Read { by, at: _ } => format!("Read by {}", by),
             DeliveryStatus::Failed { reason } => format!("Failed: {}", reason),
             DeliveryStatus::PartiallyDelivered { reached, total } => {
@@ -46,6 +246,28 @@ pub struct BitchatMessage {
     pub encrypted_content: Option<Vec<u8>>,
     pub is_encrypted: bool,
     pub delivery_status: Option<DeliveryStatus>,
+    /// Filled in from the packet envelope on receipt; never part of the payload.
+    #[serde(default)]
+    pub hop_count: Option<u8>,
+    #[serde(default)]
+    pub route: Option<Vec<String>>,
+    #[serde(default)]
+    pub is_emergency: bool,
+    /// The sender's static key matches a fingerprint we verified out of band.
+    #[serde(default)]
+    pub sender_verified: bool,
+    /// `None` for messages we did not receive over the mesh, e.g. our own.
+    #[serde(default)]
+    pub signature_status: Option<SignatureStatus>,
+    /// Local name of the private group the message was sent to.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Arrived inside a sealed-sender envelope.
+    #[serde(default)]
+    pub sealed: bool,
+    /// Replayed by a peer's sync response rather than received live.
+    #[serde(default)]
+    pub synced: bool,
 }
 
 impl BitchatMessage {
@@ -65,6 +287,14 @@ impl BitchatMessage {
             encrypted_content: None,
             is_encrypted: false,
             delivery_status: Some(DeliveryStatus::Sending),
+            hop_count: None,
+            route: None,
+            is_emergency: false,
+            sender_verified: false,
+            signature_status: None,
+            group: None,
+            sealed: false,
+            synced: false,
         }
     }
 
@@ -152,7 +382,7 @@ impl BitchatMessage {
         let is_encrypted = (flags & 0x80) != 0;
 
         let timestamp_millis = cursor.read_i64::<BigEndian>()?;
-        let timestamp = Utc.timestamp_millis_opt(timestamp_millis).unwrap();
+        let timestamp = Utc.timestamp_millis_opt(timestamp_millis).single().ok_or_else(|| anyhow!("Bad timestamp"))?;
 
         let id_len = cursor.read_u8()? as usize;
         let mut id_bytes = vec![0; id_len];
@@ -243,6 +473,14 @@ impl BitchatMessage {
             encrypted_content,
             is_encrypted,
             delivery_status: None,
+            hop_count: None,
+            route: None,
+            is_emergency: false,
+            sender_verified: false,
+            signature_status: None,
+            group: None,
+            sealed: false,
+            synced: false,
         })
     }
 }
@@ -270,6 +508,8 @@ impl DeliveryAck {
     }
 }
 
+/// Not sent yet; the UI has no notion of a message being read.
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReadReceipt {
     pub original_message_id: String,
@@ -279,6 +519,7 @@ pub struct ReadReceipt {
     pub timestamp: DateTime<Utc>,
 }
 
+#[allow(dead_code)]
 impl ReadReceipt {
     pub fn new(original_message_id: String, reader_id: String, reader_nickname: String) -> Self {
         ReadReceipt {
@@ -290,3 +531,203 @@ impl ReadReceipt {
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingRequest {
+    pub ping_id: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl PingRequest {
+    pub fn new() -> Self {
+        PingRequest {
+            ping_id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Echoes back how the request travelled, so the originator sees both legs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingReply {
+    pub ping_id: String,
+    pub request_hop_count: u8,
+    pub request_route: Option<Vec<String>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl PingReply {
+    pub fn new(ping_id: String, request_hop_count: u8, request_route: Option<Vec<String>>) -> Self {
+        PingReply {
+            ping_id,
+            request_hop_count,
+            request_route,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelMember {
+    pub fingerprint: String,
+    pub nickname: String,
+}
+
+/// Everything members of a channel need to agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChannelState {
+    /// Fingerprint of the owner's Ed25519 identity key.
+    pub owner: String,
+    pub topic: Option<String>,
+    /// `None` when the channel has no password.
+    pub key_commitment: Option<Vec<u8>>,
+    pub banned: Vec<ChannelMember>,
+}
+
+/// The change an owner made; the new values themselves are in the state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ModerationAction {
+    SetPassword,
+    SetTopic,
+    Kick(ChannelMember),
+    Ban(ChannelMember),
+    Unban(ChannelMember),
+    TransferOwnership(ChannelMember),
+    /// Publishes the owner's state as it is: sent by whoever creates a channel
+    /// and by a new owner taking over after a transfer.
+    Claim,
+}
+
+/// A channel's full state, signed by the owner's identity key. A higher
+/// sequence number supersedes a lower one, so any member can replay the
+/// latest announce to bring newcomers up to date.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelAnnounce {
+    pub channel: String,
+    pub state: ChannelState,
+    pub action: ModerationAction,
+    pub sequence: u64,
+    /// Ed25519 identity key of the owner who signed this.
+    pub signer_key: Vec<u8>,
+    pub timestamp: DateTime<Utc>,
+    pub signature: Vec<u8>,
+}
+
+impl ChannelAnnounce {
+    pub fn signable_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&(
+            &self.channel,
+            &self.state,
+            &self.action,
+            self.sequence,
+            &self.signer_key,
+            self.timestamp,
+        ))?)
+    }
+}
+
+/// Announce payload: who we are and the key our broadcasts are signed with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerAnnounce {
+    pub nickname: String,
+    pub signing_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupMember {
+    pub fingerprint: String,
+    pub nickname: String,
+}
+
+/// A member's sender key: the current state of their message chain and the
+/// key their group messages are signed with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SenderKeyDistribution {
+    pub group_id: String,
+    pub key_id: u32,
+    pub chain_key: [u8; 32],
+    pub iteration: u32,
+    pub verifying_key: [u8; 32],
+}
+
+/// Group bookkeeping, only ever sent inside a pairwise ratchet session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GroupControl {
+    Membership {
+        group_id: String,
+        name: String,
+        owner: String,
+        members: Vec<GroupMember>,
+        epoch: u32,
+    },
+    SenderKey(SenderKeyDistribution),
+}
+
+/// A group message, encrypted once for every member. Inside the group the
+/// sender key ID identifies the author; the packet carrying it still names
+/// the sender's peer ID in clear, so outsiders can see who is talking, just
+/// not what is said.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupCiphertext {
+    pub group_id: String,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupCiphertext {
+    pub fn signable_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.group_id.len() + self.ciphertext.len() + 9);
+        bytes.push(self.group_id.len() as u8);
+        bytes.extend_from_slice(self.group_id.as_bytes());
+        bytes.extend_from_slice(&self.key_id.to_be_bytes());
+        bytes.extend_from_slice(&self.iteration.to_be_bytes());
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+}
+
+/// A packet encrypted to its recipient's static key. Relays see only the
+/// envelope's routing tag; the sender's identity is inside.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedEnvelope {
+    /// Throwaway P-256 key, compressed.
+    pub ephemeral_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// What a hop finds inside its layer of an onion-routed packet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnionLayer {
+    /// Peer to pass `payload`, the next layer, on to. `None` at the last hop,
+    /// where `payload` is the packet itself.
+    pub next_hop: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let mut message = BitchatMessage::new("alice".to_string(), "hello".to_string());
+        message.channel = Some("#general".to_string());
+        let decoded = BitchatMessage::from_binary_payload(&message.to_binary_payload().unwrap()).unwrap();
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.sender, "alice");
+        assert_eq!(decoded.content, "hello");
+        assert_eq!(decoded.channel.as_deref(), Some("#general"));
+        assert_eq!(decoded.timestamp.timestamp_millis(), message.timestamp.timestamp_millis());
+    }
+
+    #[test]
+    fn out_of_range_timestamps_are_an_error() {
+        let message = BitchatMessage::new("alice".to_string(), "hello".to_string());
+        let mut payload = message.to_binary_payload().unwrap();
+        // The timestamp follows the flags byte.
+        payload[1..9].copy_from_slice(&i64::MAX.to_be_bytes());
+        assert!(BitchatMessage::from_binary_payload(&payload).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+use crate::mesh::invite::{DEFAULT_LIFETIME_HOURS, TOKEN_PREFIX};
+
+/// Slash commands typed into the input box and handed to the mesh service.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    SendMessage(String),
+    JoinChannel { channel: String, password: Option<String> },
+    /// Issues an invite token for a channel, redeemable for the given hours.
+    Invite { channel: String, hours: i64 },
+    RedeemInvite(String),
+    /// Sets the password of the current channel.
+    SetChannelPassword(String),
+    SetTopic(String),
+    /// Channel moderation, allowed only for the current channel's owner.
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    TransferChannel(String),
+    PrivateMessage { nickname: String, content: String },
+    Emergency(String),
+    Ping(String),
+    Trace(String),
+    /// Shows a peer's fingerprint, or marks them verified when the fingerprint
+    /// read out by the peer is given and matches.
+    Verify { nickname: String, fingerprint: Option<String> },
+    CreateGroup(String),
+    AddGroupMember { group: String, nickname: String },
+    RemoveGroupMember { group: String, nickname: String },
+    GroupMessage { group: String, content: String },
+    /// Destroys all keys, contacts and history and starts over as a new identity.
+    Panic,
+    Block(String),
+    Unblock(String),
+    /// Trusts or refuses a peer's changed key, which is held until then.
+    AcceptKey(String),
+    RejectKey(String),
+    /// Parsed with an empty passphrase; the UI asks for it before sending.
+    ExportBackup { path: PathBuf, passphrase: String },
+    ImportBackup { path: PathBuf, passphrase: String },
+    /// Shows recent log lines; the UI answers this itself.
+    ShowLog,
+}
+
+impl Command {
+    /// Parses a line starting with `/`. Returns an error text suitable for
+    /// showing to the user when the command is unknown or malformed.
+    pub fn parse(input: &str) -> Result<Command, String> {
+        let mut parts = input.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or_default();
+        let args = parts.next().unwrap_or_default().trim();
+
+        match name {
+            "/j" | "/join" if args.starts_with(TOKEN_PREFIX) => Ok(Command::RedeemInvite(args.to_string())),
+            "/j" | "/join" => {
+                let (channel, password) = args.split_once(' ').unwrap_or((args, ""));
+                let password = password.trim();
+                if !channel.starts_with('#') || channel.len() < 2 {
+                    return Err(format!("Usage: {} #channel [password]", name));
+                }
+                Ok(Command::JoinChannel {
+                    channel: channel.to_string(),
+                    password: (!password.is_empty()).then(|| password.to_string()),
+                })
+            }
+            "/invite" => {
+                let (channel, hours) = args.split_once(' ').unwrap_or((args, ""));
+                let hours = match hours.trim() {
+                    "" => Ok(DEFAULT_LIFETIME_HOURS),
+                    hours => hours.parse(),
+                };
+                match hours {
+                    Ok(hours) if channel.starts_with('#') && channel.len() >= 2 => {
+                        Ok(Command::Invite { channel: channel.to_string(), hours })
+                    }
+                    _ => Err("Usage: /invite #channel [hours]".to_string()),
+                }
+            }
+            "/pass" if !args.is_empty() => Ok(Command::SetChannelPassword(args.to_string())),
+            "/pass" => Err("Usage: /pass <password>".to_string()),
+            "/topic" if !args.is_empty() => Ok(Command::SetTopic(args.to_string())),
+            "/topic" => Err("Usage: /topic <text>".to_string()),
+            "/kick" => Ok(Command::Kick(Self::nickname_arg(name, args)?)),
+            "/ban" => Ok(Command::Ban(Self::nickname_arg(name, args)?)),
+            "/unban" => Ok(Command::Unban(Self::nickname_arg(name, args)?)),
+            "/transfer" => Ok(Command::TransferChannel(Self::nickname_arg(name, args)?)),
+            "/msg" => {
+                let (nickname, content) = args.split_once(' ').unwrap_or((args, ""));
+                let content = content.trim();
+                if content.is_empty() {
+                    return Err("Usage: /msg <nick> <message>".to_string());
+                }
+                Ok(Command::PrivateMessage {
+                    nickname: Self::nickname_arg(name, nickname)?,
+                    content: content.to_string(),
+                })
+            }
+            "/ping" => Ok(Command::Ping(Self::nickname_arg(name, args)?)),
+            "/trace" => Ok(Command::Trace(Self::nickname_arg(name, args)?)),
+            "/verify" => {
+                let (nickname, fingerprint) = args.split_once(' ').unwrap_or((args, ""));
+                let fingerprint = fingerprint.trim();
+                Ok(Command::Verify {
+                    nickname: Self::nickname_arg(name, nickname)?,
+                    fingerprint: (!fingerprint.is_empty()).then(|| fingerprint.to_string()),
+                })
+            }
+            "/group" => {
+                let words: Vec<&str> = args.split_whitespace().collect();
+                match words.as_slice() {
+                    ["create", group] => Ok(Command::CreateGroup(group.to_string())),
+                    ["add", group, nickname] => Ok(Command::AddGroupMember {
+                        group: group.to_string(),
+                        nickname: Self::nickname_arg(name, nickname)?,
+                    }),
+                    ["remove", group, nickname] => Ok(Command::RemoveGroupMember {
+                        group: group.to_string(),
+                        nickname: Self::nickname_arg(name, nickname)?,
+                    }),
+                    _ => Err("Usage: /group create <name> | /group add <name> <nick> | /group remove <name> <nick>"
+                        .to_string()),
+                }
+            }
+            "/g" => {
+                let (group, content) = args.split_once(' ').unwrap_or((args, ""));
+                let content = content.trim();
+                if group.is_empty() || content.is_empty() {
+                    return Err("Usage: /g <group> <message>".to_string());
+                }
+                Ok(Command::GroupMessage { group: group.to_string(), content: content.to_string() })
+            }
+            "/panic" => Ok(Command::Panic),
+            "/block" => Ok(Command::Block(Self::nickname_arg(name, args)?)),
+            "/unblock" => Ok(Command::Unblock(Self::nickname_arg(name, args)?)),
+            "/accept" => Ok(Command::AcceptKey(Self::nickname_arg(name, args)?)),
+            "/reject" => Ok(Command::RejectKey(Self::nickname_arg(name, args)?)),
+            "/export" if !args.is_empty() => Ok(Command::ExportBackup {
+                path: PathBuf::from(args),
+                passphrase: String::new(),
+            }),
+            "/export" => Err("Usage: /export <file>".to_string()),
+            "/import" if !args.is_empty() => Ok(Command::ImportBackup {
+                path: PathBuf::from(args),
+                passphrase: String::new(),
+            }),
+            "/import" => Err("Usage: /import <file>".to_string()),
+            "/emergency" if !args.is_empty() => Ok(Command::Emergency(args.to_string())),
+            "/emergency" => Err("Usage: /emergency <message>".to_string()),
+            "/log" => Ok(Command::ShowLog),
+            _ => Err(format!("Unknown command: {}", name)),
+        }
+    }
+
+    /// Whether the UI still has to ask for a passphrase before sending this.
+    pub fn needs_passphrase(&self) -> bool {
+        matches!(self, Command::ExportBackup { .. } | Command::ImportBackup { .. })
+    }
+
+    pub fn with_passphrase(self, passphrase: String) -> Command {
+        match self {
+            Command::ExportBackup { path, .. } => Command::ExportBackup { path, passphrase },
+            Command::ImportBackup { path, .. } => Command::ImportBackup { path, passphrase },
+            other => other,
+        }
+    }
+
+    fn nickname_arg(name: &str, args: &str) -> Result<String, String> {
+        let nickname = args.trim_start_matches('@');
+        if nickname.is_empty() || nickname.contains(' ') {
+            return Err(format!("Usage: {} <nick>", name));
+        }
+        Ok(nickname.to_string())
+    }
+}
@@ -1,11 +1,16 @@
 mod bitchat_packet;
+mod commands;
 mod ui;
 mod mesh;
 
-use mesh::service::BluetoothMeshService;
+use commands::Command;
+use mesh::config::{MeshConfig, USAGE};
+use mesh::contact_store::ContactStore;
+use mesh::identity::Identity;
+use mesh::service::{BluetoothMeshService, MeshEvent};
+use mesh::storage::{self, Credential};
 use std::panic;
 use tokio::sync::mpsc;
-use crate::bitchat_packet::BitchatMessage;
 
 #[tokio::main]
 async fn main() {
@@ -16,11 +21,48 @@ async fn main() {
         original_hook(panic_info);
     }));
 
-    let (tx, rx) = mpsc::channel::<BitchatMessage>(100);
-    let mesh_service = BluetoothMeshService::new(tx);
+    let config = match MeshConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = unlock_storage(&config) {
+        eprintln!("Failed to unlock {}: {:#}", config.data_dir.display(), e);
+        std::process::exit(1);
+    }
+
+    let identity = match Identity::load_or_create(&config.data_dir) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("Failed to load identity: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let contact_store = match ContactStore::load(&config.data_dir) {
+        Ok(contact_store) => contact_store,
+        Err(e) => {
+            eprintln!("Failed to load contacts: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let (tx, rx) = mpsc::channel::<MeshEvent>(100);
+    mesh::logger::init(tx.clone());
+    let mesh_service = BluetoothMeshService::new(config, identity, contact_store, tx);
     BluetoothMeshService::start(mesh_service.clone()).await.unwrap();
 
-    let result = ui::run_ui(rx).await;
+    let (command_tx, mut command_rx) = mpsc::channel::<Command>(16);
+    let command_service = mesh_service.clone();
+    tokio::spawn(async move {
+        while let Some(command) = command_rx.recv().await {
+            BluetoothMeshService::handle_command(command_service.clone(), command);
+        }
+    });
+
+    let result = ui::run_ui(rx, command_tx).await;
 
     BluetoothMeshService::stop(mesh_service.clone()).unwrap();
 
@@ -28,3 +70,37 @@ async fn main() {
         println!("Error: {}", e);
     }
 }
+
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// Unlocks the data directory with the key file if one was given, otherwise
+/// asks for the passphrase (twice on first launch, when it is being chosen).
+fn unlock_storage(config: &MeshConfig) -> anyhow::Result<()> {
+    if let Some(key_file) = &config.key_file {
+        return storage::unlock(&config.data_dir, &Credential::KeyFile(key_file.clone()));
+    }
+
+    if !storage::vault_exists(&config.data_dir) {
+        println!("Choose a passphrase to encrypt your keys and contacts at rest.");
+        let passphrase = ui::read_passphrase("New passphrase: ")?;
+        if passphrase.is_empty() {
+            anyhow::bail!("The passphrase must not be empty");
+        }
+        if ui::read_passphrase("Repeat passphrase: ")? != passphrase {
+            anyhow::bail!("Passphrases do not match");
+        }
+        return storage::unlock(&config.data_dir, &Credential::Passphrase(passphrase));
+    }
+
+    let mut attempt = 1;
+    loop {
+        let passphrase = ui::read_passphrase("Passphrase: ")?;
+        match storage::unlock(&config.data_dir, &Credential::Passphrase(passphrase)) {
+            Err(e) if attempt < PASSPHRASE_ATTEMPTS => {
+                eprintln!("{:#}", e);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use super::contact_store::Contact;
+use super::identity::Identity;
+use super::storage::write_atomic;
+
+const BACKUP_MAGIC: &[u8; 4] = b"BCBK";
+const BACKUP_VERSION: u8 = 1;
+/// magic, version, salt, Argon2 memory and passes, nonce.
+const HEADER_LEN: usize = 4 + 1 + 16 + 4 + 4 + 12;
+const KDF_MEMORY_KIB: u32 = 64 * 1024;
+const KDF_ITERATIONS: u32 = 3;
+const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 16;
+const MIN_PASSPHRASE_LEN: usize = 8;
+
+/// Everything needed to carry an identity to another machine. This client
+/// has no favourites list, so there are none to carry.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub static_secret: Vec<u8>,
+    pub signing_key: [u8; 32],
+    pub contacts: Vec<(String, Contact)>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Writes `[header][AES-GCM ciphertext of the bincode backup]`. The header is
+/// authenticated along with the contents, so the GCM tag doubles as the
+/// integrity check for the whole file.
+pub fn export(path: &Path, passphrase: &str, identity: &Identity, contacts: Vec<(String, Contact)>) -> Result<()> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        bail!("Use a backup passphrase of at least {} characters", MIN_PASSPHRASE_LEN);
+    }
+    let backup = Backup {
+        static_secret: identity.static_secret().to_bytes().to_vec(),
+        signing_key: identity.signing_key().to_bytes(),
+        contacts,
+        created_at: Utc::now(),
+    };
+
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(BACKUP_MAGIC);
+    header.push(BACKUP_VERSION);
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&KDF_MEMORY_KIB.to_be_bytes());
+    header.extend_from_slice(&KDF_ITERATIONS.to_be_bytes());
+    header.extend_from_slice(&nonce);
+
+    let cipher = backup_cipher(passphrase, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &bincode::serialize(&backup)?, aad: &header })
+        .map_err(|_| anyhow!("Failed to encrypt backup"))?;
+    header.extend(ciphertext);
+    write_atomic(path, &header)
+}
+
+pub fn import(path: &Path, passphrase: &str) -> Result<Backup> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(BACKUP_MAGIC) {
+        bail!("{} is not a bitchat backup", path.display());
+    }
+    if bytes[4] != BACKUP_VERSION {
+        bail!("Backup version {} is not supported by this version of bitchat", bytes[4]);
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+    let salt: [u8; 16] = header[5..21].try_into()?;
+    let memory_kib = u32::from_be_bytes(header[21..25].try_into()?);
+    let iterations = u32::from_be_bytes(header[25..29].try_into()?);
+    let nonce = &header[29..41];
+    if memory_kib > MAX_KDF_MEMORY_KIB {
+        bail!("Backup asks for an unreasonable amount of memory to unlock");
+    }
+    if iterations > MAX_KDF_ITERATIONS {
+        bail!("Backup asks for an unreasonable number of passes to unlock");
+    }
+
+    let cipher = backup_cipher(passphrase, &salt, memory_kib, iterations)?;
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+        .map_err(|_| anyhow!("Wrong passphrase, or the backup is damaged"))?;
+    bincode::deserialize(&plaintext).context("Backup contents are corrupt")
+}
+
+fn backup_cipher(passphrase: &str, salt: &[u8; 16], memory_kib: u32, iterations: u32) -> Result<Aes256Gcm> {
+    let params = Params::new(memory_kib, iterations, 1, Some(32))
+        .map_err(|e| anyhow!("Bad key derivation parameters in backup: {}", e))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use super::identity::normalize_fingerprint;
+use super::storage::{read_private, wipe_private, write_private};
+
+const BLOCKLIST_FILE: &str = "blocklist.bin";
+
+/// Identities whose traffic we drop, keyed by fingerprint so a block holds
+/// through nickname changes and peer ID rotation. Peer IDs are tied to a
+/// blocked fingerprint as handshakes authenticate them, so a blocked peer
+/// who rotated to a new ID gets through until that ID completes one.
+pub struct BlockList {
+    path: Option<PathBuf>,
+    /// Fingerprint to the nickname it was blocked under.
+    blocked: HashMap<String, String>,
+    /// Peer IDs known to belong to a blocked identity, or blocked for this run
+    /// only when the peer never completed a handshake.
+    blocked_peer_ids: HashMap<String, Option<String>>,
+    relay_blocked: bool,
+}
+
+impl BlockList {
+    pub fn new(relay_blocked: bool) -> Self {
+        BlockList {
+            path: None,
+            blocked: HashMap::new(),
+            blocked_peer_ids: HashMap::new(),
+            relay_blocked,
+        }
+    }
+
+    /// Restores the blocklist saved in `data_dir` and saves there from now on.
+    pub fn load(&mut self, data_dir: &Path) -> Result<()> {
+        let path = data_dir.join(BLOCKLIST_FILE);
+        self.path = Some(path.clone());
+        if !path.exists() {
+            return Ok(());
+        }
+        let bytes = read_private(&path)?;
+        self.blocked = bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+        Ok(())
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = bincode::serialize(&self.blocked)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| write_private(path, &bytes));
+        if let Err(e) = result {
+            log::warn!("Failed to save blocklist: {}", e);
+        }
+    }
+
+    pub fn block(&mut self, fingerprint: &str, nickname: &str, peer_id: &str) {
+        let fingerprint = normalize_fingerprint(fingerprint);
+        self.blocked.insert(fingerprint.clone(), nickname.to_string());
+        self.blocked_peer_ids.insert(peer_id.to_string(), Some(fingerprint));
+        self.save();
+    }
+
+    /// Blocks a peer we could not identify, until it goes away.
+    pub fn block_peer_id(&mut self, peer_id: &str) {
+        self.blocked_peer_ids.insert(peer_id.to_string(), None);
+    }
+
+    /// Lifts blocks for the peer ID or any identity blocked under `nickname`.
+    /// Returns whether anything was unblocked.
+    pub fn unblock(&mut self, nickname: &str, peer_id: Option<&str>) -> bool {
+        let mut fingerprints: Vec<String> = self
+            .blocked
+            .iter()
+            .filter(|(_, blocked_nickname)| *blocked_nickname == nickname)
+            .map(|(fp, _)| fp.clone())
+            .collect();
+        let mut unblocked = false;
+        if let Some(entry) = peer_id.and_then(|id| self.blocked_peer_ids.remove(id)) {
+            fingerprints.extend(entry);
+            unblocked = true;
+        }
+        for fingerprint in &fingerprints {
+            unblocked |= self.blocked.remove(fingerprint).is_some();
+        }
+        self.blocked_peer_ids
+            .retain(|_, fp| !fp.as_ref().is_some_and(|fp| fingerprints.contains(fp)));
+        self.save();
+        unblocked
+    }
+
+    /// Records that `peer_id` authenticated as `fingerprint`, blocking the
+    /// peer ID if the identity is blocked. Returns whether it is.
+    pub fn bind_peer(&mut self, peer_id: &str, fingerprint: &str) -> bool {
+        let fingerprint = normalize_fingerprint(fingerprint);
+        if !self.blocked.contains_key(&fingerprint) {
+            return false;
+        }
+        self.blocked_peer_ids.insert(peer_id.to_string(), Some(fingerprint));
+        true
+    }
+
+    pub fn is_blocked(&self, peer_id: &str) -> bool {
+        self.blocked_peer_ids.contains_key(peer_id)
+    }
+
+    pub fn is_blocked_fingerprint(&self, fingerprint: &str) -> bool {
+        self.blocked.contains_key(&normalize_fingerprint(fingerprint))
+    }
+
+    /// Whether some blocked identity was blocked under this nickname.
+    pub fn is_blocked_nickname(&self, nickname: &str) -> bool {
+        self.blocked.values().any(|blocked_nickname| blocked_nickname == nickname)
+    }
+
+    /// Whether blocked peers' packets are still passed along for others.
+    pub fn relays_blocked(&self) -> bool {
+        self.relay_blocked
+    }
+
+    /// Forgets every block, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
+        self.blocked.clear();
+        self.blocked_peer_ids.clear();
+        match self.path.take() {
+            Some(path) => wipe_private(&path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        self.save();
+        self.blocked_peer_ids.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::test_support::{take_turn, TestDir};
+    use super::super::storage::unlock;
+
+    const MALLORY: &str = "AB:CD:EF:01";
+
+    #[test]
+    fn blocks_follow_the_identity_to_new_peer_ids() {
+        let mut block_list = BlockList::new(false);
+        block_list.block(MALLORY, "mallory", "peer-1");
+        assert!(block_list.is_blocked("peer-1"));
+        assert!(block_list.is_blocked_fingerprint("abcdef01"));
+        assert!(block_list.is_blocked_nickname("mallory"));
+
+        // A rotated peer ID gets through until its handshake ties it to the block.
+        assert!(!block_list.is_blocked("peer-2"));
+        assert!(block_list.bind_peer("peer-2", "ab cd ef 01"));
+        assert!(block_list.is_blocked("peer-2"));
+        assert!(!block_list.bind_peer("peer-3", "12:34"));
+        assert!(!block_list.is_blocked("peer-3"));
+    }
+
+    #[test]
+    fn unblocking_a_nickname_frees_every_peer_id_of_the_identity() {
+        let mut block_list = BlockList::new(false);
+        block_list.block(MALLORY, "mallory", "peer-1");
+        block_list.bind_peer("peer-2", MALLORY);
+        block_list.block("12:34", "trudy", "peer-3");
+
+        assert!(block_list.unblock("mallory", None));
+        assert!(!block_list.is_blocked("peer-1"));
+        assert!(!block_list.is_blocked("peer-2"));
+        assert!(!block_list.is_blocked_fingerprint(MALLORY));
+        assert!(block_list.is_blocked("peer-3"));
+        assert!(!block_list.unblock("mallory", None));
+    }
+
+    #[test]
+    fn unblocking_a_peer_id_lifts_its_identity_too() {
+        let mut block_list = BlockList::new(false);
+        block_list.block(MALLORY, "mallory", "peer-1");
+        block_list.bind_peer("peer-2", MALLORY);
+        assert!(block_list.unblock("someone-else", Some("peer-1")));
+        assert!(!block_list.is_blocked("peer-2"));
+        assert!(!block_list.is_blocked_fingerprint(MALLORY));
+    }
+
+    #[test]
+    fn unidentified_peers_are_blocked_for_this_run_only() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+        let mut block_list = BlockList::new(false);
+        block_list.load(&dir.0).unwrap();
+        block_list.block_peer_id("peer-1");
+        block_list.block(MALLORY, "mallory", "peer-2");
+        assert!(block_list.is_blocked("peer-1"));
+        block_list.shutdown();
+
+        let mut restarted = BlockList::new(false);
+        restarted.load(&dir.0).unwrap();
+        assert!(!restarted.is_blocked("peer-1"));
+        assert!(restarted.is_blocked_fingerprint(MALLORY));
+        assert!(restarted.bind_peer("peer-3", MALLORY));
+    }
+
+    #[test]
+    fn wiping_forgets_every_block() {
+        let mut block_list = BlockList::new(true);
+        block_list.block(MALLORY, "mallory", "peer-1");
+        block_list.block_peer_id("peer-2");
+        block_list.wipe().unwrap();
+        assert!(!block_list.is_blocked("peer-1"));
+        assert!(!block_list.is_blocked("peer-2"));
+        assert!(!block_list.is_blocked_nickname("mallory"));
+        assert!(block_list.relays_blocked());
+    }
+}
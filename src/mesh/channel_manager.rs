@@ -0,0 +1,607 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::Utc;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::bitchat_packet::{ChannelAnnounce, ChannelMember, ChannelState, ModerationAction};
+use super::identity::{normalize_fingerprint, signing_key_fingerprint};
+use super::invite::{self, Invite};
+use super::storage::{read_private, wipe_private, write_private};
+
+const CHANNELS_FILE: &str = "channels.bin";
+const COMMITMENT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+/// Argon2id at the OWASP minimum: 19 MiB, two passes. Slow enough to make
+/// guessing a channel password from captured traffic expensive.
+const KDF_MEMORY_KIB: u32 = 19 * 1024;
+const KDF_ITERATIONS: u32 = 2;
+/// How long a kicked peer stays out before they may come back.
+pub const KICK_DURATION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedChannels {
+    joined: HashSet<String>,
+    current: Option<String>,
+    keys: HashMap<String, [u8; 32]>,
+    announces: HashMap<String, ChannelAnnounce>,
+}
+
+/// The format from before ownership was signed. Its owners and commitments
+/// were never authenticated, so only membership and keys carry over.
+#[derive(Deserialize)]
+struct LegacySavedChannels {
+    joined: HashSet<String>,
+    current: Option<String>,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+/// What a verified announce changed, for the service to report.
+pub struct AnnounceOutcome {
+    /// The password changed and the key we held was dropped.
+    pub stale_key: bool,
+    /// We were kicked or banned and have left the channel.
+    pub removed_me: bool,
+    /// The channel was handed to us; `claim` publishes that we took it.
+    pub transferred_to_me: bool,
+}
+
+/// Channel membership, keys of password-protected channels, and the state
+/// each channel's owner has published.
+///
+/// Whoever joins a channel nobody has announced yet owns it, and says so
+/// with a signed claim. Afterwards only announces signed by the current
+/// owner's Ed25519 identity key are accepted, so a node that heard another
+/// claim first keeps that owner. Kicks and bans are enforced by every member
+/// dropping the target's messages, and by the target's own client leaving.
+///
+/// Encrypted channel content is `[key commitment][nonce][ciphertext]`. The
+/// commitment lets a member with the wrong password say so instead of
+/// failing with a generic decryption error.
+pub struct ChannelManager {
+    signing_key: SigningKey,
+    /// Our static key's fingerprint, which bans and kicks name.
+    my_fingerprint: String,
+    /// Our identity signing key's fingerprint, which ownership names.
+    my_owner_id: String,
+    path: Option<PathBuf>,
+    joined: HashSet<String>,
+    current: Option<String>,
+    keys: HashMap<String, [u8; 32]>,
+    /// The latest verified announce for each channel.
+    announces: HashMap<String, ChannelAnnounce>,
+    kicked: HashMap<(String, String), Instant>,
+    locked_notified: HashSet<String>,
+}
+
+impl ChannelManager {
+    pub fn new(signing_key: SigningKey, my_fingerprint: &str) -> Self {
+        ChannelManager {
+            my_fingerprint: normalize_fingerprint(my_fingerprint),
+            my_owner_id: normalize_fingerprint(&signing_key_fingerprint(&signing_key.verifying_key().to_bytes())),
+            signing_key,
+            path: None,
+            joined: HashSet::new(),
+            current: None,
+            keys: HashMap::new(),
+            announces: HashMap::new(),
+            kicked: HashMap::new(),
+            locked_notified: HashSet::new(),
+        }
+    }
+
+    /// Restores channel state saved in `data_dir` and saves there from now on.
+    pub fn load(&mut self, data_dir: &Path) -> Result<()> {
+        let path = data_dir.join(CHANNELS_FILE);
+        self.path = Some(path.clone());
+        if !path.exists() {
+            return Ok(());
+        }
+        let bytes = read_private(&path)?;
+        let saved = match bincode::deserialize::<SavedChannels>(&bytes) {
+            Ok(saved) => saved,
+            Err(_) => {
+                let legacy: LegacySavedChannels =
+                    bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+                SavedChannels {
+                    joined: legacy.joined,
+                    current: legacy.current,
+                    keys: legacy.keys,
+                    announces: HashMap::new(),
+                }
+            }
+        };
+        self.joined = saved.joined;
+        self.current = saved.current;
+        self.keys = saved.keys;
+        // Announces signed with the static key (33 bytes) predate Ed25519
+        // ownership; their owners have to claim their channels again.
+        self.announces = saved.announces.into_iter().filter(|(_, a)| a.signer_key.len() == 32).collect();
+        Ok(())
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let saved = SavedChannels {
+            joined: self.joined.clone(),
+            current: self.current.clone(),
+            keys: self.keys.clone(),
+            announces: self.announces.clone(),
+        };
+        let result = bincode::serialize(&saved)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| write_private(path, &bytes));
+        if let Err(e) = result {
+            log::warn!("Failed to save channels: {}", e);
+        }
+    }
+
+    pub fn current_channel(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    fn state(&self, channel: &str) -> Option<&ChannelState> {
+        self.announces.get(channel).map(|announce| &announce.state)
+    }
+
+    pub fn is_protected(&self, channel: &str) -> bool {
+        self.state(channel).is_some_and(|state| state.key_commitment.is_some())
+    }
+
+    pub fn topic(&self, channel: &str) -> Option<&str> {
+        self.state(channel).and_then(|state| state.topic.as_deref())
+    }
+
+    /// Whether messages from this identity in `channel` should be hidden.
+    pub fn is_excluded(&self, channel: &str, peer_fingerprint: &str) -> bool {
+        let peer_fingerprint = normalize_fingerprint(peer_fingerprint);
+        let banned = self
+            .state(channel)
+            .is_some_and(|state| state.banned.iter().any(|m| m.fingerprint == peer_fingerprint));
+        let kicked = self
+            .kicked
+            .get(&(channel.to_string(), peer_fingerprint))
+            .is_some_and(|at| at.elapsed() < KICK_DURATION);
+        banned || kicked
+    }
+
+    /// Joins `channel` and makes it the one new messages go to. A password is
+    /// checked against the channel's published commitment when we know it.
+    /// Returns our claim when nobody has announced the channel yet.
+    pub fn join(&mut self, channel: &str, password: Option<&str>) -> Result<Option<ChannelAnnounce>> {
+        let key = password.map(|password| derive_key(channel, password)).transpose()?;
+        self.join_with_key(channel, key)?;
+        if self.announces.contains_key(channel) {
+            return Ok(None);
+        }
+        let state = ChannelState {
+            owner: self.my_owner_id.clone(),
+            key_commitment: key.map(|key| commitment(&key).to_vec()),
+            ..Default::default()
+        };
+        self.sign_announce(channel, state, ModerationAction::Claim, 1).map(Some)
+    }
+
+    /// Publishes that we own a channel that was just transferred to us, so
+    /// members who never saw the transfer accept us too.
+    pub fn claim(&mut self, channel: &str) -> Result<ChannelAnnounce> {
+        let Some(latest) = self.announces.get(channel) else {
+            bail!("Nothing is known about {}", channel);
+        };
+        if latest.state.owner != self.my_owner_id {
+            bail!("Only the owner of {} can claim it", channel);
+        }
+        let (state, sequence) = (latest.state.clone(), latest.sequence + 1);
+        self.sign_announce(channel, state, ModerationAction::Claim, sequence)
+    }
+
+    /// Joins the channel an invite is for, with the key it carries. Only the
+    /// owner issues invites; returns whether we know the owner and so could
+    /// check that.
+    pub fn join_with_invite(&mut self, invite: &Invite) -> Result<bool> {
+        let owner_known = match self.state(&invite.channel) {
+            Some(state) if state.owner != invite.issuer_fingerprint => {
+                bail!("This invite to {} was not issued by its owner", invite.channel);
+            }
+            Some(_) => true,
+            None => false,
+        };
+        self.join_with_key(&invite.channel, invite.key)?;
+        Ok(owner_known)
+    }
+
+    fn join_with_key(&mut self, channel: &str, key: Option<[u8; 32]>) -> Result<()> {
+        if self.state(channel).is_some_and(|state| state.banned.iter().any(|m| m.fingerprint == self.my_fingerprint)) {
+            bail!("You are banned from {}", channel);
+        }
+        if self.is_excluded(channel, &self.my_fingerprint) {
+            bail!("You were kicked from {}; try again later", channel);
+        }
+        if let Some(key) = key {
+            let published = self.state(channel).and_then(|state| state.key_commitment.as_deref());
+            if published.is_some_and(|c| c != commitment(&key)) {
+                bail!("Wrong password for {}", channel);
+            }
+            self.keys.insert(channel.to_string(), key);
+            self.locked_notified.remove(channel);
+        }
+        self.joined.insert(channel.to_string());
+        self.current = Some(channel.to_string());
+        self.save();
+        Ok(())
+    }
+
+    /// Issues an invite token to a channel we own, carrying its key if it
+    /// has one.
+    pub fn invite(&self, channel: &str, nickname: &str, lifetime_hours: i64) -> Result<String> {
+        if !self.joined.contains(channel) {
+            bail!("Join {} before inviting others to it", channel);
+        }
+        if self.state(channel).is_none_or(|state| state.owner != self.my_owner_id) {
+            bail!("Only the owner of {} can invite others to it", channel);
+        }
+        let key = self.keys.get(channel);
+        if key.is_none() && self.is_protected(channel) {
+            bail!("{} is password protected and we do not have its key", channel);
+        }
+        invite::create(&self.signing_key, nickname, channel, key, lifetime_hours)
+    }
+
+    /// Sets the password of the current channel and returns the announcement
+    /// that tells other members about it.
+    pub fn set_password(&mut self, password: &str) -> Result<ChannelAnnounce> {
+        let channel = self.require_current()?;
+        let key = derive_key(&channel, password)?;
+        let key_commitment = commitment(&key);
+        let announce = self.moderate(ModerationAction::SetPassword, |state| {
+            state.key_commitment = Some(key_commitment.to_vec());
+            Ok(())
+        })?;
+        self.keys.insert(channel, key);
+        self.save();
+        Ok(announce)
+    }
+
+    pub fn set_topic(&mut self, topic: &str) -> Result<ChannelAnnounce> {
+        self.moderate(ModerationAction::SetTopic, |state| {
+            state.topic = Some(topic.to_string());
+            Ok(())
+        })
+    }
+
+    pub fn kick(&mut self, member: ChannelMember) -> Result<ChannelAnnounce> {
+        self.check_not_me(&member)?;
+        let channel = self.require_current()?;
+        self.kicked.insert((channel, member.fingerprint.clone()), Instant::now());
+        self.moderate(ModerationAction::Kick(member), |_| Ok(()))
+    }
+
+    pub fn ban(&mut self, member: ChannelMember) -> Result<ChannelAnnounce> {
+        self.check_not_me(&member)?;
+        let banned = member.clone();
+        self.moderate(ModerationAction::Ban(member), |state| {
+            if state.banned.iter().any(|m| m.fingerprint == banned.fingerprint) {
+                bail!("{} is already banned", banned.nickname);
+            }
+            state.banned.push(banned);
+            Ok(())
+        })
+    }
+
+    /// Unbans by the nickname the ban was issued under, so it works while the
+    /// peer is away.
+    pub fn unban(&mut self, nickname: &str) -> Result<ChannelAnnounce> {
+        let channel = self.require_current()?;
+        let Some(member) = self
+            .state(&channel)
+            .and_then(|state| state.banned.iter().find(|m| m.nickname == nickname))
+            .cloned()
+        else {
+            bail!("{} is not banned from {}", nickname, channel);
+        };
+        let fingerprint = member.fingerprint.clone();
+        self.moderate(ModerationAction::Unban(member), |state| {
+            state.banned.retain(|m| m.fingerprint != fingerprint);
+            Ok(())
+        })
+    }
+
+    /// Hands the current channel over. `member` names the new owner by the
+    /// fingerprint of their identity signing key.
+    pub fn transfer_ownership(&mut self, member: ChannelMember) -> Result<ChannelAnnounce> {
+        let new_owner = normalize_fingerprint(&member.fingerprint);
+        if new_owner == self.my_owner_id {
+            bail!("You cannot do that to yourself");
+        }
+        self.moderate(ModerationAction::TransferOwnership(member), |state| {
+            state.owner = new_owner;
+            Ok(())
+        })
+    }
+
+    fn require_current(&self) -> Result<String> {
+        self.current.clone().ok_or_else(|| anyhow!("Join a channel with /j #channel first"))
+    }
+
+    fn check_not_me(&self, member: &ChannelMember) -> Result<()> {
+        if normalize_fingerprint(&member.fingerprint) == self.my_fingerprint {
+            bail!("You cannot do that to yourself");
+        }
+        Ok(())
+    }
+
+    /// Applies `update` to the current channel's state as its owner and signs
+    /// the result.
+    fn moderate(
+        &mut self,
+        action: ModerationAction,
+        update: impl FnOnce(&mut ChannelState) -> Result<()>,
+    ) -> Result<ChannelAnnounce> {
+        let channel = self.require_current()?;
+        let (mut state, sequence) = match self.announces.get(&channel) {
+            Some(latest) if latest.state.owner != self.my_owner_id => {
+                bail!("Only the owner of {} can do that", channel);
+            }
+            Some(latest) => (latest.state.clone(), latest.sequence + 1),
+            None => bail!("Nobody has claimed {} yet; rejoin it to claim it", channel),
+        };
+        update(&mut state)?;
+        self.sign_announce(&channel, state, action, sequence)
+    }
+
+    fn sign_announce(
+        &mut self,
+        channel: &str,
+        state: ChannelState,
+        action: ModerationAction,
+        sequence: u64,
+    ) -> Result<ChannelAnnounce> {
+        let mut announce = ChannelAnnounce {
+            channel: channel.to_string(),
+            state,
+            action,
+            sequence,
+            signer_key: self.signing_key.verifying_key().to_bytes().to_vec(),
+            timestamp: Utc::now(),
+            signature: Vec::new(),
+        };
+        announce.signature = self.signing_key.sign(&announce.signable_bytes()?).to_bytes().to_vec();
+        self.announces.insert(channel.to_string(), announce.clone());
+        self.save();
+        Ok(announce)
+    }
+
+    /// Applies an announce signed by the channel's owner. Returns `None` for
+    /// one we already have, which is normal since members replay them.
+    pub fn handle_announce(&mut self, announce: &ChannelAnnounce) -> Result<Option<AnnounceOutcome>> {
+        let channel = &announce.channel;
+        let signer_key: [u8; 32] =
+            announce.signer_key.as_slice().try_into().context("Bad signer key in channel announce")?;
+        let signer = VerifyingKey::from_bytes(&signer_key).context("Bad signer key in channel announce")?;
+        let signer_id = normalize_fingerprint(&signing_key_fingerprint(&signer_key));
+        match self.announces.get(channel) {
+            Some(latest) if announce.sequence <= latest.sequence => return Ok(None),
+            Some(latest) if latest.state.owner != signer_id => {
+                bail!("Announce for {} is not signed by its owner", channel);
+            }
+            // First we hear of the channel: it must come from the owner it
+            // names. A transfer does not, and waits for the new owner's claim.
+            None if announce.state.owner != signer_id => {
+                bail!("Announce for {} is not signed by its owner", channel);
+            }
+            _ => {}
+        }
+        let signature = Signature::from_slice(&announce.signature).context("Bad signature in channel announce")?;
+        signer
+            .verify(&announce.signable_bytes()?, &signature)
+            .map_err(|_| anyhow!("Announce for {} has an invalid signature", channel))?;
+
+        let stale_key = match &announce.state.key_commitment {
+            Some(key_commitment) => self.keys.get(channel).is_some_and(|key| commitment(key) != key_commitment.as_slice()),
+            None => false,
+        };
+        if stale_key {
+            self.keys.remove(channel);
+        }
+
+        let mut removed_me = false;
+        if let ModerationAction::Kick(member) | ModerationAction::Ban(member) = &announce.action {
+            let target = normalize_fingerprint(&member.fingerprint);
+            if matches!(announce.action, ModerationAction::Kick(_)) {
+                self.kicked.insert((channel.clone(), target.clone()), Instant::now());
+            }
+            if target == self.my_fingerprint {
+                self.joined.remove(channel);
+                if self.current.as_deref() == Some(channel.as_str()) {
+                    self.current = None;
+                }
+                removed_me = true;
+            }
+        }
+
+        let transferred_to_me = matches!(announce.action, ModerationAction::TransferOwnership(_))
+            && announce.state.owner == self.my_owner_id;
+
+        self.announces.insert(channel.clone(), announce.clone());
+        self.save();
+        Ok(Some(AnnounceOutcome { stale_key, removed_me, transferred_to_me }))
+    }
+
+    /// The latest announce of every channel we are in, for newcomers.
+    pub fn latest_announces(&self) -> Vec<ChannelAnnounce> {
+        self.joined
+            .iter()
+            .filter_map(|channel| self.announces.get(channel))
+            .cloned()
+            .collect()
+    }
+
+    pub fn encrypt(&self, channel: &str, plaintext: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(key) = self.keys.get(channel) else {
+            if self.is_protected(channel) {
+                bail!("{} is password protected; join it with /j {} <password>", channel, channel);
+            }
+            return Ok(None);
+        };
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let key_commitment = commitment(key);
+        let ciphertext = Aes256Gcm::new(key.into())
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: channel.as_bytes() })
+            .map_err(|_| anyhow!("Channel encryption failed"))?;
+
+        let mut data = Vec::with_capacity(COMMITMENT_LEN + NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(&key_commitment);
+        data.extend_from_slice(&nonce);
+        data.extend(ciphertext);
+        Ok(Some(data))
+    }
+
+    pub fn has_key(&self, channel: &str) -> bool {
+        self.keys.contains_key(channel)
+    }
+
+    pub fn decrypt(&mut self, channel: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let Some(key) = self.keys.get(channel) else {
+            bail!("No key for {}", channel);
+        };
+        if data.len() < COMMITMENT_LEN + NONCE_LEN {
+            bail!("Encrypted channel message too short");
+        }
+        let (key_commitment, rest) = data.split_at(COMMITMENT_LEN);
+        if key_commitment != commitment(key) {
+            bail!("Message in {} was sent with a different password", channel);
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        Aes256Gcm::new(key.into())
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: channel.as_bytes() })
+            .map_err(|_| anyhow!("Message in {} failed authentication", channel))
+    }
+
+    /// True the first time a locked channel is seen, so the hint is shown once.
+    pub fn note_locked(&mut self, channel: &str) -> bool {
+        self.locked_notified.insert(channel.to_string())
+    }
+
+    pub fn shutdown(&mut self) {
+        self.save();
+        self.keys.clear();
+        self.kicked.clear();
+        self.locked_notified.clear();
+    }
+
+    /// Forgets every channel and key, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
+        let path = self.path.take();
+        *self = ChannelManager::new(self.signing_key.clone(), &self.my_fingerprint);
+        match path {
+            Some(path) => wipe_private(&path),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Argon2id over the password, salted with the channel name so the same
+/// password yields unrelated keys in different channels.
+fn derive_key(channel: &str, password: &str) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bitchat channel salt");
+    hasher.update(channel.as_bytes());
+    let salt = hasher.finalize();
+
+    let params = Params::new(KDF_MEMORY_KIB, KDF_ITERATIONS, 1, Some(32))
+        .map_err(|e| anyhow!("Invalid KDF parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), &salt[..16], &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+fn commitment(key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bitchat channel key commitment");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(fingerprint: &str) -> ChannelManager {
+        ChannelManager::new(SigningKey::generate(&mut rand::thread_rng()), fingerprint)
+    }
+
+    /// `owner` claims `channel` with `password`, and `member` hears the claim.
+    fn claimed(owner: &mut ChannelManager, member: &mut ChannelManager, channel: &str, password: Option<&str>) {
+        let claim = owner.join(channel, password).unwrap().unwrap();
+        member.handle_announce(&claim).unwrap();
+    }
+
+    #[test]
+    fn invites_from_the_owner_carry_the_key() {
+        let (mut alice, mut bob) = (manager("aa"), manager("bb"));
+        claimed(&mut alice, &mut bob, "#mesh", Some("secret"));
+
+        let token = alice.invite("#mesh", "alice", 1).unwrap();
+        assert!(bob.join_with_invite(&invite::redeem(&token).unwrap()).unwrap());
+        assert!(bob.has_key("#mesh"));
+        assert_eq!(bob.current_channel(), Some("#mesh"));
+        let encrypted = alice.encrypt("#mesh", b"hello").unwrap().unwrap();
+        assert_eq!(bob.decrypt("#mesh", &encrypted).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn invites_from_anyone_but_the_owner_are_refused() {
+        let (mut alice, mut bob, mut mallory) = (manager("aa"), manager("bb"), manager("cc"));
+        claimed(&mut alice, &mut bob, "#mesh", None);
+        // Mallory never heard Alice's claim, so she thinks she owns the channel.
+        mallory.join("#mesh", None).unwrap();
+
+        let token = mallory.invite("#mesh", "mallory", 1).unwrap();
+        let error = bob.join_with_invite(&invite::redeem(&token).unwrap()).err().unwrap().to_string();
+        assert!(error.contains("not issued by its owner"), "{}", error);
+        assert_eq!(bob.current_channel(), None);
+    }
+
+    #[test]
+    fn invites_to_unknown_channels_cannot_be_checked() {
+        let (mut alice, mut bob) = (manager("aa"), manager("bb"));
+        alice.join("#mesh", None).unwrap();
+        let token = alice.invite("#mesh", "alice", 1).unwrap();
+        assert!(!bob.join_with_invite(&invite::redeem(&token).unwrap()).unwrap());
+        assert_eq!(bob.current_channel(), Some("#mesh"));
+    }
+
+    #[test]
+    fn only_owners_invite_to_channels_they_joined() {
+        let (mut alice, mut bob) = (manager("aa"), manager("bb"));
+        assert!(alice.invite("#mesh", "alice", 1).is_err());
+        claimed(&mut alice, &mut bob, "#mesh", None);
+        bob.join("#mesh", None).unwrap();
+        let error = bob.invite("#mesh", "bob", 1).err().unwrap().to_string();
+        assert!(error.contains("Only the owner"), "{}", error);
+    }
+
+    #[test]
+    fn invites_are_refused_after_a_ban() {
+        let (mut alice, mut bob) = (manager("aa"), manager("bb"));
+        claimed(&mut alice, &mut bob, "#mesh", None);
+        let token = alice.invite("#mesh", "alice", 1).unwrap();
+        let ban = alice.ban(ChannelMember { fingerprint: "bb".to_string(), nickname: "bob".to_string() }).unwrap();
+        bob.handle_announce(&ban).unwrap();
+        assert!(bob.join_with_invite(&invite::redeem(&token).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{anyhow, bail, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+use super::identity::default_data_dir;
+use super::relay_manager::{RelayMode, RelayPolicy};
+use super::sealed_sender::MAX_ONION_HOPS;
+use super::security_manager::RekeyPolicy;
+use super::signature_manager::SignaturePolicy;
+
+pub const USAGE: &str = "Usage: bitchat-rust [--nickname <name>] [--data-dir <path>] [--relay all|never] \
+[--relay-channels <a,b,...>] [--max-hops <n>] [--max-relay-bandwidth <bytes/s>] [--no-relay-dms] [--no-relay-blocked] \
+[--unsigned flag|drop] [--rotate-id <minutes>] [--key-file <path>] [--max-clock-skew <seconds>] \
+[--rekey-messages <n>] [--rekey-minutes <minutes>] [--sealed-sender] [--onion-hops <n>]";
+
+/// A day either way covers any clock worth trusting, and keeps timestamp
+/// arithmetic far from overflowing.
+const MAX_CLOCK_SKEW_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    pub nickname: String,
+    /// Where the identity key and other persistent state live.
+    pub data_dir: PathBuf,
+    /// Unlocks the data directory instead of a passphrase typed at startup.
+    pub key_file: Option<PathBuf>,
+    pub relay_policy: RelayPolicy,
+    /// Whether packets from peers we blocked are still passed along for others.
+    pub relay_blocked: bool,
+    /// What happens to broadcasts with a missing or bad signature.
+    pub signature_policy: SignaturePolicy,
+    /// How often the advertised peer ID changes; `None` keeps it for the whole run.
+    pub id_rotation: Option<Duration>,
+    /// How far a live message's timestamp may be from our clock before it is
+    /// treated as a replay.
+    pub max_clock_skew: Duration,
+    /// When sessions with peers are replaced by a fresh handshake.
+    pub rekey_policy: RekeyPolicy,
+    /// Private messages and their acks hide who sent them from relays.
+    pub sealed_sender: bool,
+    /// Relays each private message is layered through; 0 sends it directly.
+    pub onion_hops: usize,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        MeshConfig {
+            nickname: std::env::var("BITCHAT_NICKNAME").unwrap_or_else(|_| "anon".to_string()),
+            data_dir: default_data_dir(),
+            key_file: None,
+            relay_policy: RelayPolicy::default(),
+            relay_blocked: true,
+            signature_policy: SignaturePolicy::Flag,
+            id_rotation: Some(Duration::from_secs(15 * 60)),
+            max_clock_skew: Duration::from_secs(5 * 60),
+            rekey_policy: RekeyPolicy::default(),
+            sealed_sender: false,
+            onion_hops: 0,
+        }
+    }
+}
+
+impl MeshConfig {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config = MeshConfig::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--nickname" => config.nickname = value()?,
+                "--data-dir" => config.data_dir = PathBuf::from(value()?),
+                "--key-file" => config.key_file = Some(PathBuf::from(value()?)),
+                "--relay" => {
+                    config.relay_policy.mode = match value()?.as_str() {
+                        "all" => RelayMode::All,
+                        "never" => RelayMode::Never,
+                        other => bail!("Unknown relay mode: {}", other),
+                    }
+                }
+                "--relay-channels" => {
+                    let channels = value()?
+                        .split(',')
+                        .map(|c| c.trim().to_string())
+                        .filter(|c| !c.is_empty())
+                        .collect();
+                    config.relay_policy.mode = RelayMode::Channels(channels);
+                }
+                "--max-hops" => config.relay_policy.max_hops = value()?.parse()?,
+                "--max-relay-bandwidth" => config.relay_policy.max_bandwidth = Some(value()?.parse()?),
+                "--no-relay-dms" => config.relay_policy.relay_direct_messages = false,
+                "--no-relay-blocked" => config.relay_blocked = false,
+                "--sealed-sender" => config.sealed_sender = true,
+                "--onion-hops" => {
+                    let hops: usize = value()?.parse()?;
+                    if hops > MAX_ONION_HOPS {
+                        bail!("--onion-hops can be at most {}", MAX_ONION_HOPS);
+                    }
+                    config.onion_hops = hops;
+                }
+                "--rotate-id" => {
+                    let minutes: u64 = value()?.parse()?;
+                    config.id_rotation = (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+                }
+                "--max-clock-skew" => {
+                    let seconds: u64 = value()?.parse()?;
+                    if seconds > MAX_CLOCK_SKEW_SECS {
+                        bail!("--max-clock-skew can be at most {} seconds", MAX_CLOCK_SKEW_SECS);
+                    }
+                    config.max_clock_skew = Duration::from_secs(seconds);
+                }
+                "--rekey-messages" => config.rekey_policy.max_messages = value()?.parse()?,
+                "--rekey-minutes" => {
+                    let minutes: u64 = value()?.parse()?;
+                    if minutes == 0 {
+                        bail!("--rekey-minutes must be at least 1");
+                    }
+                    config.rekey_policy.max_age = Duration::from_secs(minutes * 60);
+                }
+                "--unsigned" => {
+                    config.signature_policy = match value()?.as_str() {
+                        "flag" => SignaturePolicy::Flag,
+                        "drop" => SignaturePolicy::Drop,
+                        other => bail!("Unknown signature policy: {}", other),
+                    }
+                }
+                other => bail!("Unknown argument: {}", other),
+            }
+        }
+
+        Ok(config)
+    }
+}
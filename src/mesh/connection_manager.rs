@@ -1,47 +1,213 @@
-use bluer::{Adapter, Session};
-use std::time::Duration;
-use tokio::time;
-use anyhow::Result;
+use bluer::adv::{Advertisement, AdvertisementHandle};
+use bluer::gatt::local::{
+    Application, ApplicationHandle, Characteristic, CharacteristicNotifier, CharacteristicNotify,
+    CharacteristicNotifyMethod, CharacteristicWrite, CharacteristicWriteMethod, Service,
+};
+use bluer::gatt::remote;
+use bluer::{AdapterEvent, Device, Session, Uuid};
+use futures::{FutureExt, StreamExt};
+use anyhow::{anyhow, Result};
 use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The GATT service every bitchat node offers, and the one characteristic
+/// packets travel over in both directions.
+const SERVICE_UUID: Uuid = Uuid::from_u128(0xF47B5E2D_4A9E_4C5A_9B3F_8E1D2C3A4B5C);
+const CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xA1B2C3D4_E5F6_4A5B_8C9D_0E1F2A3B4C5D);
 
 pub trait BluetoothConnectionManagerDelegate: Send + Sync {
     fn on_packet_received(&self, packet: &[u8], peer_id: &str);
 }
 
+type Delegate = Option<Arc<Mutex<dyn BluetoothConnectionManagerDelegate>>>;
+
+/// Neighbours we can push packets to: centrals subscribed to our
+/// characteristic, and peripherals whose characteristic we write to.
+#[derive(Default)]
+struct Links {
+    subscribers: Vec<CharacteristicNotifier>,
+    peripherals: Vec<remote::Characteristic>,
+}
+
 pub struct BluetoothConnectionManager {
-    delegate: Option<Arc<Mutex<dyn BluetoothConnectionManagerDelegate>>>,
+    delegate: Delegate,
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    published: Option<(AdvertisementHandle, ApplicationHandle)>,
+    tasks: Vec<JoinHandle<()>>,
 }
 
 impl BluetoothConnectionManager {
     pub fn new() -> Self {
-        BluetoothConnectionManager { delegate: None }
+        BluetoothConnectionManager { delegate: None, outgoing: None, published: None, tasks: Vec::new() }
     }
 
     pub fn set_delegate(&mut self, delegate: Arc<Mutex<dyn BluetoothConnectionManagerDelegate>>) {
         self.delegate = Some(delegate);
     }
 
-    pub async fn start_services(&self) -> Result<()> {
+    /// Serves our characteristic to peers that find us and connects to every
+    /// peer advertising it, so packets flow whichever side scanned first.
+    pub async fn start_services(manager: Arc<Mutex<Self>>) -> Result<()> {
         let session = Session::new().await?;
         let adapter = session.default_adapter().await?;
         adapter.set_powered(true).await?;
 
-        let discover = adapter.discover_devices().await?;
-        time::sleep(Duration::from_secs(5)).await;
+        let delegate = manager.lock().unwrap().delegate.clone();
+        let links = Arc::new(tokio::sync::Mutex::new(Links::default()));
+        let app = Application {
+            services: vec![Service {
+                uuid: SERVICE_UUID,
+                primary: true,
+                characteristics: vec![Characteristic {
+                    uuid: CHARACTERISTIC_UUID,
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        write_without_response: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new({
+                            let delegate = delegate.clone();
+                            move |packet, _request| {
+                                deliver(&delegate, &packet, "central");
+                                async { Ok(()) }.boxed()
+                            }
+                        })),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new({
+                            let links = links.clone();
+                            move |notifier| {
+                                let links = links.clone();
+                                async move { links.lock().await.subscribers.push(notifier) }.boxed()
+                            }
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let app_handle = adapter.serve_gatt_application(app).await?;
+        let advertisement = Advertisement {
+            service_uuids: [SERVICE_UUID].into_iter().collect(),
+            discoverable: Some(true),
+            ..Default::default()
+        };
+        let adv_handle = adapter.advertise(advertisement).await?;
 
-        let devices = adapter.device_addresses().await?;
-        for addr in devices {
-            println!("Found device: {}", addr);
-        }
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let sender_links = links.clone();
+        let sender = tokio::spawn(async move {
+            while let Some(packet) = outgoing_rx.recv().await {
+                let mut links = sender_links.lock().await;
+                let mut subscribers = Vec::new();
+                for mut notifier in links.subscribers.drain(..) {
+                    if notifier.notify(packet.clone()).await.is_ok() {
+                        subscribers.push(notifier);
+                    }
+                }
+                links.subscribers = subscribers;
+                let mut peripherals = Vec::new();
+                for peripheral in links.peripherals.drain(..) {
+                    if peripheral.write(&packet).await.is_ok() {
+                        peripherals.push(peripheral);
+                    }
+                }
+                links.peripherals = peripherals;
+            }
+        });
+
+        let discovery = adapter.discover_devices().await?;
+        let scanner = tokio::spawn(async move {
+            futures::pin_mut!(discovery);
+            while let Some(event) = discovery.next().await {
+                let AdapterEvent::DeviceAdded(address) = event else {
+                    continue;
+                };
+                let Ok(device) = adapter.device(address) else {
+                    continue;
+                };
+                let delegate = delegate.clone();
+                let links = links.clone();
+                tokio::spawn(async move {
+                    // Most devices nearby are not bitchat nodes; that is not worth reporting.
+                    let _ = subscribe(device, delegate, links).await;
+                });
+            }
+        });
 
+        let mut manager = manager.lock().unwrap();
+        manager.outgoing = Some(outgoing_tx);
+        manager.published = Some((adv_handle, app_handle));
+        manager.tasks = vec![sender, scanner];
         Ok(())
     }
 
-    pub fn stop_services(&self) {
-        // TODO: Stop scanning and disconnect from peripherals
+    pub fn stop_services(&mut self) {
+        self.outgoing = None;
+        self.published = None;
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
     }
 
     pub fn broadcast_packet(&self, packet: &[u8]) {
-        // TODO: Implement packet broadcasting
+        if let Some(outgoing) = &self.outgoing {
+            // Only fails once the sender task is gone, i.e. after stopping.
+            let _ = outgoing.send(packet.to_vec());
+        }
+    }
+
+    /// Links are not tied to peer IDs, so this reaches every neighbour; the
+    /// packets sent this way carry a TTL of 1 and go no further.
+    pub fn send_packet(&self, packet: &[u8], _peer_id: &str) {
+        self.broadcast_packet(packet);
+    }
+}
+
+fn deliver(delegate: &Delegate, packet: &[u8], peer_id: &str) {
+    if let Some(delegate) = delegate {
+        delegate.lock().unwrap().on_packet_received(packet, peer_id);
+    }
+}
+
+/// Connects to a device offering our service and feeds its notifications to
+/// the delegate until the link drops.
+async fn subscribe(
+    device: Device,
+    delegate: Delegate,
+    links: Arc<tokio::sync::Mutex<Links>>,
+) -> Result<()> {
+    if !device.uuids().await?.unwrap_or_default().contains(&SERVICE_UUID) {
+        return Ok(());
+    }
+    if !device.is_connected().await? {
+        device.connect().await?;
+    }
+    let characteristic = find_characteristic(&device).await?;
+    let notifications = characteristic.notify().await?;
+    links.lock().await.peripherals.push(characteristic);
+    let peer_id = device.address().to_string();
+    futures::pin_mut!(notifications);
+    while let Some(packet) = notifications.next().await {
+        deliver(&delegate, &packet, &peer_id);
+    }
+    Ok(())
+}
+
+async fn find_characteristic(device: &Device) -> Result<remote::Characteristic> {
+    for service in device.services().await? {
+        if service.uuid().await? != SERVICE_UUID {
+            continue;
+        }
+        for characteristic in service.characteristics().await? {
+            if characteristic.uuid().await? == CHARACTERISTIC_UUID {
+                return Ok(characteristic);
+            }
+        }
     }
+    Err(anyhow!("{} offers no bitchat characteristic", device.address()))
 }
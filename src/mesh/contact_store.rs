@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use super::identity::normalize_fingerprint;
+use super::storage::{read_private, wipe_private, write_private};
+
+const CONTACTS_FILE: &str = "contacts.bin";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Contact {
+    pub nickname: String,
+    pub verified: bool,
+    pub first_seen: DateTime<Utc>,
+}
+
+/// What goes to disk.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedContacts {
+    contacts: HashMap<String, Contact>,
+    pins: HashMap<String, String>,
+}
+
+/// Static keys we have seen, keyed by fingerprint and kept across restarts.
+///
+/// Peer IDs change on every launch and rotation, so the nickname is what a
+/// key is pinned to: the first key seen under a nickname keeps it until the
+/// user accepts another. Keys are never forgotten, verified or not.
+pub struct ContactStore {
+    path: PathBuf,
+    contacts: HashMap<String, Contact>,
+    /// Nickname to the fingerprint pinned to it.
+    pins: HashMap<String, String>,
+}
+
+impl ContactStore {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(CONTACTS_FILE);
+        if !path.exists() {
+            return Ok(ContactStore { path, contacts: HashMap::new(), pins: HashMap::new() });
+        }
+        let bytes = read_private(&path)?;
+        let saved = match bincode::deserialize::<SavedContacts>(&bytes) {
+            Ok(saved) => saved,
+            // Written before keys were pinned to nicknames.
+            Err(_) => {
+                let contacts: HashMap<String, Contact> =
+                    bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+                SavedContacts { pins: initial_pins(&contacts), contacts }
+            }
+        };
+        Ok(ContactStore { path, contacts: saved.contacts, pins: saved.pins })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let saved = SavedContacts { contacts: self.contacts.clone(), pins: self.pins.clone() };
+        write_private(&self.path, &bincode::serialize(&saved)?)
+    }
+
+    pub fn is_verified(&self, fingerprint: &str) -> bool {
+        self.contacts
+            .get(&normalize_fingerprint(fingerprint))
+            .is_some_and(|contact| contact.verified)
+    }
+
+    /// Verifying a key also pins the nickname to it.
+    pub fn set_verified(&mut self, fingerprint: &str, nickname: &str) {
+        let contact = self.entry(fingerprint, nickname);
+        contact.verified = true;
+        self.pin(nickname, fingerprint);
+    }
+
+    pub fn pinned_fingerprint(&self, nickname: &str) -> Option<&str> {
+        self.pins.get(nickname).map(String::as_str)
+    }
+
+    /// Remembers this key under the nickname it last used, and pins the
+    /// nickname to it unless another key has it already.
+    pub fn record_key(&mut self, nickname: &str, fingerprint: &str) {
+        self.entry(fingerprint, nickname);
+        self.pins.entry(nickname.to_string()).or_insert_with(|| normalize_fingerprint(fingerprint));
+    }
+
+    /// Moves the nickname's pin to this key, once the user accepted it. The
+    /// key it was pinned to before stays known, along with its verification.
+    pub fn pin(&mut self, nickname: &str, fingerprint: &str) {
+        self.entry(fingerprint, nickname);
+        self.pins.insert(nickname.to_string(), normalize_fingerprint(fingerprint));
+    }
+
+    pub fn verified_contacts(&self) -> Vec<(String, Contact)> {
+        self.contacts
+            .iter()
+            .filter(|(_, contact)| contact.verified)
+            .map(|(fp, contact)| (fp.clone(), contact.clone()))
+            .collect()
+    }
+
+    /// Adds verified contacts from a backup, keeping anything already known.
+    pub fn merge_verified(&mut self, contacts: Vec<(String, Contact)>) {
+        for (fingerprint, contact) in contacts {
+            let fingerprint = normalize_fingerprint(&fingerprint);
+            self.pins.entry(contact.nickname.clone()).or_insert_with(|| fingerprint.clone());
+            let existing = self.contacts.entry(fingerprint).or_insert(contact);
+            existing.verified = true;
+        }
+    }
+
+    fn entry(&mut self, fingerprint: &str, nickname: &str) -> &mut Contact {
+        let contact = self
+            .contacts
+            .entry(normalize_fingerprint(fingerprint))
+            .or_insert_with(|| Contact {
+                nickname: nickname.to_string(),
+                verified: false,
+                first_seen: Utc::now(),
+            });
+        contact.nickname = nickname.to_string();
+        contact
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Err(e) = self.save() {
+            log::warn!("Failed to save contacts: {}", e);
+        }
+    }
+
+    /// Forgets every contact, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
+        self.contacts.clear();
+        self.pins.clear();
+        wipe_private(&self.path)
+    }
+}
+
+/// Pins for contacts saved before there were any: each nickname goes to its
+/// verified key, else the one seen first.
+fn initial_pins(contacts: &HashMap<String, Contact>) -> HashMap<String, String> {
+    let mut pins: HashMap<String, (&String, &Contact)> = HashMap::new();
+    for (fingerprint, contact) in contacts {
+        let rank = |contact: &Contact| (contact.verified, Reverse(contact.first_seen));
+        let better = pins.get(&contact.nickname).is_none_or(|(_, pinned)| rank(contact) > rank(pinned));
+        if better {
+            pins.insert(contact.nickname.clone(), (fingerprint, contact));
+        }
+    }
+    pins.into_iter().map(|(nickname, (fingerprint, _))| (nickname, fingerprint.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> ContactStore {
+        ContactStore { path: PathBuf::from("contacts.bin"), contacts: HashMap::new(), pins: HashMap::new() }
+    }
+
+    #[test]
+    fn the_first_key_seen_under_a_nickname_is_pinned() {
+        let mut store = store();
+        store.record_key("bob", "AAAA BBBB");
+        store.record_key("bob", "cccc dddd");
+        assert_eq!(store.pinned_fingerprint("bob"), Some("aaaabbbb"));
+        assert_eq!(store.pinned_fingerprint("carol"), None);
+    }
+
+    #[test]
+    fn accepting_a_key_moves_the_pin_and_forgets_nothing() {
+        let mut store = store();
+        store.set_verified("aaaabbbb", "bob");
+        store.record_key("bob", "ccccdddd");
+        store.pin("bob", "ccccdddd");
+        assert_eq!(store.pinned_fingerprint("bob"), Some("ccccdddd"));
+        assert!(store.is_verified("aaaabbbb"));
+        assert!(!store.is_verified("ccccdddd"));
+    }
+
+    #[test]
+    fn verifying_a_key_pins_it() {
+        let mut store = store();
+        store.record_key("bob", "aaaabbbb");
+        store.set_verified("ccccdddd", "bob");
+        assert_eq!(store.pinned_fingerprint("bob"), Some("ccccdddd"));
+    }
+
+    #[test]
+    fn contacts_saved_without_pins_get_them() {
+        let contact = |verified, seconds_ago| Contact {
+            nickname: "bob".to_string(),
+            verified,
+            first_seen: Utc::now() - chrono::Duration::seconds(seconds_ago),
+        };
+        let mut contacts = HashMap::new();
+        contacts.insert("old".to_string(), contact(false, 60));
+        contacts.insert("new".to_string(), contact(false, 10));
+        assert_eq!(initial_pins(&contacts).get("bob").map(String::as_str), Some("old"));
+        contacts.insert("verified".to_string(), contact(true, 5));
+        assert_eq!(initial_pins(&contacts).get("bob").map(String::as_str), Some("verified"));
+    }
+}
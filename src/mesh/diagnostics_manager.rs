@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::bitchat_packet::{BitchatPacket, PingReply, PingRequest};
+
+pub const PING_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct PendingPing {
+    target_nickname: String,
+    sent_at: Instant,
+}
+
+pub struct PingResult {
+    pub target_nickname: String,
+    pub round_trip: Duration,
+    pub hops_out: u8,
+    pub hops_back: u8,
+    pub route_out: Option<Vec<String>>,
+    pub route_back: Option<Vec<String>>,
+}
+
+impl PingResult {
+    pub fn get_display_text(&self) -> String {
+        let mut text = format!(
+            "{}: {} ms, {} hops out, {} hops back",
+            self.target_nickname,
+            self.round_trip.as_millis(),
+            self.hops_out,
+            self.hops_back
+        );
+        if let Some(route) = &self.route_out {
+            text.push_str(&format!(" | out: {}", Self::format_route(route)));
+        }
+        if let Some(route) = &self.route_back {
+            text.push_str(&format!(" | back: {}", Self::format_route(route)));
+        }
+        text
+    }
+
+    fn format_route(route: &[String]) -> String {
+        if route.is_empty() {
+            return "direct".to_string();
+        }
+        route
+            .iter()
+            .map(|hop| hop.chars().take(8).collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" > ")
+    }
+}
+
+/// Tracks outstanding pings and traceroutes until their reply arrives.
+pub struct DiagnosticsManager {
+    pending: HashMap<String, PendingPing>,
+}
+
+impl DiagnosticsManager {
+    pub fn new() -> Self {
+        DiagnosticsManager {
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn start_ping(&mut self, target_nickname: &str) -> PingRequest {
+        let request = PingRequest::new();
+        self.pending.insert(request.ping_id.clone(), PendingPing {
+            target_nickname: target_nickname.to_string(),
+            sent_at: Instant::now(),
+        });
+        request
+    }
+
+    pub fn complete_ping(&mut self, reply: &PingReply, packet: &BitchatPacket) -> Option<PingResult> {
+        let pending = self.pending.remove(&reply.ping_id)?;
+        Some(PingResult {
+            target_nickname: pending.target_nickname,
+            round_trip: pending.sent_at.elapsed(),
+            hops_out: reply.request_hop_count,
+            hops_back: packet.hop_count,
+            route_out: reply.request_route.clone(),
+            route_back: packet.route.clone(),
+        })
+    }
+
+    /// Drops a ping that never got an answer and returns its target.
+    pub fn expire_ping(&mut self, ping_id: &str) -> Option<String> {
+        if self.pending.get(ping_id)?.sent_at.elapsed() < PING_TIMEOUT {
+            return None;
+        }
+        self.pending.remove(ping_id).map(|p| p.target_nickname)
+    }
+
+    pub fn shutdown(&mut self) {
+        self.pending.clear();
+    }
+}
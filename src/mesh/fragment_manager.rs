@@ -12,12 +12,14 @@ impl FragmentManager {
         }
     }
 
-    pub fn handle_fragment(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+    #[allow(dead_code)]
+    pub fn handle_fragment(&mut self, _packet: &[u8]) -> Option<Vec<u8>> {
         // TODO: Implement fragment handling logic
         None
     }
 
-    pub fn create_fragments(&self, message: &BitchatMessage, mtu: usize) -> Vec<Vec<u8>> {
+    #[allow(dead_code)]
+    pub fn create_fragments(&self, _message: &BitchatMessage, _mtu: usize) -> Vec<Vec<u8>> {
         // TODO: Implement fragment creation logic
         vec![]
     }
@@ -0,0 +1,677 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use crate::bitchat_packet::{GroupCiphertext, GroupControl, GroupMember, SenderKeyDistribution};
+use super::storage::{read_private, wipe_private, write_private};
+
+const GROUPS_FILE: &str = "groups.bin";
+const MESSAGE_KEY_INFO: &[u8] = b"bitchat group message";
+const MAX_SKIP: u32 = 1000;
+const MAX_SKIPPED_KEYS: usize = 200;
+const MAX_PENDING_SENDER_KEYS: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct OwnSenderKey {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+    signing_key: [u8; 32],
+}
+
+impl OwnSenderKey {
+    fn generate() -> Self {
+        OwnSenderKey {
+            key_id: rand::thread_rng().r#gen(),
+            chain_key: rand::thread_rng().r#gen(),
+            iteration: 0,
+            signing_key: SigningKey::generate(&mut rand::thread_rng()).to_bytes(),
+        }
+    }
+}
+
+/// Another member's chain, advanced as their messages arrive.
+#[derive(Serialize, Deserialize, Clone)]
+struct SenderChain {
+    sender: String,
+    chain_key: [u8; 32],
+    iteration: u32,
+    verifying_key: [u8; 32],
+    skipped: Vec<(u32, [u8; 32])>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Group {
+    id: String,
+    name: String,
+    owner: String,
+    members: Vec<GroupMember>,
+    epoch: u32,
+    my_key: OwnSenderKey,
+    chains: HashMap<u32, SenderChain>,
+}
+
+impl Group {
+    fn has_member(&self, fingerprint: &str) -> bool {
+        self.members.iter().any(|m| m.fingerprint == fingerprint)
+    }
+}
+
+/// What applying a membership update did to our view of the group.
+pub enum MembershipChange {
+    Joined(String),
+    Updated(String),
+    Removed(String),
+    Ignored,
+}
+
+/// Private groups using sender keys: each member encrypts with their own
+/// hash chain and hands its state to the others over pairwise sessions, so a
+/// message is encrypted once no matter how many members there are.
+///
+/// Members are identified by fingerprint; only the owner can change who is in
+/// a group, and every change makes all members switch to fresh sender keys.
+pub struct GroupManager {
+    path: Option<PathBuf>,
+    my_fingerprint: String,
+    groups: HashMap<String, Group>,
+    /// Sender keys that arrived before the membership update naming their
+    /// sender, applied once it comes. Not saved; members resend theirs when
+    /// they reconnect.
+    pending_keys: Vec<(String, SenderKeyDistribution)>,
+}
+
+impl GroupManager {
+    pub fn new(my_fingerprint: String) -> Self {
+        GroupManager {
+            path: None,
+            my_fingerprint,
+            groups: HashMap::new(),
+            pending_keys: Vec::new(),
+        }
+    }
+
+    /// Restores groups saved in `data_dir` and saves there from now on.
+    pub fn load(&mut self, data_dir: &Path) -> Result<()> {
+        let path = data_dir.join(GROUPS_FILE);
+        self.path = Some(path.clone());
+        if !path.exists() {
+            return Ok(());
+        }
+        let bytes = read_private(&path)?;
+        self.groups = bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+        Ok(())
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = bincode::serialize(&self.groups)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| write_private(path, &bytes));
+        if let Err(e) = result {
+            log::warn!("Failed to save groups: {}", e);
+        }
+    }
+
+    fn group_by_name(&self, name: &str) -> Result<&Group> {
+        self.groups
+            .values()
+            .find(|g| g.name == name)
+            .ok_or_else(|| anyhow!("No group named {}", name))
+    }
+
+    fn owned_group_mut(&mut self, name: &str) -> Result<&mut Group> {
+        let my_fingerprint = self.my_fingerprint.clone();
+        let group = self
+            .groups
+            .values_mut()
+            .find(|g| g.name == name)
+            .ok_or_else(|| anyhow!("No group named {}", name))?;
+        if group.owner != my_fingerprint {
+            bail!("Only the owner of {} can change its members", name);
+        }
+        Ok(group)
+    }
+
+    pub fn create(&mut self, name: &str, my_nickname: &str) -> Result<String> {
+        if self.groups.values().any(|g| g.name == name) {
+            bail!("A group named {} already exists", name);
+        }
+        let group = Group {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            owner: self.my_fingerprint.clone(),
+            members: vec![GroupMember {
+                fingerprint: self.my_fingerprint.clone(),
+                nickname: my_nickname.to_string(),
+            }],
+            epoch: 0,
+            my_key: OwnSenderKey::generate(),
+            chains: HashMap::new(),
+        };
+        let id = group.id.clone();
+        self.groups.insert(id.clone(), group);
+        self.save();
+        Ok(id)
+    }
+
+    pub fn add_member(&mut self, name: &str, member: GroupMember) -> Result<String> {
+        let group = self.owned_group_mut(name)?;
+        if group.has_member(&member.fingerprint) {
+            bail!("{} is already in {}", member.nickname, name);
+        }
+        group.members.push(member);
+        group.epoch += 1;
+        let id = group.id.clone();
+        self.save();
+        Ok(id)
+    }
+
+    /// Removes a member and forgets their chain. Returns the group ID.
+    pub fn remove_member(&mut self, name: &str, fingerprint: &str) -> Result<String> {
+        let group = self.owned_group_mut(name)?;
+        if fingerprint == group.owner {
+            bail!("The owner cannot be removed from {}", name);
+        }
+        if !group.has_member(fingerprint) {
+            bail!("That peer is not in {}", name);
+        }
+        group.members.retain(|m| m.fingerprint != fingerprint);
+        group.chains.retain(|_, chain| chain.sender != fingerprint);
+        group.epoch += 1;
+        let id = group.id.clone();
+        self.save();
+        Ok(id)
+    }
+
+    /// Fingerprints of everyone in the group except us.
+    pub fn other_members(&self, group_id: &str) -> Vec<String> {
+        self.groups
+            .get(group_id)
+            .map(|g| {
+                g.members
+                    .iter()
+                    .filter(|m| m.fingerprint != self.my_fingerprint)
+                    .map(|m| m.fingerprint.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn groups_with_member(&self, fingerprint: &str) -> Vec<String> {
+        self.groups
+            .values()
+            .filter(|g| g.has_member(fingerprint))
+            .map(|g| g.id.clone())
+            .collect()
+    }
+
+    pub fn is_owner(&self, group_id: &str) -> bool {
+        self.groups.get(group_id).is_some_and(|g| g.owner == self.my_fingerprint)
+    }
+
+    pub fn membership(&self, group_id: &str) -> Option<GroupControl> {
+        let group = self.groups.get(group_id)?;
+        Some(GroupControl::Membership {
+            group_id: group.id.clone(),
+            name: group.name.clone(),
+            owner: group.owner.clone(),
+            members: group.members.clone(),
+            epoch: group.epoch,
+        })
+    }
+
+    /// The current state of our own chain, for members who need to catch up.
+    pub fn sender_key(&self, group_id: &str) -> Option<GroupControl> {
+        let group = self.groups.get(group_id)?;
+        let signing_key = SigningKey::from_bytes(&group.my_key.signing_key);
+        Some(GroupControl::SenderKey(SenderKeyDistribution {
+            group_id: group.id.clone(),
+            key_id: group.my_key.key_id,
+            chain_key: group.my_key.chain_key,
+            iteration: group.my_key.iteration,
+            verifying_key: signing_key.verifying_key().to_bytes(),
+        }))
+    }
+
+    /// Replaces our sender key, so members who left cannot read what follows.
+    pub fn rotate_sender_key(&mut self, group_id: &str) -> Option<GroupControl> {
+        self.groups.get_mut(group_id)?.my_key = OwnSenderKey::generate();
+        self.save();
+        self.sender_key(group_id)
+    }
+
+    /// Applies a membership list sent by `from`, which must be the owner.
+    pub fn apply_membership(
+        &mut self,
+        from: &str,
+        group_id: &str,
+        name: &str,
+        owner: &str,
+        members: Vec<GroupMember>,
+        epoch: u32,
+    ) -> MembershipChange {
+        if from != owner {
+            return MembershipChange::Ignored;
+        }
+        let still_member = members.iter().any(|m| m.fingerprint == self.my_fingerprint);
+
+        let change = match self.groups.get_mut(group_id) {
+            Some(group) if group.owner != owner || epoch <= group.epoch => return MembershipChange::Ignored,
+            Some(_) if !still_member => {
+                self.groups.remove(group_id);
+                MembershipChange::Removed(name.to_string())
+            }
+            Some(group) => {
+                group.chains.retain(|_, chain| members.iter().any(|m| m.fingerprint == chain.sender));
+                group.members = members;
+                group.epoch = epoch;
+                MembershipChange::Updated(group.name.clone())
+            }
+            None if !still_member => return MembershipChange::Ignored,
+            None => {
+                // Keep local names unique; the ID is what counts on the wire.
+                let mut local_name = name.to_string();
+                while self.groups.values().any(|g| g.name == local_name) {
+                    local_name.push('\'');
+                }
+                self.groups.insert(group_id.to_string(), Group {
+                    id: group_id.to_string(),
+                    name: local_name.clone(),
+                    owner: owner.to_string(),
+                    members,
+                    epoch,
+                    my_key: OwnSenderKey::generate(),
+                    chains: HashMap::new(),
+                });
+                MembershipChange::Joined(local_name)
+            }
+        };
+        if let Some(group) = self.groups.get_mut(group_id) {
+            let (ready, waiting) = std::mem::take(&mut self.pending_keys)
+                .into_iter()
+                .partition(|(from, distribution)| distribution.group_id == group_id && group.has_member(from));
+            self.pending_keys = waiting;
+            for (from, distribution) in ready {
+                insert_chain(group, &from, distribution);
+            }
+        }
+        self.save();
+        change
+    }
+
+    /// Stores a member's sender key, replacing any older one of theirs. A key
+    /// for a group we have not heard of, or from someone not yet listed as a
+    /// member, is held until the owner's membership update arrives.
+    pub fn add_sender_key(&mut self, from: &str, distribution: SenderKeyDistribution) {
+        if from == self.my_fingerprint {
+            return;
+        }
+        match self.groups.get_mut(&distribution.group_id) {
+            Some(group) if group.has_member(from) => {
+                insert_chain(group, from, distribution);
+                self.save();
+            }
+            _ => {
+                self.pending_keys
+                    .retain(|(sender, pending)| sender != from || pending.group_id != distribution.group_id);
+                if self.pending_keys.len() >= MAX_PENDING_SENDER_KEYS {
+                    self.pending_keys.remove(0);
+                }
+                self.pending_keys.push((from.to_string(), distribution));
+            }
+        }
+    }
+
+    pub fn encrypt(&mut self, name: &str, plaintext: &[u8]) -> Result<GroupCiphertext> {
+        let group_id = self.group_by_name(name)?.id.clone();
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            bail!("No group named {}", name);
+        };
+        let key = &mut group.my_key;
+        let (chain_key, message_key) = kdf_ck(&key.chain_key);
+        let iteration = key.iteration;
+        key.chain_key = chain_key;
+        key.iteration += 1;
+
+        let mut message = GroupCiphertext {
+            group_id: group_id.clone(),
+            key_id: key.key_id,
+            iteration,
+            ciphertext: Vec::new(),
+            signature: Vec::new(),
+        };
+        message.ciphertext = seal(&message_key, &associated_data(&message), plaintext)?;
+        let signing_key = SigningKey::from_bytes(&key.signing_key);
+        message.signature = signing_key.sign(&message.signable_bytes()).to_bytes().to_vec();
+        self.save();
+        Ok(message)
+    }
+
+    /// Returns the group name, the member who sent it and the plaintext.
+    pub fn decrypt(&mut self, message: &GroupCiphertext) -> Result<(String, GroupMember, Vec<u8>)> {
+        let Some(group) = self.groups.get_mut(&message.group_id) else {
+            bail!("Not a member of this group");
+        };
+        let Some(chain) = group.chains.get_mut(&message.key_id) else {
+            bail!("No sender key {} in {}", message.key_id, group.name);
+        };
+
+        let verifying_key = VerifyingKey::from_bytes(&chain.verifying_key)?;
+        let signature = Signature::from_slice(&message.signature)?;
+        verifying_key
+            .verify(&message.signable_bytes(), &signature)
+            .map_err(|_| anyhow!("Bad signature on group message in {}", group.name))?;
+
+        let message_key = if message.iteration < chain.iteration {
+            let index = chain
+                .skipped
+                .iter()
+                .position(|(iteration, _)| *iteration == message.iteration)
+                .ok_or_else(|| anyhow!("Group message replayed or too old"))?;
+            chain.skipped.remove(index).1
+        } else {
+            if message.iteration - chain.iteration > MAX_SKIP {
+                bail!("Too many skipped group messages");
+            }
+            while chain.iteration < message.iteration {
+                let (chain_key, skipped_key) = kdf_ck(&chain.chain_key);
+                chain.skipped.push((chain.iteration, skipped_key));
+                chain.chain_key = chain_key;
+                chain.iteration += 1;
+            }
+            if chain.skipped.len() > MAX_SKIPPED_KEYS {
+                let excess = chain.skipped.len() - MAX_SKIPPED_KEYS;
+                chain.skipped.drain(..excess);
+            }
+            let (chain_key, message_key) = kdf_ck(&chain.chain_key);
+            chain.chain_key = chain_key;
+            chain.iteration += 1;
+            message_key
+        };
+
+        let plaintext = open(&message_key, &associated_data(message), &message.ciphertext)?;
+        let sender = chain.sender.clone();
+        let Some(member) = group.members.iter().find(|m| m.fingerprint == sender) else {
+            bail!("Sender key {} belongs to a former member", message.key_id);
+        };
+        let result = (group.name.clone(), member.clone(), plaintext);
+        self.save();
+        Ok(result)
+    }
+
+    pub fn shutdown(&mut self) {
+        self.save();
+        self.groups.clear();
+        self.pending_keys.clear();
+    }
+
+    /// Forgets every group and sender key, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
+        self.groups.clear();
+        self.pending_keys.clear();
+        match self.path.take() {
+            Some(path) => wipe_private(&path),
+            None => Ok(()),
+        }
+    }
+}
+
+fn insert_chain(group: &mut Group, from: &str, distribution: SenderKeyDistribution) {
+    group.chains.retain(|_, chain| chain.sender != from);
+    group.chains.insert(distribution.key_id, SenderChain {
+        sender: from.to_string(),
+        chain_key: distribution.chain_key,
+        iteration: distribution.iteration,
+        verifying_key: distribution.verifying_key,
+        skipped: Vec::new(),
+    });
+}
+
+fn kdf_ck(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |constant: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+    (step(0x02), step(0x01))
+}
+
+/// Binds the ciphertext to its group and position in the sender's chain.
+fn associated_data(message: &GroupCiphertext) -> Vec<u8> {
+    let mut aad = message.group_id.as_bytes().to_vec();
+    aad.extend_from_slice(&message.key_id.to_be_bytes());
+    aad.extend_from_slice(&message.iteration.to_be_bytes());
+    aad
+}
+
+fn message_cipher(message_key: &[u8; 32]) -> (Aes256Gcm, [u8; 12]) {
+    let hkdf = Hkdf::<Sha256>::new(None, message_key);
+    let mut okm = [0u8; 44];
+    hkdf.expand(MESSAGE_KEY_INFO, &mut okm).expect("44 bytes is a valid HKDF-SHA256 length");
+    let key: [u8; 32] = okm[..32].try_into().unwrap();
+    (Aes256Gcm::new(&key.into()), okm[32..].try_into().unwrap())
+}
+
+fn seal(message_key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| anyhow!("Group encryption failed"))
+}
+
+fn open(message_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| anyhow!("Group message failed authentication"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(fingerprint: &str) -> GroupMember {
+        GroupMember { fingerprint: fingerprint.to_string(), nickname: fingerprint.to_string() }
+    }
+
+    fn distribution(control: Option<GroupControl>) -> SenderKeyDistribution {
+        match control {
+            Some(GroupControl::SenderKey(distribution)) => distribution,
+            _ => panic!("expected a sender key"),
+        }
+    }
+
+    /// Hands the owner's current membership list to `to`.
+    fn send_membership(owner: &GroupManager, group_id: &str, to: &mut GroupManager) -> MembershipChange {
+        let Some(GroupControl::Membership { group_id, name, owner: owner_fingerprint, members, epoch }) =
+            owner.membership(group_id)
+        else {
+            panic!("expected a membership list");
+        };
+        to.apply_membership(&owner.my_fingerprint, &group_id, &name, &owner_fingerprint, members, epoch)
+    }
+
+    fn send_sender_key(from: &GroupManager, group_id: &str, to: &mut GroupManager) {
+        to.add_sender_key(&from.my_fingerprint, distribution(from.sender_key(group_id)));
+    }
+
+    /// Alice owns "team" with Bob in it, and both hold each other's sender keys.
+    fn team() -> (GroupManager, GroupManager, String) {
+        let mut alice = GroupManager::new("alice".to_string());
+        let mut bob = GroupManager::new("bob".to_string());
+        alice.create("team", "alice").unwrap();
+        let group_id = alice.add_member("team", member("bob")).unwrap();
+        assert!(matches!(send_membership(&alice, &group_id, &mut bob), MembershipChange::Joined(_)));
+        send_sender_key(&alice, &group_id, &mut bob);
+        send_sender_key(&bob, &group_id, &mut alice);
+        (alice, bob, group_id)
+    }
+
+    #[test]
+    fn group_messages_round_trip() {
+        let (mut alice, mut bob, _) = team();
+        let message = alice.encrypt("team", b"hi bob").unwrap();
+        let (name, sender, plaintext) = bob.decrypt(&message).unwrap();
+        assert_eq!(name, "team");
+        assert_eq!((sender.fingerprint.as_str(), plaintext.as_slice()), ("alice", &b"hi bob"[..]));
+
+        let message = bob.encrypt("team", b"hi alice").unwrap();
+        let (_, sender, plaintext) = alice.decrypt(&message).unwrap();
+        assert_eq!((sender.fingerprint.as_str(), plaintext.as_slice()), ("bob", &b"hi alice"[..]));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let (mut alice, mut bob, _) = team();
+        let message = alice.encrypt("team", b"payload").unwrap();
+
+        let mut body = message.clone();
+        body.ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&body).is_err());
+
+        let mut signature = message.clone();
+        signature.signature[0] ^= 1;
+        assert!(bob.decrypt(&signature).is_err());
+
+        let mut iteration = message.clone();
+        iteration.iteration += 1;
+        assert!(bob.decrypt(&iteration).is_err());
+
+        assert_eq!(bob.decrypt(&message).unwrap().2, b"payload");
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt_once() {
+        let (mut alice, mut bob, _) = team();
+        let messages: Vec<GroupCiphertext> = (0..3u8).map(|i| alice.encrypt("team", &[i]).unwrap()).collect();
+        assert_eq!(bob.decrypt(&messages[2]).unwrap().2, [2]);
+        assert_eq!(bob.decrypt(&messages[0]).unwrap().2, [0]);
+        assert_eq!(bob.decrypt(&messages[1]).unwrap().2, [1]);
+        for message in &messages {
+            assert!(bob.decrypt(message).is_err());
+        }
+    }
+
+    #[test]
+    fn too_many_skipped_messages_are_rejected() {
+        let (mut alice, mut bob, _) = team();
+        let mut message = alice.encrypt("team", b"").unwrap();
+        for _ in 0..MAX_SKIP + 1 {
+            message = alice.encrypt("team", b"far ahead").unwrap();
+        }
+        assert!(bob.decrypt(&message).is_err());
+    }
+
+    #[test]
+    fn messages_signed_with_another_key_are_rejected() {
+        let (mut alice, mut bob, group_id) = team();
+        let mut carol = GroupManager::new("carol".to_string());
+        carol.create("team", "carol").unwrap();
+        let mut forged = carol.encrypt("team", b"from alice, honest").unwrap();
+        forged.group_id = group_id;
+        forged.key_id = distribution(alice.sender_key(&forged.group_id)).key_id;
+        assert!(bob.decrypt(&forged).is_err());
+        // Bob's chain for Alice is untouched by the forgery.
+        assert_eq!(bob.decrypt(&alice.encrypt("team", b"real").unwrap()).unwrap().2, b"real");
+    }
+
+    #[test]
+    fn removed_members_cannot_read_new_messages() {
+        let (mut alice, mut bob, group_id) = team();
+        alice.remove_member("team", "bob").unwrap();
+        alice.rotate_sender_key(&group_id).unwrap();
+        assert!(matches!(send_membership(&alice, &group_id, &mut bob), MembershipChange::Removed(_)));
+        assert!(bob.decrypt(&alice.encrypt("team", b"after").unwrap()).is_err());
+    }
+
+    #[test]
+    fn a_rotated_sender_key_needs_redistributing() {
+        let (mut alice, mut bob, group_id) = team();
+        alice.rotate_sender_key(&group_id).unwrap();
+        assert!(bob.decrypt(&alice.encrypt("team", b"too early").unwrap()).is_err());
+        send_sender_key(&alice, &group_id, &mut bob);
+        assert_eq!(bob.decrypt(&alice.encrypt("team", b"new key").unwrap()).unwrap().2, b"new key");
+    }
+
+    #[test]
+    fn sender_keys_arriving_before_membership_are_kept() {
+        let (mut alice, mut bob, group_id) = team();
+        let mut carol = GroupManager::new("carol".to_string());
+        alice.add_member("team", member("carol")).unwrap();
+        send_membership(&alice, &group_id, &mut carol);
+        // Carol's key reaches Bob before Alice's update naming her does.
+        send_sender_key(&carol, &group_id, &mut bob);
+        let message = carol.encrypt("team", b"hello all").unwrap();
+        assert!(bob.decrypt(&message).is_err());
+
+        send_membership(&alice, &group_id, &mut bob);
+        let (_, sender, plaintext) = bob.decrypt(&message).unwrap();
+        assert_eq!((sender.fingerprint.as_str(), plaintext.as_slice()), ("carol", &b"hello all"[..]));
+    }
+
+    #[test]
+    fn sender_keys_arriving_before_joining_are_kept() {
+        let mut alice = GroupManager::new("alice".to_string());
+        let mut bob = GroupManager::new("bob".to_string());
+        alice.create("team", "alice").unwrap();
+        let group_id = alice.add_member("team", member("bob")).unwrap();
+        send_sender_key(&alice, &group_id, &mut bob);
+        send_membership(&alice, &group_id, &mut bob);
+        assert_eq!(bob.decrypt(&alice.encrypt("team", b"welcome").unwrap()).unwrap().2, b"welcome");
+    }
+
+    #[test]
+    fn sender_keys_from_non_members_are_not_applied() {
+        let (alice, mut bob, group_id) = team();
+        let mut mallory = GroupManager::new("mallory".to_string());
+        mallory.create("team", "mallory").unwrap();
+        let mut key = distribution(mallory.sender_key(&mallory.group_by_name("team").unwrap().id.clone()));
+        key.group_id = group_id.clone();
+        bob.add_sender_key("mallory", key);
+        // A later update that still leaves Mallory out does not let the key in.
+        let mut alice = alice;
+        alice.add_member("team", member("carol")).unwrap();
+        send_membership(&alice, &group_id, &mut bob);
+
+        let mut message = mallory.encrypt("team", b"let me in").unwrap();
+        message.group_id = group_id;
+        assert!(bob.decrypt(&message).is_err());
+    }
+
+    #[test]
+    fn membership_only_comes_from_the_owner() {
+        let (mut alice, mut bob, group_id) = team();
+        let Some(GroupControl::Membership { name, owner, mut members, epoch, .. }) = alice.membership(&group_id) else {
+            panic!("expected a membership list");
+        };
+        members.push(member("mallory"));
+        let change = bob.apply_membership("mallory", &group_id, &name, &owner, members.clone(), epoch + 1);
+        assert!(matches!(change, MembershipChange::Ignored));
+        let change = bob.apply_membership("mallory", &group_id, &name, "mallory", members, epoch + 1);
+        assert!(matches!(change, MembershipChange::Ignored));
+
+        // A replayed, older list is ignored as well.
+        let stale = alice.membership(&group_id);
+        alice.add_member("team", member("carol")).unwrap();
+        send_membership(&alice, &group_id, &mut bob);
+        let Some(GroupControl::Membership { name, owner, members, epoch, .. }) = stale else {
+            panic!("expected a membership list");
+        };
+        let change = bob.apply_membership("alice", &group_id, &name, &owner, members, epoch);
+        assert!(matches!(change, MembershipChange::Ignored));
+        assert!(bob.other_members(&group_id).contains(&"carol".to_string()));
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::SigningKey;
+use p256::{PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use super::noise::compressed_public_key;
+use super::storage::{read_private, wipe_private, write_private};
+
+const IDENTITY_FILE: &str = "identity.key";
+const SIGNING_KEY_FILE: &str = "signing.key";
+
+/// The long-term keys that identify this node across restarts: the P-256
+/// static key used by the Noise handshake and the Ed25519 key broadcasts are
+/// signed with. Session traffic always runs on fresh ephemeral keys.
+pub struct Identity {
+    static_secret: SecretKey,
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    /// Loads the identity from `data_dir`, generating and saving a new one on
+    /// first launch.
+    pub fn load_or_create(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(IDENTITY_FILE);
+        let static_secret = if path.exists() {
+            let bytes = read_private(&path)?;
+            SecretKey::from_slice(&bytes)
+                .with_context(|| format!("{} does not contain a valid identity key", path.display()))?
+        } else {
+            let static_secret = SecretKey::random(&mut rand::thread_rng());
+            write_private(&path, &static_secret.to_bytes())?;
+            static_secret
+        };
+
+        let path = data_dir.join(SIGNING_KEY_FILE);
+        let signing_key = if path.exists() {
+            let bytes = read_private(&path)?;
+            let seed: [u8; 32] = bytes
+                .as_slice()
+                .try_into()
+                .with_context(|| format!("{} does not contain a valid signing key", path.display()))?;
+            SigningKey::from_bytes(&seed)
+        } else {
+            let signing_key = SigningKey::generate(&mut rand::thread_rng());
+            write_private(&path, &signing_key.to_bytes())?;
+            signing_key
+        };
+
+        Ok(Identity { static_secret, signing_key })
+    }
+
+    /// Replaces the identity saved in `data_dir`, e.g. with one from a backup.
+    pub fn restore(data_dir: &Path, static_secret: &[u8], signing_key: &[u8; 32]) -> Result<Self> {
+        let static_secret = SecretKey::from_slice(static_secret).context("Not a valid identity key")?;
+        let signing_key = SigningKey::from_bytes(signing_key);
+        write_private(&data_dir.join(IDENTITY_FILE), &static_secret.to_bytes())?;
+        write_private(&data_dir.join(SIGNING_KEY_FILE), &signing_key.to_bytes())?;
+        Ok(Identity { static_secret, signing_key })
+    }
+
+    /// Destroys the identity saved in `data_dir`; the next load creates a new one.
+    pub fn wipe(data_dir: &Path) -> Result<()> {
+        wipe_private(&data_dir.join(IDENTITY_FILE))?;
+        wipe_private(&data_dir.join(SIGNING_KEY_FILE))
+    }
+
+    pub fn static_secret(&self) -> &SecretKey {
+        &self.static_secret
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.static_secret.public_key()
+    }
+}
+
+/// SHA-256 of the compressed static key as 16 groups of four hex digits,
+/// short enough to read aloud and compare side by side.
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    let digest = Sha256::digest(compressed_public_key(public_key));
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    group_fingerprint(&hex)
+}
+
+/// The same for an Ed25519 identity key, which channel ownership is tied to.
+pub fn signing_key_fingerprint(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    group_fingerprint(&hex)
+}
+
+pub fn group_fingerprint(fingerprint: &str) -> String {
+    let hex: Vec<char> = normalize_fingerprint(fingerprint).chars().collect();
+    hex.chunks(4).map(|group| group.iter().collect::<String>()).collect::<Vec<_>>().join(" ")
+}
+
+/// Strips spacing and case so a fingerprint typed back in still compares equal.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':' && *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// `$BITCHAT_DATA_DIR`, else `~/.bitchat`, else `.bitchat` in the working directory.
+pub fn default_data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("BITCHAT_DATA_DIR") {
+        return PathBuf::from(dir);
+    }
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".bitchat"),
+        None => PathBuf::from(".bitchat"),
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use super::identity::{normalize_fingerprint, signing_key_fingerprint};
+
+/// Marks a `/join` argument as an invite rather than a channel name.
+pub const TOKEN_PREFIX: &str = "bcinv3:";
+pub const DEFAULT_LIFETIME_HOURS: i64 = 24;
+const MAX_LIFETIME_HOURS: i64 = 24 * 30;
+
+/// The token is a bearer credential: the channel key is in it as is, and
+/// anyone who copies it has the key. The signature only proves who issued
+/// it and keeps the fields from being altered.
+#[derive(Serialize, Deserialize)]
+struct SignedInvite {
+    channel: String,
+    issuer_key: Vec<u8>,
+    issuer_nickname: String,
+    expires_at: i64,
+    key: Option<[u8; 32]>,
+    signature: Vec<u8>,
+}
+
+impl SignedInvite {
+    fn signable_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&(
+            &self.channel,
+            &self.issuer_key,
+            &self.issuer_nickname,
+            self.expires_at,
+            &self.key,
+        ))?)
+    }
+}
+
+/// A verified invite that had not expired when redeemed.
+pub struct Invite {
+    pub channel: String,
+    pub issuer_fingerprint: String,
+    pub issuer_nickname: String,
+    pub expires_at: DateTime<Utc>,
+    /// Present for password-protected channels.
+    pub key: Option<[u8; 32]>,
+}
+
+/// Issues a token for `channel`, signed with our identity signing key. The
+/// channel key travels instead of the password, which we never keep.
+///
+/// The expiry only stops clients from redeeming the token late. The key it
+/// carries works until the password changes, so set a new one to shut out
+/// everyone a token was shared with.
+pub fn create(
+    identity: &SigningKey,
+    nickname: &str,
+    channel: &str,
+    key: Option<&[u8; 32]>,
+    lifetime_hours: i64,
+) -> Result<String> {
+    if !(1..=MAX_LIFETIME_HOURS).contains(&lifetime_hours) {
+        bail!("Invites can last from 1 to {} hours", MAX_LIFETIME_HOURS);
+    }
+    let mut invite = SignedInvite {
+        channel: channel.to_string(),
+        issuer_key: identity.verifying_key().to_bytes().to_vec(),
+        issuer_nickname: nickname.to_string(),
+        expires_at: (Utc::now() + Duration::hours(lifetime_hours)).timestamp(),
+        key: key.copied(),
+        signature: Vec::new(),
+    };
+    invite.signature = identity.sign(&invite.signable_bytes()?).to_bytes().to_vec();
+    Ok(format!("{}{}", TOKEN_PREFIX, Base64UrlUnpadded::encode_string(&bincode::serialize(&invite)?)))
+}
+
+/// Checks a token's signature and expiry.
+pub fn redeem(token: &str) -> Result<Invite> {
+    let encoded = token.trim().strip_prefix(TOKEN_PREFIX).ok_or_else(|| anyhow!("Not an invite token"))?;
+    let bytes = Base64UrlUnpadded::decode_vec(encoded).map_err(|_| anyhow!("Invite token is garbled"))?;
+    let invite: SignedInvite = bincode::deserialize(&bytes).context("Invite token is garbled")?;
+
+    let issuer_key: [u8; 32] = invite.issuer_key.as_slice().try_into().context("Bad issuer key in invite")?;
+    let issuer = VerifyingKey::from_bytes(&issuer_key).context("Bad issuer key in invite")?;
+    let signature = Signature::from_slice(&invite.signature).context("Bad signature in invite")?;
+    issuer
+        .verify(&invite.signable_bytes()?, &signature)
+        .map_err(|_| anyhow!("Invite signature is invalid; the token was altered"))?;
+
+    let expires_at = DateTime::from_timestamp(invite.expires_at, 0).ok_or_else(|| anyhow!("Bad invite expiry"))?;
+    if expires_at < Utc::now() {
+        bail!("This invite to {} expired at {}", invite.channel, expires_at.format("%Y-%m-%d %H:%M UTC"));
+    }
+
+    Ok(Invite {
+        issuer_fingerprint: normalize_fingerprint(&signing_key_fingerprint(&issuer_key)),
+        channel: invite.channel,
+        issuer_nickname: invite.issuer_nickname,
+        expires_at,
+        key: invite.key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> SigningKey {
+        SigningKey::generate(&mut rand::thread_rng())
+    }
+
+    /// Decodes a token, lets `change` alter it, and encodes it again,
+    /// re-signing it with `resign` if given.
+    fn altered(token: &str, change: impl FnOnce(&mut SignedInvite), resign: Option<&SigningKey>) -> String {
+        let bytes = Base64UrlUnpadded::decode_vec(token.strip_prefix(TOKEN_PREFIX).unwrap()).unwrap();
+        let mut invite: SignedInvite = bincode::deserialize(&bytes).unwrap();
+        change(&mut invite);
+        if let Some(key) = resign {
+            invite.signature = key.sign(&invite.signable_bytes().unwrap()).to_bytes().to_vec();
+        }
+        format!("{}{}", TOKEN_PREFIX, Base64UrlUnpadded::encode_string(&bincode::serialize(&invite).unwrap()))
+    }
+
+    #[test]
+    fn invites_round_trip_with_their_issuer() {
+        let alice = identity();
+        let token = create(&alice, "alice", "#mesh", Some(&[7; 32]), 2).unwrap();
+        let invite = redeem(&format!("  {}\n", token)).unwrap();
+        assert_eq!(invite.channel, "#mesh");
+        assert_eq!(invite.issuer_nickname, "alice");
+        assert_eq!(invite.key, Some([7; 32]));
+        let fingerprint = normalize_fingerprint(&signing_key_fingerprint(&alice.verifying_key().to_bytes()));
+        assert_eq!(invite.issuer_fingerprint, fingerprint);
+        let minutes_left = (invite.expires_at - Utc::now()).num_minutes();
+        assert!((115..=120).contains(&minutes_left));
+    }
+
+    #[test]
+    fn lifetimes_are_bounded() {
+        let alice = identity();
+        assert!(create(&alice, "alice", "#mesh", None, 0).is_err());
+        assert!(create(&alice, "alice", "#mesh", None, MAX_LIFETIME_HOURS + 1).is_err());
+        assert!(create(&alice, "alice", "#mesh", None, MAX_LIFETIME_HOURS).is_ok());
+    }
+
+    #[test]
+    fn expired_invites_are_refused() {
+        let alice = identity();
+        let token = create(&alice, "alice", "#mesh", None, 1).unwrap();
+        let expired = altered(&token, |invite| invite.expires_at = Utc::now().timestamp() - 60, Some(&alice));
+        let error = redeem(&expired).err().unwrap().to_string();
+        assert!(error.contains("expired"), "{}", error);
+    }
+
+    #[test]
+    fn altered_invites_are_refused() {
+        let alice = identity();
+        let token = create(&alice, "alice", "#mesh", None, 1).unwrap();
+
+        let extended = altered(&token, |invite| invite.expires_at += 3600, None);
+        assert!(redeem(&extended).err().unwrap().to_string().contains("altered"));
+        let redirected = altered(&token, |invite| invite.channel = "#other".to_string(), None);
+        assert!(redeem(&redirected).is_err());
+
+        // Re-signed by someone else, the invite names them as its issuer.
+        let mallory = identity();
+        let forged = altered(
+            &token,
+            |invite| invite.issuer_key = mallory.verifying_key().to_bytes().to_vec(),
+            Some(&mallory),
+        );
+        let fingerprint = normalize_fingerprint(&signing_key_fingerprint(&mallory.verifying_key().to_bytes()));
+        assert_eq!(redeem(&forged).unwrap().issuer_fingerprint, fingerprint);
+    }
+
+    #[test]
+    fn other_text_is_not_an_invite() {
+        assert!(redeem("#mesh").is_err());
+        assert!(redeem(&format!("{}not base64!", TOKEN_PREFIX)).is_err());
+        assert!(redeem(&format!("{}AAAA", TOKEN_PREFIX)).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use super::service::MeshEvent;
+
+const LOG_LINES: usize = 50;
+
+/// The latest routine lines, for `/log`. Kept here rather than sent to the
+/// UI so a burst of them cannot crowd messages out of the event queue.
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Sends log records to the UI, since stderr would tear up the terminal
+/// while it is in raw mode. Warnings and errors show up as notices; the
+/// rest, mostly trouble with other nodes' traffic, is kept for `/log`.
+struct EventLogger {
+    event_tx: mpsc::Sender<MeshEvent>,
+}
+
+impl Log for EventLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let text = record.args().to_string();
+        if record.level() > Level::Warn {
+            let mut recent = RECENT.lock().unwrap();
+            if recent.len() == LOG_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(text);
+            return;
+        }
+        // A full queue means the UI is behind; a lost log line is the least of it.
+        let _ = self.event_tx.try_send(MeshEvent::Notice(text));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. Call once, before the mesh service starts.
+pub fn init(event_tx: mpsc::Sender<MeshEvent>) {
+    if log::set_boxed_logger(Box::new(EventLogger { event_tx })).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
+
+/// The latest lines below warning level, oldest first.
+pub fn recent() -> Vec<String> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn clear() {
+    RECENT.lock().unwrap().clear();
+}
@@ -1,51 +1,79 @@
-use crate::bitchat_packet::{BitchatMessage, DeliveryAck, ReadReceipt};
-use std::sync::{Arc, Mutex};
+use crate::bitchat_packet::BitchatMessage;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-pub trait MessageHandlerDelegate: Send + Sync {
-    fn on_message_received(&self, message: &BitchatMessage);
-    fn on_delivery_ack_received(&self, ack: &DeliveryAck);
-    fn on_read_receipt_received(&self, receipt: &ReadReceipt);
-}
+/// Old enough that an ack for it is not coming any more.
+const MAX_AWAITING_ACK: usize = 1000;
 
 pub struct MessageHandler {
     my_peer_id: String,
-    delegate: Option<Arc<Mutex<dyn MessageHandlerDelegate>>>,
+    /// Private messages waiting for a secure session with their recipient.
+    outbox: HashMap<String, Vec<BitchatMessage>>,
+    /// IDs of private messages we sent, oldest first, which delivery acks
+    /// may name. Acks for anything else are other peers' business.
+    awaiting_ack: HashSet<String>,
+    awaiting_ack_order: VecDeque<String>,
 }
 
 impl MessageHandler {
     pub fn new(my_peer_id: String) -> Self {
         MessageHandler {
             my_peer_id,
-            delegate: None,
+            outbox: HashMap::new(),
+            awaiting_ack: HashSet::new(),
+            awaiting_ack_order: VecDeque::new(),
         }
     }
 
-    pub fn set_delegate(&mut self, delegate: Arc<Mutex<dyn MessageHandlerDelegate>>) {
-        self.delegate = Some(delegate);
+    pub fn set_my_peer_id(&mut self, my_peer_id: String) {
+        self.my_peer_id = my_peer_id;
     }
 
-    pub fn handle_message(&self, message: &BitchatMessage) {
-        if let Some(delegate) = &self.delegate {
-            let delegate = delegate.lock().unwrap();
-            delegate.on_message_received(message);
-        }
+    pub fn queue_outgoing(&mut self, peer_id: &str, message: BitchatMessage) {
+        self.outbox.entry(peer_id.to_string()).or_default().push(message);
     }
 
-    pub fn handle_delivery_ack(&self, ack: &DeliveryAck) {
-        if let Some(delegate) = &self.delegate {
-            let delegate = delegate.lock().unwrap();
-            delegate.on_delivery_ack_received(ack);
-        }
+    pub fn take_outgoing(&mut self, peer_id: &str) -> Vec<BitchatMessage> {
+        self.outbox.remove(peer_id).unwrap_or_default()
     }
 
-    pub fn handle_read_receipt(&self, receipt: &ReadReceipt) {
-        if let Some(delegate) = &self.delegate {
-            let delegate = delegate.lock().unwrap();
-            delegate.on_read_receipt_received(receipt);
+    pub fn record_sent(&mut self, message_id: &str) {
+        if !self.awaiting_ack.insert(message_id.to_string()) {
+            return;
         }
+        self.awaiting_ack_order.push_back(message_id.to_string());
+        if self.awaiting_ack_order.len() > MAX_AWAITING_ACK
+            && let Some(oldest) = self.awaiting_ack_order.pop_front()
+        {
+            self.awaiting_ack.remove(&oldest);
+        }
+    }
+
+    /// Whether an ack for `message_id` is for one of our messages.
+    pub fn is_awaiting_ack(&self, message_id: &str) -> bool {
+        self.awaiting_ack.contains(message_id)
     }
 
     pub fn shutdown(&mut self) {
-        // No-op
+        self.outbox.clear();
+        self.awaiting_ack.clear();
+        self.awaiting_ack_order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_recent_sent_messages_await_acks() {
+        let mut handler = MessageHandler::new("me".to_string());
+        assert!(!handler.is_awaiting_ack("0"));
+        for i in 0..=MAX_AWAITING_ACK {
+            handler.record_sent(&i.to_string());
+        }
+        handler.record_sent("1");
+        assert!(!handler.is_awaiting_ack("0"));
+        assert!(handler.is_awaiting_ack("1"));
+        assert!(handler.is_awaiting_ack(&MAX_AWAITING_ACK.to_string()));
     }
 }
@@ -6,3 +6,21 @@ pub mod message_handler;
 pub mod connection_manager;
 pub mod packet_processor;
 pub mod protocol;
+pub mod sync_manager;
+pub mod relay_manager;
+pub mod diagnostics_manager;
+pub mod config;
+pub mod ratchet;
+pub mod noise;
+pub mod identity;
+pub mod storage;
+pub mod contact_store;
+pub mod channel_manager;
+pub mod signature_manager;
+pub mod group_manager;
+pub mod backup;
+pub mod block_list;
+pub mod invite;
+pub mod replay_cache;
+pub mod sealed_sender;
+pub mod logger;
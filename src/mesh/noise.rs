@@ -0,0 +1,441 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Result};
+use hkdf::Hkdf;
+use p256::{ecdh, PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
+
+/// Noise only standardises 25519 and 448; P-256 slots into the same framework
+/// and keeps us on the curve the rest of the stack already uses.
+const PROTOCOL_NAME: &[u8] = b"Noise_XX_P256_AESGCM_SHA256";
+const PROLOGUE: &[u8] = b"bitchat";
+pub const DH_LEN: usize = 33;
+const TAG_LEN: usize = 16;
+const XX_MESSAGE_COUNT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+struct CipherState {
+    key: Option<[u8; 32]>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new() -> Self {
+        CipherState { key: None, nonce: 0 }
+    }
+
+    fn initialize_key(&mut self, key: [u8; 32]) {
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let Some(key) = &self.key else {
+            return Ok(plaintext.to_vec());
+        };
+        let cipher = Aes256Gcm::new(key.into());
+        let ciphertext = cipher
+            .encrypt(&Self::nonce(self.nonce), Payload { msg: plaintext, aad: ad })
+            .map_err(|_| anyhow!("Handshake encryption failed"))?;
+        self.nonce += 1;
+        Ok(ciphertext)
+    }
+
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let Some(key) = &self.key else {
+            return Ok(ciphertext.to_vec());
+        };
+        let cipher = Aes256Gcm::new(key.into());
+        let plaintext = cipher
+            .decrypt(&Self::nonce(self.nonce), Payload { msg: ciphertext, aad: ad })
+            .map_err(|_| anyhow!("Handshake message failed authentication"))?;
+        self.nonce += 1;
+        Ok(plaintext)
+    }
+
+    fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn nonce(counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce.into()
+    }
+}
+
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    cipher: CipherState,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut hash = [0u8; 32];
+        if PROTOCOL_NAME.len() <= hash.len() {
+            hash[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+        } else {
+            hash = Sha256::digest(PROTOCOL_NAME).into();
+        }
+        SymmetricState {
+            chaining_key: hash,
+            hash,
+            cipher: CipherState::new(),
+        }
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (chaining_key, key) = hkdf2(&self.chaining_key, input_key_material);
+        self.chaining_key = chaining_key;
+        self.cipher.initialize_key(key);
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(data);
+        self.hash = hasher.finalize().into();
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = self.cipher.encrypt_with_ad(&self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = self.cipher.decrypt_with_ad(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+}
+
+/// The Noise HKDF with two outputs: HMAC-SHA256 extract keyed by the chaining
+/// key, then expand with empty info.
+fn hkdf2(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(chaining_key), input_key_material);
+    let mut okm = [0u8; 64];
+    hkdf.expand(&[], &mut okm).expect("64 bytes is a valid HKDF-SHA256 length");
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+fn dh(secret: &SecretKey, public: &PublicKey) -> [u8; 32] {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    (*shared.raw_secret_bytes()).into()
+}
+
+pub fn compressed_public_key(public_key: &PublicKey) -> Vec<u8> {
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    public_key.to_encoded_point(true).as_bytes().to_vec()
+}
+
+/// The three-message Noise XX pattern:
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// -> s, se
+/// ```
+pub struct HandshakeState {
+    role: Role,
+    symmetric: SymmetricState,
+    s: SecretKey,
+    e: Option<SecretKey>,
+    rs: Option<PublicKey>,
+    re: Option<PublicKey>,
+    message_index: usize,
+}
+
+impl HandshakeState {
+    pub fn new(role: Role, static_key: SecretKey) -> Self {
+        let mut symmetric = SymmetricState::new();
+        symmetric.mix_hash(PROLOGUE);
+        HandshakeState {
+            role,
+            symmetric,
+            s: static_key,
+            e: None,
+            rs: None,
+            re: None,
+            message_index: 0,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Index of the next handshake message, 0 to 2.
+    pub fn message_index(&self) -> usize {
+        self.message_index
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.message_index >= XX_MESSAGE_COUNT
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        let initiator_turn = self.message_index.is_multiple_of(2);
+        (self.role == Role::Initiator) == initiator_turn
+    }
+
+    pub fn remote_static(&self) -> Option<&PublicKey> {
+        self.rs.as_ref()
+    }
+
+    pub fn handshake_hash(&self) -> [u8; 32] {
+        self.symmetric.hash
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        if self.is_complete() || !self.is_my_turn() {
+            bail!("Not our turn to send a handshake message");
+        }
+
+        let mut message = Vec::new();
+        match self.message_index {
+            0 => {
+                message.extend(self.write_ephemeral());
+            }
+            1 => {
+                message.extend(self.write_ephemeral());
+                let ee = dh(self.ephemeral()?, self.remote_ephemeral()?);
+                self.symmetric.mix_key(&ee);
+                message.extend(self.write_static()?);
+                let es = dh(&self.s, self.remote_ephemeral()?);
+                self.symmetric.mix_key(&es);
+            }
+            _ => {
+                message.extend(self.write_static()?);
+                let se = dh(&self.s, self.remote_ephemeral()?);
+                self.symmetric.mix_key(&se);
+            }
+        }
+        message.extend(self.symmetric.encrypt_and_hash(payload)?);
+        self.message_index += 1;
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if self.is_complete() || self.is_my_turn() {
+            bail!("Unexpected handshake message");
+        }
+
+        let mut rest = message;
+        match self.message_index {
+            0 => {
+                rest = self.read_ephemeral(rest)?;
+            }
+            1 => {
+                rest = self.read_ephemeral(rest)?;
+                let ee = dh(self.ephemeral()?, self.remote_ephemeral()?);
+                self.symmetric.mix_key(&ee);
+                rest = self.read_static(rest)?;
+                let es = dh(self.ephemeral()?, self.remote_static_key()?);
+                self.symmetric.mix_key(&es);
+            }
+            _ => {
+                rest = self.read_static(rest)?;
+                let se = dh(self.ephemeral()?, self.remote_static_key()?);
+                self.symmetric.mix_key(&se);
+            }
+        }
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.message_index += 1;
+        Ok(payload)
+    }
+
+    /// Starting secret for the Double Ratchet, bound to the full handshake
+    /// transcript. Only valid once the handshake is complete.
+    pub fn session_secret(&self) -> Result<[u8; 32]> {
+        if !self.is_complete() {
+            bail!("Handshake is not complete");
+        }
+        let (secret, _) = hkdf2(&self.symmetric.chaining_key, &self.symmetric.hash);
+        Ok(secret)
+    }
+
+    fn write_ephemeral(&mut self) -> Vec<u8> {
+        let e = SecretKey::random(&mut rand::thread_rng());
+        let encoded = compressed_public_key(&e.public_key());
+        self.symmetric.mix_hash(&encoded);
+        self.e = Some(e);
+        encoded
+    }
+
+    fn write_static(&mut self) -> Result<Vec<u8>> {
+        let encoded = compressed_public_key(&self.s.public_key());
+        self.symmetric.encrypt_and_hash(&encoded)
+    }
+
+    fn read_ephemeral<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8]> {
+        if data.len() < DH_LEN {
+            bail!("Handshake message too short");
+        }
+        let (encoded, rest) = data.split_at(DH_LEN);
+        self.re = Some(PublicKey::from_sec1_bytes(encoded)?);
+        self.symmetric.mix_hash(encoded);
+        Ok(rest)
+    }
+
+    fn read_static<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8]> {
+        let len = if self.symmetric.cipher.has_key() { DH_LEN + TAG_LEN } else { DH_LEN };
+        if data.len() < len {
+            bail!("Handshake message too short");
+        }
+        let (encrypted, rest) = data.split_at(len);
+        let encoded = self.symmetric.decrypt_and_hash(encrypted)?;
+        self.rs = Some(PublicKey::from_sec1_bytes(&encoded)?);
+        Ok(rest)
+    }
+
+    fn ephemeral(&self) -> Result<&SecretKey> {
+        self.e.as_ref().ok_or_else(|| anyhow!("Missing local ephemeral key"))
+    }
+
+    fn remote_ephemeral(&self) -> Result<&PublicKey> {
+        self.re.as_ref().ok_or_else(|| anyhow!("Missing remote ephemeral key"))
+    }
+
+    fn remote_static_key(&self) -> Result<&PublicKey> {
+        self.rs.as_ref().ok_or_else(|| anyhow!("Missing remote static key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key() -> SecretKey {
+        SecretKey::random(&mut rand::thread_rng())
+    }
+
+    /// Runs a full handshake and returns both finished states.
+    fn handshake(initiator_key: SecretKey, responder_key: SecretKey) -> (HandshakeState, HandshakeState) {
+        let mut initiator = HandshakeState::new(Role::Initiator, initiator_key);
+        let mut responder = HandshakeState::new(Role::Responder, responder_key);
+        let first = initiator.write_message(b"").unwrap();
+        responder.read_message(&first).unwrap();
+        let second = responder.write_message(b"").unwrap();
+        initiator.read_message(&second).unwrap();
+        let third = initiator.write_message(b"").unwrap();
+        responder.read_message(&third).unwrap();
+        (initiator, responder)
+    }
+
+    #[test]
+    fn handshake_round_trip_agrees_on_keys_and_payloads() {
+        let initiator_key = new_key();
+        let responder_key = new_key();
+        let mut initiator = HandshakeState::new(Role::Initiator, initiator_key.clone());
+        let mut responder = HandshakeState::new(Role::Responder, responder_key.clone());
+
+        let first = initiator.write_message(b"hello").unwrap();
+        assert_eq!(responder.read_message(&first).unwrap(), b"hello");
+        let second = responder.write_message(b"ratchet key").unwrap();
+        assert_eq!(initiator.read_message(&second).unwrap(), b"ratchet key");
+        let third = initiator.write_message(b"signing key").unwrap();
+        assert_eq!(responder.read_message(&third).unwrap(), b"signing key");
+
+        assert!(initiator.is_complete() && responder.is_complete());
+        assert_eq!(initiator.remote_static(), Some(&responder_key.public_key()));
+        assert_eq!(responder.remote_static(), Some(&initiator_key.public_key()));
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        assert_eq!(initiator.session_secret().unwrap(), responder.session_secret().unwrap());
+    }
+
+    #[test]
+    fn static_keys_are_not_sent_in_the_clear() {
+        let responder_key = new_key();
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, responder_key.clone());
+        responder.read_message(&initiator.write_message(b"").unwrap()).unwrap();
+        let second = responder.write_message(b"").unwrap();
+
+        let encoded = compressed_public_key(&responder_key.public_key());
+        assert!(!second.windows(encoded.len()).any(|window| window == encoded.as_slice()));
+    }
+
+    #[test]
+    fn separate_handshakes_derive_separate_secrets() {
+        let initiator_key = new_key();
+        let responder_key = new_key();
+        let (first, _) = handshake(initiator_key.clone(), responder_key.clone());
+        let (second, _) = handshake(initiator_key, responder_key);
+        assert_ne!(first.session_secret().unwrap(), second.session_secret().unwrap());
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, new_key());
+        responder.read_message(&initiator.write_message(b"").unwrap()).unwrap();
+        let mut second = responder.write_message(b"payload").unwrap();
+        let last = second.len() - 1;
+        second[last] ^= 1;
+        assert!(initiator.read_message(&second).is_err());
+
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, new_key());
+        responder.read_message(&initiator.write_message(b"").unwrap()).unwrap();
+        initiator.read_message(&responder.write_message(b"").unwrap()).unwrap();
+        let mut third = initiator.write_message(b"").unwrap();
+        third[DH_LEN] ^= 1;
+        assert!(responder.read_message(&third).is_err());
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, new_key());
+        let first = initiator.write_message(b"").unwrap();
+        assert!(responder.read_message(&first[..DH_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn messages_out_of_turn_or_replayed_are_rejected() {
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, new_key());
+        assert!(responder.write_message(b"").is_err());
+
+        let first = initiator.write_message(b"").unwrap();
+        assert!(initiator.write_message(b"").is_err());
+        responder.read_message(&first).unwrap();
+        // The responder now expects to send, not to read the first message again.
+        assert!(responder.read_message(&first).is_err());
+
+        let second = responder.write_message(b"").unwrap();
+        initiator.read_message(&second).unwrap();
+        let third = initiator.write_message(b"").unwrap();
+        responder.read_message(&third).unwrap();
+        assert!(responder.read_message(&third).is_err());
+        assert!(initiator.write_message(b"").is_err());
+    }
+
+    #[test]
+    fn session_secret_needs_a_complete_handshake() {
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        initiator.write_message(b"").unwrap();
+        assert!(initiator.session_secret().is_err());
+    }
+
+    #[test]
+    fn messages_for_another_handshake_are_rejected() {
+        let mut initiator = HandshakeState::new(Role::Initiator, new_key());
+        let mut other = HandshakeState::new(Role::Initiator, new_key());
+        let mut responder = HandshakeState::new(Role::Responder, new_key());
+        other.write_message(b"").unwrap();
+        responder.read_message(&initiator.write_message(b"").unwrap()).unwrap();
+        let second = responder.write_message(b"").unwrap();
+        // Same shape, but sealed for another initiator's ephemeral key.
+        assert!(other.read_message(&second).is_err());
+    }
+}
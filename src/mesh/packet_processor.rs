@@ -1,21 +1,50 @@
-use crate::bitchat_packet::BitchatMessage;
+use crate::bitchat_packet::{
+    BitchatMessage, BitchatPacket, ChannelAnnounce, DeliveryAck, GroupCiphertext, PeerAnnounce, PingReply,
+    OnionLayer, PingRequest, SealedEnvelope,
+};
+use super::block_list::BlockList;
+use super::connection_manager::BluetoothConnectionManagerDelegate;
 use super::protocol::MessageType;
-use anyhow::Result;
+use super::signature_manager::{SignatureManager, SignatureStatus};
+use super::sync_manager::SyncRequest;
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::VerifyingKey;
 use std::sync::{Arc, Mutex};
 
 pub trait PacketProcessorDelegate: Send + Sync {
-    fn handle_message(&self, message: &BitchatMessage);
+    /// Our current peer ID, or the one before the last rotation.
+    fn is_my_peer_id(&self, peer_id: &str) -> bool;
+    fn handle_message(&self, message: &BitchatMessage, packet: &BitchatPacket);
+    fn handle_announce(&self, nickname: &str, peer_id: &str);
+    fn handle_sync_request(&self, request: &SyncRequest, peer_id: &str);
+    fn handle_delivery_ack(&self, ack: &DeliveryAck, packet: &BitchatPacket);
+    fn handle_ping(&self, request: &PingRequest, packet: &BitchatPacket);
+    fn handle_pong(&self, reply: &PingReply, packet: &BitchatPacket);
+    fn handle_key_exchange(&self, packet: &BitchatPacket);
+    fn handle_channel_announce(&self, announce: &ChannelAnnounce, packet: &BitchatPacket);
+    fn handle_group_control(&self, packet: &BitchatPacket);
+    fn handle_group_message(&self, message: &GroupCiphertext, packet: &BitchatPacket);
+    /// Returns the packet inside a sealed envelope addressed to us.
+    fn open_sealed(&self, envelope: &SealedEnvelope) -> Option<Vec<u8>>;
+    /// Passes the rest of an onion-routed packet on to its next hop.
+    fn forward_onion(&self, next_hop: &str, envelope: &SealedEnvelope);
+    fn handle_relay(&self, packet: &BitchatPacket);
 }
 
 pub struct PacketProcessor {
-    my_peer_id: String,
+    signature_manager: Arc<Mutex<SignatureManager>>,
+    block_list: Arc<Mutex<BlockList>>,
     delegate: Option<Arc<Mutex<dyn PacketProcessorDelegate>>>,
 }
 
 impl PacketProcessor {
-    pub fn new(my_peer_id: String) -> Self {
+    pub fn new(
+        signature_manager: Arc<Mutex<SignatureManager>>,
+        block_list: Arc<Mutex<BlockList>>,
+    ) -> Self {
         PacketProcessor {
-            my_peer_id,
+            signature_manager,
+            block_list,
             delegate: None,
         }
     }
@@ -24,14 +53,235 @@ impl PacketProcessor {
         self.delegate = Some(delegate);
     }
 
-    pub fn process_packet(&self, packet: &[u8], peer_id: &str) -> Result<()> {
-        let message_type = packet[0];
-        match message_type {
-            t if t == MessageType::Message as u8 => {
-                let message = BitchatMessage::from_binary_payload(&packet[1..])?;
+    /// The service owns our peer ID; asking it keeps the processor out of
+    /// the service's hands, so the two are only ever locked in that order.
+    fn is_my_peer_id(&self, peer_id: &str) -> bool {
+        self.delegate.as_ref().is_some_and(|delegate| delegate.lock().unwrap().is_my_peer_id(peer_id))
+    }
+
+    pub fn process_packet(&self, data: &[u8], _peer_id: &str) -> Result<()> {
+        self.process(BitchatPacket::from_bytes(data)?, false)
+    }
+
+    /// Decrypts an envelope sealed to us; `None` if it was not ours to open.
+    fn open_envelope(&self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let envelope: SealedEnvelope = bincode::deserialize(payload)?;
+        Ok(match &self.delegate {
+            Some(delegate) => delegate.lock().unwrap().open_sealed(&envelope),
+            None => None,
+        })
+    }
+
+    /// Handles the packet found in a sealed envelope or an onion's last layer.
+    fn process_unsealed(&self, inner: &[u8]) -> Result<()> {
+        // Anything but a packet for us inside would be relayed, or
+        // unwrapped again, on the sender's behalf.
+        let inner = BitchatPacket::from_bytes(inner)?;
+        if !inner.recipient_id.as_deref().is_some_and(|id| self.is_my_peer_id(id))
+            || inner.message_type == MessageType::SealedMessage as u8
+            || inner.message_type == MessageType::OnionMessage as u8
+        {
+            bail!("Sealed envelope holds a packet not meant for us");
+        }
+        self.process(inner, true)
+    }
+
+    /// Hands a message or emergency packet to the delegate. `synced` marks
+    /// the author's packet replayed in a sync response.
+    fn deliver_message(
+        &self,
+        packet: &BitchatPacket,
+        signature_status: SignatureStatus,
+        sealed: bool,
+        synced: bool,
+    ) -> Result<()> {
+        let mut message = BitchatMessage::from_binary_payload(&packet.payload)?;
+        // Someone else may replay a blocked peer's message during sync.
+        if message
+            .sender_peer_id
+            .as_deref()
+            .is_some_and(|id| self.block_list.lock().unwrap().is_blocked(id))
+        {
+            return Ok(());
+        }
+        message.hop_count = Some(packet.hop_count);
+        message.route = packet.route.clone();
+        message.is_emergency = packet.message_type == MessageType::Emergency as u8;
+        message.sealed = sealed;
+        message.synced = synced;
+        message.signature_status = Some(signature_status);
+        if let Some(delegate) = &self.delegate {
+            let delegate = delegate.lock().unwrap();
+            delegate.handle_message(&message, packet);
+        }
+        Ok(())
+    }
+
+    /// `sealed` marks a packet taken out of a sealed envelope.
+    fn process(&self, packet: BitchatPacket, sealed: bool) -> Result<()> {
+        if self.is_my_peer_id(&packet.sender_id) {
+            return Ok(());
+        }
+
+        // Packets addressed to someone else are only passed along.
+        if let Some(recipient_id) = &packet.recipient_id
+            && !self.is_my_peer_id(recipient_id)
+        {
+            if let Some(delegate) = &self.delegate {
+                let delegate = delegate.lock().unwrap();
+                delegate.handle_relay(&packet);
+            }
+            return Ok(());
+        }
+
+        // Blocked peers still announce and handshake, which is how their
+        // rotated peer IDs get tied back to the blocked identity.
+        if packet.message_type != MessageType::Announce as u8
+            && packet.message_type != MessageType::KeyExchange as u8
+            && self.block_list.lock().unwrap().is_blocked(&packet.sender_id)
+        {
+            let relay = self.block_list.lock().unwrap().relays_blocked();
+            if relay && let Some(delegate) = &self.delegate {
+                let delegate = delegate.lock().unwrap();
+                delegate.handle_relay(&packet);
+            }
+            return Ok(());
+        }
+
+        // Announces carry the key they are signed with and are checked on their
+        // own. Sealed envelopes are unsigned; the packet inside is checked.
+        let signature_status = if packet.message_type == MessageType::Announce as u8
+            || packet.message_type == MessageType::SealedMessage as u8
+            || packet.message_type == MessageType::OnionMessage as u8
+        {
+            SignatureStatus::Valid
+        } else {
+            let signature_manager = self.signature_manager.lock().unwrap();
+            let status = signature_manager.verify(&packet);
+            if status.is_rejected_by(signature_manager.policy()) {
+                return Ok(());
+            }
+            status
+        };
+
+        match packet.message_type {
+            t if t == MessageType::Message as u8 || t == MessageType::Emergency as u8 => {
+                self.deliver_message(&packet, signature_status, sealed, false)?;
+            }
+            t if t == MessageType::SyncResponse as u8 => {
+                // The payload is the author's own packet, so the message is
+                // checked against the author's signature, not the replayer's.
+                let original = BitchatPacket::from_bytes(&packet.payload)?;
+                if original.recipient_id.is_some()
+                    || (original.message_type != MessageType::Message as u8
+                        && original.message_type != MessageType::Emergency as u8)
+                {
+                    bail!("Sync response holds a packet that is not a public message");
+                }
+                if self.is_my_peer_id(&original.sender_id)
+                    || self.block_list.lock().unwrap().is_blocked(&original.sender_id)
+                {
+                    return Ok(());
+                }
+                let signature_manager = self.signature_manager.lock().unwrap();
+                // Without the author's key there is nothing to check it against.
+                let status = match signature_manager.verify(&original) {
+                    SignatureStatus::Valid => SignatureStatus::Valid,
+                    SignatureStatus::Invalid => SignatureStatus::Invalid,
+                    _ => SignatureStatus::Unsigned,
+                };
+                if status.is_rejected_by(signature_manager.policy()) {
+                    return Ok(());
+                }
+                drop(signature_manager);
+                self.deliver_message(&original, status, false, true)?;
+            }
+            t if t == MessageType::Announce as u8 => {
+                let announce = self.verify_announce(&packet)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_announce(&announce.nickname, &packet.sender_id);
+                }
+            }
+            t if t == MessageType::SyncRequest as u8 => {
+                let request: SyncRequest = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_sync_request(&request, &packet.sender_id);
+                }
+            }
+            t if t == MessageType::DeliveryAck as u8 => {
+                let ack: DeliveryAck = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_delivery_ack(&ack, &packet);
+                }
+            }
+            t if t == MessageType::KeyExchange as u8 => {
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_key_exchange(&packet);
+                }
+            }
+            t if t == MessageType::Ping as u8 => {
+                let request: PingRequest = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_ping(&request, &packet);
+                }
+            }
+            t if t == MessageType::Pong as u8 => {
+                let reply: PingReply = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_pong(&reply, &packet);
+                }
+            }
+            t if t == MessageType::ChannelAnnounce as u8 => {
+                let announce: ChannelAnnounce = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_channel_announce(&announce, &packet);
+                }
+            }
+            t if t == MessageType::GroupControl as u8 => {
                 if let Some(delegate) = &self.delegate {
                     let delegate = delegate.lock().unwrap();
-                    delegate.handle_message(&message);
+                    delegate.handle_group_control(&packet);
+                }
+            }
+            t if t == MessageType::GroupMessage as u8 => {
+                let message: GroupCiphertext = bincode::deserialize(&packet.payload)?;
+                if let Some(delegate) = &self.delegate {
+                    let delegate = delegate.lock().unwrap();
+                    delegate.handle_group_message(&message, &packet);
+                }
+            }
+            t if t == MessageType::SealedMessage as u8 => {
+                if packet.recipient_id.is_none() {
+                    bail!("Sealed envelope without a recipient");
+                }
+                let Some(inner) = self.open_envelope(&packet.payload)? else {
+                    return Ok(());
+                };
+                self.process_unsealed(&inner)?;
+            }
+            t if t == MessageType::OnionMessage as u8 => {
+                if packet.recipient_id.is_none() {
+                    bail!("Onion layer without a recipient");
+                }
+                let Some(layer) = self.open_envelope(&packet.payload)? else {
+                    return Ok(());
+                };
+                let layer: OnionLayer = bincode::deserialize(&layer)?;
+                match layer.next_hop {
+                    Some(next_hop) => {
+                        let envelope: SealedEnvelope = bincode::deserialize(&layer.payload)?;
+                        if let Some(delegate) = &self.delegate {
+                            delegate.lock().unwrap().forward_onion(&next_hop, &envelope);
+                        }
+                    }
+                    None => self.process_unsealed(&layer.payload)?,
                 }
             }
             _ => {
@@ -41,7 +291,177 @@ impl PacketProcessor {
         Ok(())
     }
 
-    pub fn shutdown(&mut self) {
-        // No-op
+    /// Checks the announce is signed with the key it carries and that the key
+    /// matches any key already known for the sender.
+    fn verify_announce(&self, packet: &BitchatPacket) -> Result<PeerAnnounce> {
+        let announce: PeerAnnounce = bincode::deserialize(&packet.payload)?;
+        let key_bytes: [u8; 32] = announce
+            .signing_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Bad signing key length in announce"))?;
+        let key = VerifyingKey::from_bytes(&key_bytes)?;
+        if SignatureManager::verify_with(&key, packet) != SignatureStatus::Valid {
+            bail!("Announce from {} is not signed by its own key", packet.sender_id);
+        }
+        if !self.signature_manager.lock().unwrap().learn_announced_key(&packet.sender_id, key) {
+            bail!("Announce from {} uses a different signing key than before", packet.sender_id);
+        }
+        Ok(announce)
+    }
+}
+
+impl BluetoothConnectionManagerDelegate for PacketProcessor {
+    fn on_packet_received(&self, packet: &[u8], peer_id: &str) {
+        if let Err(e) = self.process_packet(packet, peer_id) {
+            log::debug!("Dropping packet from {}: {}", peer_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::signature_manager::SignaturePolicy;
+    use ed25519_dalek::SigningKey;
+
+    /// Keeps the messages handed over, with the packet each was checked against.
+    #[derive(Default)]
+    struct Recorder {
+        messages: Mutex<Vec<(BitchatMessage, BitchatPacket)>>,
+    }
+
+    impl PacketProcessorDelegate for Recorder {
+        fn is_my_peer_id(&self, peer_id: &str) -> bool {
+            peer_id == "me"
+        }
+        fn handle_message(&self, message: &BitchatMessage, packet: &BitchatPacket) {
+            self.messages.lock().unwrap().push((message.clone(), packet.clone()));
+        }
+        fn handle_announce(&self, _: &str, _: &str) {}
+        fn handle_sync_request(&self, _: &SyncRequest, _: &str) {}
+        fn handle_delivery_ack(&self, _: &DeliveryAck, _: &BitchatPacket) {}
+        fn handle_ping(&self, _: &PingRequest, _: &BitchatPacket) {}
+        fn handle_pong(&self, _: &PingReply, _: &BitchatPacket) {}
+        fn handle_key_exchange(&self, _: &BitchatPacket) {}
+        fn handle_channel_announce(&self, _: &ChannelAnnounce, _: &BitchatPacket) {}
+        fn handle_group_control(&self, _: &BitchatPacket) {}
+        fn handle_group_message(&self, _: &GroupCiphertext, _: &BitchatPacket) {}
+        fn open_sealed(&self, _: &SealedEnvelope) -> Option<Vec<u8>> {
+            None
+        }
+        fn forward_onion(&self, _: &str, _: &SealedEnvelope) {}
+        fn handle_relay(&self, _: &BitchatPacket) {}
+    }
+
+    struct Mesh {
+        processor: PacketProcessor,
+        signature_manager: Arc<Mutex<SignatureManager>>,
+        recorder: Arc<Mutex<Recorder>>,
+    }
+
+    impl Mesh {
+        fn new(policy: SignaturePolicy) -> Self {
+            let signature_manager = Arc::new(Mutex::new(SignatureManager::new(key(), policy)));
+            let block_list = Arc::new(Mutex::new(BlockList::new(false)));
+            let mut processor = PacketProcessor::new(signature_manager.clone(), block_list);
+            let recorder = Arc::new(Mutex::new(Recorder::default()));
+            processor.set_delegate(recorder.clone());
+            Mesh { processor, signature_manager, recorder }
+        }
+
+        fn learn(&self, peer_id: &str, key: &SigningKey) {
+            self.signature_manager.lock().unwrap().learn_announced_key(peer_id, key.verifying_key());
+        }
+
+        fn receive(&self, packet: &BitchatPacket) -> Result<()> {
+            self.processor.process_packet(&packet.to_bytes()?, "relay")
+        }
+
+        fn received(&self) -> Vec<(BitchatMessage, BitchatPacket)> {
+            self.recorder.lock().unwrap().messages.lock().unwrap().clone()
+        }
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::generate(&mut rand::thread_rng())
+    }
+
+    fn signed(message_type: MessageType, sender_id: &str, payload: Vec<u8>, key: &SigningKey) -> BitchatPacket {
+        let mut packet = BitchatPacket::new(message_type as u8, sender_id.to_string(), payload);
+        SignatureManager::new(key.clone(), SignaturePolicy::Flag).sign(&mut packet);
+        packet
+    }
+
+    fn authored_by(author: &str, key: Option<&SigningKey>) -> BitchatPacket {
+        let payload = BitchatMessage::new(author.to_string(), "hi".to_string()).to_binary_payload().unwrap();
+        match key {
+            Some(key) => signed(MessageType::Message, author, payload, key),
+            None => BitchatPacket::new(MessageType::Message as u8, author.to_string(), payload),
+        }
+    }
+
+    fn sync_response(original: &BitchatPacket, relay_key: &SigningKey) -> BitchatPacket {
+        signed(MessageType::SyncResponse, "relay", original.to_bytes().unwrap(), relay_key)
+    }
+
+    #[test]
+    fn synced_messages_are_checked_against_the_author() {
+        let (alice, relay) = (key(), key());
+        let mesh = Mesh::new(SignaturePolicy::Drop);
+        mesh.learn("alice", &alice);
+        mesh.learn("relay", &relay);
+
+        mesh.receive(&sync_response(&authored_by("alice", Some(&alice)), &relay)).unwrap();
+        let received = mesh.received();
+        assert_eq!(received.len(), 1);
+        let (message, packet) = &received[0];
+        assert!(message.synced);
+        assert_eq!(message.signature_status, Some(SignatureStatus::Valid));
+        assert_eq!(packet.sender_id, "alice");
+    }
+
+    #[test]
+    fn unverifiable_synced_messages_are_dropped_under_the_drop_policy() {
+        let (alice, relay) = (key(), key());
+        let mesh = Mesh::new(SignaturePolicy::Drop);
+        mesh.learn("relay", &relay);
+
+        // Signed, but we never learned Alice's key.
+        mesh.receive(&sync_response(&authored_by("alice", Some(&alice)), &relay)).unwrap();
+        // No signature at all, relayed by a peer whose own signature is fine.
+        mesh.receive(&sync_response(&authored_by("alice", None), &relay)).unwrap();
+        // Signed by the relay, claiming to be Alice.
+        mesh.learn("alice", &alice);
+        mesh.receive(&sync_response(&authored_by("alice", Some(&relay)), &relay)).unwrap();
+        assert!(mesh.received().is_empty());
+    }
+
+    #[test]
+    fn unverifiable_synced_messages_are_flagged_under_the_flag_policy() {
+        let (alice, relay) = (key(), key());
+        let mesh = Mesh::new(SignaturePolicy::Flag);
+        mesh.learn("relay", &relay);
+
+        mesh.receive(&sync_response(&authored_by("alice", Some(&alice)), &relay)).unwrap();
+        let received = mesh.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0.signature_status, Some(SignatureStatus::Unsigned));
+    }
+
+    #[test]
+    fn sync_responses_only_carry_public_messages() {
+        let (alice, relay) = (key(), key());
+        let mesh = Mesh::new(SignaturePolicy::Flag);
+        mesh.learn("alice", &alice);
+        mesh.learn("relay", &relay);
+
+        let mut addressed = authored_by("alice", Some(&alice));
+        addressed.recipient_id = Some("me".to_string());
+        assert!(mesh.receive(&sync_response(&addressed, &relay)).is_err());
+
+        let announce = signed(MessageType::Announce, "alice", Vec::new(), &alice);
+        assert!(mesh.receive(&sync_response(&announce, &relay)).is_err());
+        assert!(mesh.received().is_empty());
     }
 }
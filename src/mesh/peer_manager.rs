@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use chrono::{DateTime, Utc};
-use std::sync::{Arc, Mutex};
 
+/// RSSI and announce tracking are not wired to the transport yet.
+#[allow(dead_code)]
 pub struct Peer {
     pub nickname: String,
     pub last_seen: DateTime<Utc>,
@@ -9,29 +10,19 @@ pub struct Peer {
     pub announced_to: bool,
 }
 
-pub trait PeerManagerDelegate: Send + Sync {
-    fn on_peer_connected(&self, nickname: &str);
-    fn on_peer_disconnected(&self, nickname: &str);
-    fn on_peer_list_updated(&self, peer_ids: &[String]);
-}
-
 pub struct PeerManager {
     peers: HashMap<String, Peer>,
-    delegate: Option<Arc<Mutex<dyn PeerManagerDelegate>>>,
 }
 
 impl PeerManager {
     pub fn new() -> Self {
         PeerManager {
             peers: HashMap::new(),
-            delegate: None,
         }
     }
 
-    pub fn set_delegate(&mut self, delegate: Arc<Mutex<dyn PeerManagerDelegate>>) {
-        self.delegate = Some(delegate);
-    }
-
+    /// Returns true for a peer not seen before; the caller greets it. This
+    /// runs under the service lock, so there is no callback into the service.
     pub fn add_or_update_peer(&mut self, peer_id: &str, nickname: &str) -> bool {
         let is_new = !self.peers.contains_key(peer_id);
         let peer = self.peers.entry(peer_id.to_string()).or_insert_with(|| {
@@ -45,53 +36,70 @@ impl PeerManager {
 
         peer.nickname = nickname.to_string();
         peer.last_seen = Utc::now();
-
-        if is_new {
-            if let Some(delegate) = &self.delegate {
-                let delegate = delegate.lock().unwrap();
-                delegate.on_peer_connected(nickname);
-                delegate.on_peer_list_updated(&self.get_all_peer_ids());
-            }
-        }
         is_new
     }
 
+    /// Folds the entry for a peer's previous ID into its new one after a
+    /// handshake showed both belong to the same identity.
+    pub fn merge_peer(&mut self, old_peer_id: &str, new_peer_id: &str) {
+        let Some(old) = self.peers.remove(old_peer_id) else {
+            return;
+        };
+        self.peers.entry(new_peer_id.to_string()).or_insert(old);
+    }
+
+    #[allow(dead_code)]
     pub fn remove_peer(&mut self, peer_id: &str) {
-        if let Some(peer) = self.peers.remove(peer_id) {
-            if let Some(delegate) = &self.delegate {
-                let delegate = delegate.lock().unwrap();
-                delegate.on_peer_disconnected(&peer.nickname);
-                delegate.on_peer_list_updated(&self.get_all_peer_ids());
-            }
-        }
+        self.peers.remove(peer_id);
     }
 
+    #[allow(dead_code)]
     pub fn update_peer_last_seen(&mut self, peer_id: &str) {
         if let Some(peer) = self.peers.get_mut(peer_id) {
             peer.last_seen = Utc::now();
         }
     }
 
+    #[allow(dead_code)]
     pub fn update_peer_rssi(&mut self, peer_id: &str, rssi: i32) {
         if let Some(peer) = self.peers.get_mut(peer_id) {
             peer.rssi = rssi;
         }
     }
 
+    #[allow(dead_code)]
     pub fn mark_peer_as_announced_to(&mut self, peer_id: &str) {
         if let Some(peer) = self.peers.get_mut(peer_id) {
             peer.announced_to = true;
         }
     }
 
+    #[allow(dead_code)]
     pub fn has_announced_to_peer(&self, peer_id: &str) -> bool {
-        self.peers.get(peer_id).map_or(false, |p| p.announced_to)
+        self.peers.get(peer_id).is_some_and(|p| p.announced_to)
     }
 
     pub fn get_peer_nickname(&self, peer_id: &str) -> Option<String> {
         self.peers.get(peer_id).map(|p| p.nickname.clone())
     }
 
+    pub fn find_peer_id_by_nickname(&self, nickname: &str) -> Option<String> {
+        self.peers
+            .iter()
+            .find(|(_, peer)| peer.nickname == nickname)
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Every peer using `nickname`; nicknames are not unique.
+    pub fn find_peer_ids_by_nickname(&self, nickname: &str) -> Vec<String> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.nickname == nickname)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    #[allow(dead_code)]
     pub fn get_all_peer_nicknames(&self) -> HashMap<String, String> {
         self.peers
             .iter()
@@ -99,6 +107,7 @@ impl PeerManager {
             .collect()
     }
 
+    #[allow(dead_code)]
     pub fn get_all_peer_rssi(&self) -> HashMap<String, i32> {
         self.peers
             .iter()
@@ -110,10 +119,12 @@ impl PeerManager {
         self.peers.keys().cloned().collect()
     }
 
+    #[allow(dead_code)]
     pub fn get_active_peer_count(&self) -> usize {
         self.peers.len()
     }
 
+    #[allow(dead_code)]
     pub fn is_peer_active(&self, peer_id: &str) -> bool {
         self.peers.contains_key(peer_id)
     }
@@ -1,3 +1,5 @@
+/// Every wire value, including ones nothing sends yet.
+#[allow(dead_code)]
 #[repr(u8)]
 pub enum MessageType {
     Message = 0x01,
@@ -7,4 +9,14 @@ pub enum MessageType {
     Fragment = 0x05,
     DeliveryAck = 0x06,
     ReadReceipt = 0x07,
+    SyncRequest = 0x08,
+    SyncResponse = 0x09,
+    Ping = 0x0A,
+    Pong = 0x0B,
+    Emergency = 0x0C,
+    ChannelAnnounce = 0x0D,
+    GroupControl = 0x0E,
+    GroupMessage = 0x0F,
+    SealedMessage = 0x10,
+    OnionMessage = 0x11,
 }
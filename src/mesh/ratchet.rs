@@ -0,0 +1,426 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Result};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use p256::{ecdh, PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use super::noise::{compressed_public_key, DH_LEN};
+
+const ROOT_INFO: &[u8] = b"bitchat ratchet root";
+const MESSAGE_KEY_INFO: &[u8] = b"bitchat ratchet message";
+const HEADER_LEN: usize = DH_LEN + 8;
+/// Most message keys we will derive ahead to reach a late-numbered message.
+const MAX_SKIP: u32 = 1000;
+/// Skipped keys kept around for messages that arrive out of order.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Message key for a message we have not received yet. Deleted as soon as
+/// it is used, like every other message key.
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    ratchet_key: Vec<u8>,
+    number: u32,
+    message_key: [u8; 32],
+}
+
+struct Header {
+    ratchet_key: Vec<u8>,
+    previous_chain_length: u32,
+    number: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&self.ratchet_key);
+        bytes.extend_from_slice(&self.previous_chain_length.to_be_bytes());
+        bytes.extend_from_slice(&self.number.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != HEADER_LEN {
+            bail!("Bad ratchet header length");
+        }
+        Ok(Header {
+            ratchet_key: bytes[..DH_LEN].to_vec(),
+            previous_chain_length: u32::from_be_bytes(bytes[DH_LEN..DH_LEN + 4].try_into()?),
+            number: u32::from_be_bytes(bytes[DH_LEN + 4..].try_into()?),
+        })
+    }
+}
+
+/// Signal-style Double Ratchet. Every message gets its own key from a
+/// symmetric chain, and each change of speaker mixes a fresh DH exchange
+/// into the root key, so a seized device cannot decrypt earlier traffic.
+///
+/// Keys are kept as raw bytes so the whole session can be written to disk.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RatchetSession {
+    associated_data: [u8; 32],
+    root_key: [u8; 32],
+    dh_self: [u8; 32],
+    dh_remote: Vec<u8>,
+    send_chain: [u8; 32],
+    recv_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    previous_send_count: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+impl RatchetSession {
+    /// The initiator starts sending on a chain derived from the responder's
+    /// ratchet key, which arrived in the second handshake message.
+    pub fn new_initiator(
+        shared_secret: [u8; 32],
+        associated_data: [u8; 32],
+        dh_self: &SecretKey,
+        remote_ratchet_key: &PublicKey,
+    ) -> Self {
+        let (root_key, send_chain) = kdf_rk(&shared_secret, &dh(dh_self, remote_ratchet_key));
+        RatchetSession {
+            associated_data,
+            root_key,
+            dh_self: dh_self.to_bytes().into(),
+            dh_remote: compressed_public_key(remote_ratchet_key),
+            send_chain,
+            recv_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// The responder already knows the initiator's ratchet key from the final
+    /// handshake message, so it ratchets once up front and can send right away.
+    pub fn new_responder(
+        shared_secret: [u8; 32],
+        associated_data: [u8; 32],
+        dh_self: &SecretKey,
+        remote_ratchet_key: &PublicKey,
+    ) -> Self {
+        let (root_key, recv_chain) = kdf_rk(&shared_secret, &dh(dh_self, remote_ratchet_key));
+        let next_dh = SecretKey::random(&mut rand::thread_rng());
+        let (root_key, send_chain) = kdf_rk(&root_key, &dh(&next_dh, remote_ratchet_key));
+        RatchetSession {
+            associated_data,
+            root_key,
+            dh_self: next_dh.to_bytes().into(),
+            dh_remote: compressed_public_key(remote_ratchet_key),
+            send_chain,
+            recv_chain: Some(recv_chain),
+            send_count: 0,
+            recv_count: 0,
+            previous_send_count: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Returns `[header][ciphertext]`.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (send_chain, message_key) = kdf_ck(&self.send_chain);
+        let header = Header {
+            ratchet_key: compressed_public_key(&self.dh_self_key()?.public_key()),
+            previous_chain_length: self.previous_send_count,
+            number: self.send_count,
+        };
+        self.send_chain = send_chain;
+        self.send_count += 1;
+
+        let header = header.to_bytes();
+        let mut message = header.clone();
+        message.extend(seal(&message_key, &self.aad(&header), plaintext)?);
+        Ok(message)
+    }
+
+    /// Decrypts a message, including ones that arrive out of order. The
+    /// session is left untouched if the message does not authenticate.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if message.len() < HEADER_LEN {
+            bail!("Ratchet message too short");
+        }
+        let (header_bytes, ciphertext) = message.split_at(HEADER_LEN);
+        let header = Header::from_bytes(header_bytes)?;
+        let aad = self.aad(header_bytes);
+
+        if let Some(index) = self
+            .skipped
+            .iter()
+            .position(|k| k.ratchet_key == header.ratchet_key && k.number == header.number)
+        {
+            let plaintext = open(&self.skipped[index].message_key, &aad, ciphertext)?;
+            self.skipped.remove(index);
+            return Ok(plaintext);
+        }
+
+        let mut next = self.clone();
+        if header.ratchet_key != next.dh_remote {
+            next.skip_message_keys(header.previous_chain_length)?;
+            next.dh_ratchet(&header)?;
+        }
+        next.skip_message_keys(header.number)?;
+        let recv_chain = next.recv_chain.ok_or_else(|| anyhow!("No receiving chain"))?;
+        let (recv_chain, message_key) = kdf_ck(&recv_chain);
+        next.recv_chain = Some(recv_chain);
+        next.recv_count += 1;
+
+        let plaintext = open(&message_key, &aad, ciphertext)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        let Some(mut recv_chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until > self.recv_count.saturating_add(MAX_SKIP) {
+            bail!("Too many skipped messages");
+        }
+        while self.recv_count < until {
+            let (next_chain, message_key) = kdf_ck(&recv_chain);
+            self.skipped.push(SkippedKey {
+                ratchet_key: self.dh_remote.clone(),
+                number: self.recv_count,
+                message_key,
+            });
+            recv_chain = next_chain;
+            self.recv_count += 1;
+        }
+        self.recv_chain = Some(recv_chain);
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &Header) -> Result<()> {
+        let remote = PublicKey::from_sec1_bytes(&header.ratchet_key)?;
+        self.previous_send_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = header.ratchet_key.clone();
+
+        let (root_key, recv_chain) = kdf_rk(&self.root_key, &dh(&self.dh_self_key()?, &remote));
+        let next_dh = SecretKey::random(&mut rand::thread_rng());
+        let (root_key, send_chain) = kdf_rk(&root_key, &dh(&next_dh, &remote));
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = send_chain;
+        self.dh_self = next_dh.to_bytes().into();
+        Ok(())
+    }
+
+    fn dh_self_key(&self) -> Result<SecretKey> {
+        Ok(SecretKey::from_slice(&self.dh_self)?)
+    }
+
+    fn aad(&self, header: &[u8]) -> Vec<u8> {
+        let mut aad = self.associated_data.to_vec();
+        aad.extend_from_slice(header);
+        aad
+    }
+}
+
+fn dh(secret: &SecretKey, public: &PublicKey) -> [u8; 32] {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    (*shared.raw_secret_bytes()).into()
+}
+
+fn kdf_rk(root_key: &[u8; 32], dh_output: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::new(Some(root_key), dh_output);
+    let mut okm = [0u8; 64];
+    hkdf.expand(ROOT_INFO, &mut okm).expect("64 bytes is a valid HKDF-SHA256 length");
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+/// Returns the next chain key and the message key for this step.
+fn kdf_ck(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |constant: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).expect("HMAC accepts any key length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+    (step(0x02), step(0x01))
+}
+
+fn message_cipher(message_key: &[u8; 32]) -> (Aes256Gcm, [u8; 12]) {
+    let hkdf = Hkdf::<Sha256>::new(None, message_key);
+    let mut okm = [0u8; 44];
+    hkdf.expand(MESSAGE_KEY_INFO, &mut okm).expect("44 bytes is a valid HKDF-SHA256 length");
+    let key: [u8; 32] = okm[..32].try_into().unwrap();
+    (Aes256Gcm::new(&key.into()), okm[32..].try_into().unwrap())
+}
+
+fn seal(message_key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| anyhow!("Encryption failed"))
+}
+
+fn open(message_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| anyhow!("Message failed authentication"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key() -> SecretKey {
+        SecretKey::random(&mut rand::thread_rng())
+    }
+
+    /// Sessions as both ends of a handshake would set them up.
+    fn pair_with(shared_secret: [u8; 32], associated_data: [u8; 32]) -> (RatchetSession, RatchetSession) {
+        let initiator_ratchet = new_key();
+        let responder_ratchet = new_key();
+        let initiator = RatchetSession::new_initiator(
+            shared_secret,
+            associated_data,
+            &initiator_ratchet,
+            &responder_ratchet.public_key(),
+        );
+        let responder = RatchetSession::new_responder(
+            shared_secret,
+            associated_data,
+            &responder_ratchet,
+            &initiator_ratchet.public_key(),
+        );
+        (initiator, responder)
+    }
+
+    fn pair() -> (RatchetSession, RatchetSession) {
+        pair_with([7; 32], [9; 32])
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_directions() {
+        let (mut alice, mut bob) = pair();
+        for round in 0..3 {
+            let text = format!("from alice {}", round);
+            let message = alice.encrypt(text.as_bytes()).unwrap();
+            assert_eq!(bob.decrypt(&message).unwrap(), text.as_bytes());
+
+            let text = format!("from bob {}", round);
+            let message = bob.encrypt(text.as_bytes()).unwrap();
+            assert_eq!(alice.decrypt(&message).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn responder_can_send_first() {
+        let (mut alice, mut bob) = pair();
+        let message = bob.encrypt(b"first").unwrap();
+        assert_eq!(alice.decrypt(&message).unwrap(), b"first");
+    }
+
+    #[test]
+    fn every_message_gets_a_fresh_key() {
+        let (mut alice, _) = pair();
+        let first = alice.encrypt(b"same").unwrap();
+        let second = alice.encrypt(b"same").unwrap();
+        assert_ne!(first[HEADER_LEN..], second[HEADER_LEN..]);
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt_once() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<Vec<u8>> = (0..4u8).map(|i| alice.encrypt(&[i]).unwrap()).collect();
+        assert_eq!(bob.decrypt(&messages[3]).unwrap(), [3]);
+        assert_eq!(bob.decrypt(&messages[1]).unwrap(), [1]);
+        assert_eq!(bob.decrypt(&messages[0]).unwrap(), [0]);
+        assert_eq!(bob.decrypt(&messages[2]).unwrap(), [2]);
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn skipped_messages_survive_a_dh_ratchet() {
+        let (mut alice, mut bob) = pair();
+        let late = alice.encrypt(b"late").unwrap();
+        let on_time = alice.encrypt(b"on time").unwrap();
+        bob.decrypt(&on_time).unwrap();
+        alice.decrypt(&bob.encrypt(b"reply").unwrap()).unwrap();
+        let next = alice.encrypt(b"next chain").unwrap();
+        assert_eq!(bob.decrypt(&next).unwrap(), b"next chain");
+        assert_eq!(bob.decrypt(&late).unwrap(), b"late");
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.encrypt(b"once").unwrap();
+        let second = alice.encrypt(b"twice").unwrap();
+        bob.decrypt(&first).unwrap();
+        assert!(bob.decrypt(&first).is_err());
+        // A key kept for a skipped message is gone once used, too.
+        let third = alice.encrypt(b"thrice").unwrap();
+        bob.decrypt(&third).unwrap();
+        bob.decrypt(&second).unwrap();
+        assert!(bob.decrypt(&second).is_err());
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected_and_leave_the_session_intact() {
+        let (mut alice, mut bob) = pair();
+        let message = alice.encrypt(b"payload").unwrap();
+
+        let mut body = message.clone();
+        let last = body.len() - 1;
+        body[last] ^= 1;
+        assert!(bob.decrypt(&body).is_err());
+
+        let mut header = message.clone();
+        header[HEADER_LEN - 1] ^= 1;
+        assert!(bob.decrypt(&header).is_err());
+
+        assert!(bob.decrypt(&message[..HEADER_LEN - 1]).is_err());
+        assert_eq!(bob.decrypt(&message).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn too_many_skipped_messages_are_rejected() {
+        let (mut alice, mut bob) = pair();
+        let mut message = Vec::new();
+        for _ in 0..=MAX_SKIP + 1 {
+            message = alice.encrypt(b"far ahead").unwrap();
+        }
+        assert!(bob.decrypt(&message).is_err());
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn sessions_with_other_secrets_cannot_decrypt() {
+        let (mut alice, _) = pair_with([1; 32], [9; 32]);
+        let (_, mut wrong_secret) = pair_with([2; 32], [9; 32]);
+        let message = alice.encrypt(b"secret").unwrap();
+        assert!(wrong_secret.decrypt(&message).is_err());
+
+        // Same keys, bound to another handshake transcript.
+        let initiator_ratchet = new_key();
+        let responder_ratchet = new_key();
+        let mut alice =
+            RatchetSession::new_initiator([1; 32], [3; 32], &initiator_ratchet, &responder_ratchet.public_key());
+        let mut bob =
+            RatchetSession::new_responder([1; 32], [4; 32], &responder_ratchet, &initiator_ratchet.public_key());
+        assert!(bob.decrypt(&alice.encrypt(b"secret").unwrap()).is_err());
+    }
+
+    #[test]
+    fn saved_sessions_carry_on() {
+        let (mut alice, bob) = pair();
+        let message = alice.encrypt(b"before saving").unwrap();
+        let mut bob: RatchetSession = bincode::deserialize(&bincode::serialize(&bob).unwrap()).unwrap();
+        assert_eq!(bob.decrypt(&message).unwrap(), b"before saving");
+        assert_eq!(alice.decrypt(&bob.encrypt(b"after").unwrap()).unwrap(), b"after");
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use crate::bitchat_packet::{BitchatPacket, DEFAULT_TTL};
+
+const MAX_SEEN_PACKETS: usize = 4096;
+const MAX_QUEUED_PACKETS: usize = 256;
+const MAX_QUEUED_EMERGENCIES: usize = 32;
+/// Emergencies skip the bandwidth cap, but only this many per window.
+const EMERGENCY_BURST: usize = 8;
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayMode {
+    All,
+    Never,
+    /// Only public messages in these channels are relayed.
+    Channels(HashSet<String>),
+}
+
+/// How much of its own battery and airtime a node spends on other people's traffic.
+#[derive(Debug, Clone)]
+pub struct RelayPolicy {
+    pub mode: RelayMode,
+    pub max_hops: u8,
+    /// Bytes per second; `None` means unlimited.
+    pub max_bandwidth: Option<u64>,
+    pub relay_direct_messages: bool,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy {
+            mode: RelayMode::All,
+            max_hops: DEFAULT_TTL,
+            max_bandwidth: None,
+            relay_direct_messages: true,
+        }
+    }
+}
+
+/// What a packet carries, as far as the relay policy is concerned.
+pub enum RelayKind<'a> {
+    Public { channel: Option<&'a str> },
+    Direct,
+    /// Skips the bandwidth cap, the hop limit and channel filters, within
+    /// its own small per-second allowance. Only for validly signed packets.
+    Emergency,
+}
+
+/// Decides which packets get flooded onwards and remembers what has already
+/// been forwarded so a packet never loops through the mesh.
+pub struct RelayManager {
+    policy: RelayPolicy,
+    seen_packets: HashSet<[u8; 32]>,
+    seen_order: VecDeque<[u8; 32]>,
+    window_start: Instant,
+    window_bytes: u64,
+    window_emergencies: usize,
+    emergency_queue: VecDeque<BitchatPacket>,
+    normal_queue: VecDeque<BitchatPacket>,
+}
+
+impl RelayManager {
+    pub fn new(policy: RelayPolicy) -> Self {
+        RelayManager {
+            policy,
+            seen_packets: HashSet::new(),
+            seen_order: VecDeque::new(),
+            window_start: Instant::now(),
+            window_bytes: 0,
+            window_emergencies: 0,
+            emergency_queue: VecDeque::new(),
+            normal_queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues the relayed copy of a packet unless it was already relayed, its
+    /// TTL is used up or the relay policy rules it out.
+    pub fn queue_relay(&mut self, packet: &BitchatPacket, kind: RelayKind, my_peer_id: &str) {
+        if !self.mark_seen(packet) || !self.policy_allows(packet, &kind) {
+            return;
+        }
+        let Some(relayed) = packet.relayed(my_peer_id) else {
+            return;
+        };
+
+        if matches!(kind, RelayKind::Emergency) {
+            self.emergency_queue.push_back(relayed);
+            if self.emergency_queue.len() > MAX_QUEUED_EMERGENCIES {
+                self.emergency_queue.pop_front();
+            }
+        } else {
+            self.normal_queue.push_back(relayed);
+            if self.normal_queue.len() > MAX_QUEUED_PACKETS {
+                self.normal_queue.pop_front();
+            }
+        }
+    }
+
+    /// Returns the packets that may go out now: emergency packets first, up
+    /// to their allowance, then as many normal packets as the bandwidth
+    /// budget allows.
+    pub fn take_ready_packets(&mut self) -> Vec<BitchatPacket> {
+        self.roll_window();
+        let emergencies = EMERGENCY_BURST.saturating_sub(self.window_emergencies).min(self.emergency_queue.len());
+        self.window_emergencies += emergencies;
+        let mut ready: Vec<BitchatPacket> = self.emergency_queue.drain(..emergencies).collect();
+        while let Some(packet) = self.normal_queue.front() {
+            if !self.consume_bandwidth(packet.payload.len() as u64) {
+                break;
+            }
+            ready.extend(self.normal_queue.pop_front());
+        }
+        ready
+    }
+
+    fn policy_allows(&self, packet: &BitchatPacket, kind: &RelayKind) -> bool {
+        if matches!(kind, RelayKind::Emergency) {
+            return self.policy.mode != RelayMode::Never;
+        }
+        if packet.hop_count >= self.policy.max_hops {
+            return false;
+        }
+        // A packet over the whole per-second budget would block the queue for good.
+        if self.policy.max_bandwidth.is_some_and(|max_bandwidth| packet.payload.len() as u64 > max_bandwidth) {
+            return false;
+        }
+        match kind {
+            RelayKind::Direct => {
+                self.policy.mode != RelayMode::Never && self.policy.relay_direct_messages
+            }
+            RelayKind::Public { channel } => match &self.policy.mode {
+                RelayMode::All => true,
+                RelayMode::Never => false,
+                RelayMode::Channels(channels) => channel.is_some_and(|c| channels.contains(c)),
+            },
+            RelayKind::Emergency => true,
+        }
+    }
+
+    fn consume_bandwidth(&mut self, bytes: u64) -> bool {
+        let Some(max_bandwidth) = self.policy.max_bandwidth else {
+            return true;
+        };
+        if self.window_bytes + bytes > max_bandwidth {
+            return false;
+        }
+        self.window_bytes += bytes;
+        true
+    }
+
+    fn roll_window(&mut self) {
+        if self.window_start.elapsed() >= BANDWIDTH_WINDOW {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+            self.window_emergencies = 0;
+        }
+    }
+
+    /// Records a packet and returns `false` if it was seen before. Only the
+    /// fields relays cannot change are hashed.
+    pub fn mark_seen(&mut self, packet: &BitchatPacket) -> bool {
+        let digest = Self::packet_digest(packet);
+        if !self.seen_packets.insert(digest) {
+            return false;
+        }
+        self.seen_order.push_back(digest);
+        while self.seen_order.len() > MAX_SEEN_PACKETS {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen_packets.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn packet_digest(packet: &BitchatPacket) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([packet.message_type]);
+        hasher.update(packet.sender_id.as_bytes());
+        hasher.update(&packet.payload);
+        hasher.finalize().into()
+    }
+
+    pub fn shutdown(&mut self) {
+        self.seen_packets.clear();
+        self.seen_order.clear();
+        self.emergency_queue.clear();
+        self.normal_queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::protocol::MessageType;
+
+    fn packet(fill: u8, len: usize) -> BitchatPacket {
+        BitchatPacket::new(MessageType::Message as u8, "alice".to_string(), vec![fill; len])
+    }
+
+    fn relay(manager: &mut RelayManager, packet: &BitchatPacket, kind: RelayKind) -> usize {
+        manager.queue_relay(packet, kind, "me");
+        manager.take_ready_packets().len()
+    }
+
+    fn next_window(manager: &mut RelayManager) {
+        manager.window_start = Instant::now() - BANDWIDTH_WINDOW;
+    }
+
+    #[test]
+    fn relayed_copies_carry_one_more_hop_and_are_sent_once() {
+        let mut manager = RelayManager::new(RelayPolicy::default());
+        let original = packet(1, 10);
+        manager.queue_relay(&original, RelayKind::Public { channel: None }, "me");
+        let ready = manager.take_ready_packets();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].hop_count, original.hop_count + 1);
+        assert_eq!(ready[0].ttl, original.ttl - 1);
+
+        assert_eq!(relay(&mut manager, &original, RelayKind::Public { channel: None }), 0);
+    }
+
+    #[test]
+    fn never_mode_relays_nothing() {
+        let policy = RelayPolicy { mode: RelayMode::Never, ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        assert_eq!(relay(&mut manager, &packet(1, 10), RelayKind::Public { channel: None }), 0);
+        assert_eq!(relay(&mut manager, &packet(2, 10), RelayKind::Direct), 0);
+        assert_eq!(relay(&mut manager, &packet(3, 10), RelayKind::Emergency), 0);
+    }
+
+    #[test]
+    fn channel_mode_relays_only_the_listed_channels() {
+        let channels = HashSet::from(["#mesh".to_string()]);
+        let policy = RelayPolicy { mode: RelayMode::Channels(channels), ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        assert_eq!(relay(&mut manager, &packet(1, 10), RelayKind::Public { channel: Some("#mesh") }), 1);
+        assert_eq!(relay(&mut manager, &packet(2, 10), RelayKind::Public { channel: Some("#other") }), 0);
+        assert_eq!(relay(&mut manager, &packet(3, 10), RelayKind::Public { channel: None }), 0);
+        assert_eq!(relay(&mut manager, &packet(4, 10), RelayKind::Direct), 1);
+        assert_eq!(relay(&mut manager, &packet(5, 10), RelayKind::Emergency), 1);
+    }
+
+    #[test]
+    fn direct_messages_can_be_left_alone() {
+        let policy = RelayPolicy { relay_direct_messages: false, ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        assert_eq!(relay(&mut manager, &packet(1, 10), RelayKind::Direct), 0);
+        assert_eq!(relay(&mut manager, &packet(2, 10), RelayKind::Public { channel: None }), 1);
+    }
+
+    #[test]
+    fn hop_limit_stops_all_but_emergencies() {
+        let policy = RelayPolicy { max_hops: 2, ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        let mut near = packet(1, 10);
+        near.hop_count = 1;
+        assert_eq!(relay(&mut manager, &near, RelayKind::Public { channel: None }), 1);
+
+        let mut far = packet(2, 10);
+        far.hop_count = 2;
+        assert_eq!(relay(&mut manager, &far, RelayKind::Public { channel: None }), 0);
+        let mut far_emergency = packet(3, 10);
+        far_emergency.hop_count = 2;
+        assert_eq!(relay(&mut manager, &far_emergency, RelayKind::Emergency), 1);
+
+        let mut spent = packet(4, 10);
+        spent.ttl = 1;
+        assert_eq!(relay(&mut manager, &spent, RelayKind::Emergency), 0);
+    }
+
+    #[test]
+    fn bandwidth_cap_holds_packets_until_the_next_window() {
+        let policy = RelayPolicy { max_bandwidth: Some(250), ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        for fill in 0..3 {
+            manager.queue_relay(&packet(fill, 100), RelayKind::Public { channel: None }, "me");
+        }
+        assert_eq!(manager.take_ready_packets().len(), 2);
+        assert!(manager.take_ready_packets().is_empty());
+
+        next_window(&mut manager);
+        assert_eq!(manager.take_ready_packets().len(), 1);
+    }
+
+    #[test]
+    fn packets_larger_than_the_cap_do_not_block_the_queue() {
+        let policy = RelayPolicy { max_bandwidth: Some(250), ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        manager.queue_relay(&packet(1, 300), RelayKind::Public { channel: None }, "me");
+        manager.queue_relay(&packet(2, 100), RelayKind::Public { channel: None }, "me");
+        let ready = manager.take_ready_packets();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].payload.len(), 100);
+    }
+
+    #[test]
+    fn emergencies_skip_the_cap_within_their_burst() {
+        let policy = RelayPolicy { max_bandwidth: Some(100), ..RelayPolicy::default() };
+        let mut manager = RelayManager::new(policy);
+        for fill in 0..EMERGENCY_BURST as u8 + 2 {
+            manager.queue_relay(&packet(fill, 500), RelayKind::Emergency, "me");
+        }
+        manager.queue_relay(&packet(100, 50), RelayKind::Public { channel: None }, "me");
+
+        let ready = manager.take_ready_packets();
+        assert_eq!(ready.len(), EMERGENCY_BURST + 1);
+        assert!(ready[..EMERGENCY_BURST].iter().all(|packet| packet.payload.len() == 500));
+        assert!(manager.take_ready_packets().is_empty());
+
+        next_window(&mut manager);
+        assert_eq!(manager.take_ready_packets().len(), 2);
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::bitchat_packet::BitchatMessage;
+use super::storage::{read_private, wipe_private, write_private};
+use super::sync_manager::SYNC_WINDOW_HOURS;
+
+const REPLAY_CACHE_FILE: &str = "replay.bin";
+const MAX_CACHED_IDS: usize = 20_000;
+const MAX_ONION_LAYERS: usize = 4096;
+
+/// Why a message was turned away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replay {
+    /// Its ID was seen before, possibly in an earlier run.
+    Duplicate,
+    /// Its timestamp is further from our clock than we accept.
+    OutsideWindow,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SeenId {
+    timestamp: i64,
+    /// Claimed by a copy with a valid signature. Anyone can stamp a forged
+    /// or unsigned copy with a message's ID, so such a copy only holds the ID
+    /// until a signed one arrives.
+    verified: bool,
+}
+
+/// Message IDs seen recently, with their timestamps, kept across restarts.
+///
+/// A live message must be stamped within `max_skew` of our clock. Sync
+/// responses carry messages from up to the sync window ago, so they may be
+/// older. Either way an ID is remembered until its timestamp leaves every
+/// window, and a replay from then on fails the timestamp check instead.
+pub struct ReplayCache {
+    path: Option<PathBuf>,
+    max_skew: Duration,
+    seen: HashMap<String, SeenId>,
+    dirty: bool,
+    /// Hashes of the ephemeral keys of onion layers we forwarded, oldest
+    /// first. Layers carry no timestamp, so only the newest are kept.
+    onion_layers: HashSet<[u8; 32]>,
+    onion_order: VecDeque<[u8; 32]>,
+}
+
+impl ReplayCache {
+    pub fn new(max_skew: Duration) -> Self {
+        ReplayCache {
+            path: None,
+            max_skew,
+            seen: HashMap::new(),
+            dirty: false,
+            onion_layers: HashSet::new(),
+            onion_order: VecDeque::new(),
+        }
+    }
+
+    /// Restores IDs saved in `data_dir` and saves there from now on.
+    pub fn load(&mut self, data_dir: &Path) -> Result<()> {
+        let path = data_dir.join(REPLAY_CACHE_FILE);
+        self.path = Some(path.clone());
+        if !path.exists() {
+            return Ok(());
+        }
+        let bytes = read_private(&path)?;
+        self.seen = bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+        self.prune();
+        Ok(())
+    }
+
+    /// Writes the cache if it changed; called periodically rather than per
+    /// message, since a busy mesh would otherwise rewrite it constantly.
+    pub fn save(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !self.dirty {
+            return;
+        }
+        let result = bincode::serialize(&self.seen)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| write_private(path, &bytes));
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(e) => log::warn!("Failed to save replay cache: {}", e),
+        }
+    }
+
+    /// Records a message unless it is a replay. `from_sync` allows timestamps
+    /// from as far back as the sync window. A message whose signature checked
+    /// out is only a replay of another one that did.
+    pub fn check(&mut self, message: &BitchatMessage, from_sync: bool, verified: bool) -> Result<(), Replay> {
+        let now = Utc::now();
+        let max_skew = chrono::Duration::from_std(self.max_skew).unwrap_or(chrono::Duration::MAX);
+        let max_age = if from_sync { max_skew.max(chrono::Duration::hours(SYNC_WINDOW_HOURS)) } else { max_skew };
+        if message.timestamp > now + max_skew || message.timestamp < now - max_age {
+            return Err(Replay::OutsideWindow);
+        }
+        if self.seen.get(&message.id).is_some_and(|seen| seen.verified || !verified) {
+            return Err(Replay::Duplicate);
+        }
+        self.remember(&message.id, message.timestamp, verified);
+        Ok(())
+    }
+
+    /// Records an onion layer about to be forwarded, and returns false if it
+    /// was forwarded before. Every layer is sealed to a fresh key, so a
+    /// repeat can only be a replay, which would otherwise let an observer
+    /// follow the packet by sending it again and watching where it goes.
+    pub fn check_onion(&mut self, ephemeral_key: &[u8]) -> bool {
+        let hash: [u8; 32] = Sha256::digest(ephemeral_key).into();
+        if !self.onion_layers.insert(hash) {
+            return false;
+        }
+        self.onion_order.push_back(hash);
+        if self.onion_order.len() > MAX_ONION_LAYERS
+            && let Some(oldest) = self.onion_order.pop_front()
+        {
+            self.onion_layers.remove(&oldest);
+        }
+        true
+    }
+
+    /// Records one of our own messages so echoes of it are dropped as well.
+    pub fn record_own(&mut self, message: &BitchatMessage) {
+        self.remember(&message.id, message.timestamp, true);
+    }
+
+    fn remember(&mut self, id: &str, timestamp: DateTime<Utc>, verified: bool) {
+        self.seen.insert(id.to_string(), SeenId { timestamp: timestamp.timestamp_millis(), verified });
+        self.dirty = true;
+        if self.seen.len() > MAX_CACHED_IDS {
+            self.prune();
+        }
+    }
+
+    /// Forgets IDs whose timestamps no window accepts any more, then the
+    /// oldest ones if the cache is still over its limit.
+    fn prune(&mut self) {
+        let retention = self.max_skew.max(Duration::from_secs(SYNC_WINDOW_HOURS as u64 * 3600));
+        let cutoff = Utc::now().timestamp_millis() - retention.as_millis() as i64;
+        let before = self.seen.len();
+        self.seen.retain(|_, seen| seen.timestamp >= cutoff);
+        if self.seen.len() > MAX_CACHED_IDS {
+            let mut timestamps: Vec<i64> = self.seen.values().map(|seen| seen.timestamp).collect();
+            timestamps.sort_unstable();
+            let cutoff = timestamps[self.seen.len() - MAX_CACHED_IDS];
+            self.seen.retain(|_, seen| seen.timestamp >= cutoff);
+        }
+        self.dirty |= self.seen.len() != before;
+    }
+
+    pub fn shutdown(&mut self) {
+        self.save();
+        self.seen.clear();
+        self.onion_layers.clear();
+        self.onion_order.clear();
+    }
+
+    /// Forgets every ID, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
+        self.seen.clear();
+        self.onion_layers.clear();
+        self.onion_order.clear();
+        self.dirty = false;
+        match self.path.take() {
+            Some(path) => wipe_private(&path),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SKEW: Duration = Duration::from_secs(60);
+
+    fn message_at(timestamp: DateTime<Utc>) -> BitchatMessage {
+        let mut message = BitchatMessage::new("alice".to_string(), "hi".to_string());
+        message.timestamp = timestamp;
+        message
+    }
+
+    #[test]
+    fn live_messages_must_be_within_the_clock_skew() {
+        let mut cache = ReplayCache::new(SKEW);
+        let now = Utc::now();
+        assert_eq!(cache.check(&message_at(now), false, true), Ok(()));
+        assert_eq!(cache.check(&message_at(now - chrono::Duration::seconds(30)), false, true), Ok(()));
+        let stale = message_at(now - chrono::Duration::minutes(5));
+        assert_eq!(cache.check(&stale, false, true), Err(Replay::OutsideWindow));
+        let early = message_at(now + chrono::Duration::minutes(5));
+        assert_eq!(cache.check(&early, false, true), Err(Replay::OutsideWindow));
+        assert_eq!(cache.check(&early, true, true), Err(Replay::OutsideWindow));
+    }
+
+    #[test]
+    fn synced_messages_may_be_as_old_as_the_sync_window() {
+        let mut cache = ReplayCache::new(SKEW);
+        let now = Utc::now();
+        let hours_ago = message_at(now - chrono::Duration::hours(SYNC_WINDOW_HOURS - 1));
+        assert_eq!(cache.check(&hours_ago, false, true), Err(Replay::OutsideWindow));
+        assert_eq!(cache.check(&hours_ago, true, true), Ok(()));
+        let too_old = message_at(now - chrono::Duration::hours(SYNC_WINDOW_HOURS + 1));
+        assert_eq!(cache.check(&too_old, true, true), Err(Replay::OutsideWindow));
+    }
+
+    #[test]
+    fn repeated_ids_are_duplicates() {
+        let mut cache = ReplayCache::new(SKEW);
+        let message = message_at(Utc::now());
+        assert_eq!(cache.check(&message, false, true), Ok(()));
+        assert_eq!(cache.check(&message, false, true), Err(Replay::Duplicate));
+        assert_eq!(cache.check(&message, true, true), Err(Replay::Duplicate));
+        assert_eq!(cache.check(&message, false, false), Err(Replay::Duplicate));
+
+        let own = message_at(Utc::now());
+        cache.record_own(&own);
+        assert_eq!(cache.check(&own, false, true), Err(Replay::Duplicate));
+    }
+
+    #[test]
+    fn a_verified_copy_overrides_an_unverified_claim() {
+        let mut cache = ReplayCache::new(SKEW);
+        let message = message_at(Utc::now());
+        assert_eq!(cache.check(&message, false, false), Ok(()));
+        assert_eq!(cache.check(&message, false, false), Err(Replay::Duplicate));
+        assert_eq!(cache.check(&message, false, true), Ok(()));
+        assert_eq!(cache.check(&message, false, true), Err(Replay::Duplicate));
+    }
+
+    #[test]
+    fn expired_ids_are_pruned() {
+        let mut cache = ReplayCache::new(SKEW);
+        let old = Utc::now() - chrono::Duration::hours(SYNC_WINDOW_HOURS + 1);
+        cache.remember("old", old, true);
+        cache.remember("new", Utc::now(), true);
+        cache.prune();
+        assert!(!cache.seen.contains_key("old"));
+        assert!(cache.seen.contains_key("new"));
+    }
+
+    #[test]
+    fn the_oldest_ids_go_once_the_cache_is_full() {
+        let mut cache = ReplayCache::new(SKEW);
+        let now = Utc::now();
+        for i in 0..=MAX_CACHED_IDS as i64 {
+            cache.remember(&i.to_string(), now - chrono::Duration::milliseconds(MAX_CACHED_IDS as i64 - i), true);
+        }
+        assert_eq!(cache.seen.len(), MAX_CACHED_IDS);
+        assert!(!cache.seen.contains_key("0"));
+        assert!(cache.seen.contains_key(&MAX_CACHED_IDS.to_string()));
+    }
+
+    #[test]
+    fn repeated_onion_layers_are_rejected() {
+        let mut cache = ReplayCache::new(SKEW);
+        assert!(cache.check_onion(b"first"));
+        assert!(cache.check_onion(b"second"));
+        assert!(!cache.check_onion(b"first"));
+
+        for i in 0..MAX_ONION_LAYERS {
+            cache.check_onion(&i.to_be_bytes());
+        }
+        // Only the newest layers are remembered.
+        assert!(cache.check_onion(b"first"));
+        assert!(!cache.check_onion(&(MAX_ONION_LAYERS - 1).to_be_bytes()));
+    }
+}
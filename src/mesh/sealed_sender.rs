@@ -0,0 +1,182 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use p256::{ecdh, PublicKey, SecretKey};
+use sha2::Sha256;
+use crate::bitchat_packet::{OnionLayer, SealedEnvelope};
+use super::noise::compressed_public_key;
+
+const SEAL_INFO: &[u8] = b"bitchat sealed sender";
+/// Each hop adds airtime and another peer that has to stay in range.
+pub const MAX_ONION_HOPS: usize = 5;
+
+/// Encrypts `inner` to a peer's static key with a throwaway key of our own,
+/// so nothing in the envelope points back at us. Each envelope gets a fresh
+/// key, which is why a fixed nonce derived alongside it is safe.
+pub fn seal(recipient: &PublicKey, inner: &[u8]) -> Result<SealedEnvelope> {
+    let ephemeral = SecretKey::random(&mut rand::thread_rng());
+    let ephemeral_key = compressed_public_key(&ephemeral.public_key());
+    let (cipher, nonce) = envelope_cipher(&ephemeral, recipient, &ephemeral_key)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: inner, aad: &compressed_public_key(recipient) })
+        .map_err(|_| anyhow!("Failed to seal envelope"))?;
+    Ok(SealedEnvelope { ephemeral_key, ciphertext })
+}
+
+/// Seals `inner` in one layer per hop of `path`, which ends with the
+/// recipient. Each relay can open only its own layer, which names the next
+/// hop and nothing else; the outermost envelope goes to `path[0]`.
+pub fn wrap_onion(path: &[(String, PublicKey)], inner: &[u8]) -> Result<SealedEnvelope> {
+    let ((_, recipient), relays) = path.split_last().ok_or_else(|| anyhow!("Empty onion path"))?;
+    let last = OnionLayer { next_hop: None, payload: inner.to_vec() };
+    let mut envelope = seal(recipient, &bincode::serialize(&last)?)?;
+    for (relay, next) in relays.iter().zip(&path[1..]).rev() {
+        let layer = OnionLayer { next_hop: Some(next.0.clone()), payload: bincode::serialize(&envelope)? };
+        envelope = seal(&relay.1, &bincode::serialize(&layer)?)?;
+    }
+    Ok(envelope)
+}
+
+/// Opens an envelope sealed to our static key.
+pub fn open(static_secret: &SecretKey, envelope: &SealedEnvelope) -> Result<Vec<u8>> {
+    let ephemeral = PublicKey::from_sec1_bytes(&envelope.ephemeral_key)?;
+    let (cipher, nonce) = envelope_cipher(static_secret, &ephemeral, &envelope.ephemeral_key)?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload { msg: &envelope.ciphertext, aad: &compressed_public_key(&static_secret.public_key()) },
+        )
+        .map_err(|_| anyhow!("Sealed envelope failed authentication"))
+}
+
+/// Both sides compute the same DH; the ephemeral key salts the derivation.
+fn envelope_cipher(secret: &SecretKey, public: &PublicKey, ephemeral_key: &[u8]) -> Result<(Aes256Gcm, [u8; 12])> {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(Some(ephemeral_key), shared.raw_secret_bytes())
+        .expand(SEAL_INFO, &mut okm)
+        .map_err(|_| anyhow!("Key derivation failed"))?;
+    let (key, nonce) = okm.split_at(32);
+    Ok((Aes256Gcm::new(key.into()), nonce.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::replay_cache::ReplayCache;
+    use std::time::Duration;
+
+    fn new_key() -> SecretKey {
+        SecretKey::random(&mut rand::thread_rng())
+    }
+
+    fn peel(secret: &SecretKey, envelope: &SealedEnvelope) -> OnionLayer {
+        bincode::deserialize(&open(secret, envelope).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sealed_envelopes_round_trip() {
+        let recipient = new_key();
+        let envelope = seal(&recipient.public_key(), b"inner packet").unwrap();
+        assert_eq!(open(&recipient, &envelope).unwrap(), b"inner packet");
+    }
+
+    #[test]
+    fn every_envelope_uses_a_fresh_key() {
+        let recipient = new_key().public_key();
+        let first = seal(&recipient, b"same").unwrap();
+        let second = seal(&recipient, b"same").unwrap();
+        assert_ne!(first.ephemeral_key, second.ephemeral_key);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn tampered_envelopes_are_rejected() {
+        let recipient = new_key();
+        let envelope = seal(&recipient.public_key(), b"inner packet").unwrap();
+
+        let mut ciphertext = envelope.clone();
+        ciphertext.ciphertext[0] ^= 1;
+        assert!(open(&recipient, &ciphertext).is_err());
+
+        let mut ephemeral_key = envelope.clone();
+        ephemeral_key.ephemeral_key = compressed_public_key(&new_key().public_key());
+        assert!(open(&recipient, &ephemeral_key).is_err());
+
+        let mut garbage = envelope;
+        garbage.ephemeral_key[1] ^= 1;
+        assert!(open(&recipient, &garbage).is_err());
+    }
+
+    #[test]
+    fn only_the_recipient_can_open() {
+        let envelope = seal(&new_key().public_key(), b"inner packet").unwrap();
+        assert!(open(&new_key(), &envelope).is_err());
+    }
+
+    #[test]
+    fn onion_layers_peel_one_hop_at_a_time() {
+        let keys: Vec<SecretKey> = (0..3).map(|_| new_key()).collect();
+        let names = ["first", "second", "recipient"];
+        let path: Vec<(String, PublicKey)> =
+            names.iter().zip(&keys).map(|(name, key)| (name.to_string(), key.public_key())).collect();
+        let outer = wrap_onion(&path, b"inner packet").unwrap();
+
+        let layer = peel(&keys[0], &outer);
+        assert_eq!(layer.next_hop.as_deref(), Some("second"));
+        let envelope: SealedEnvelope = bincode::deserialize(&layer.payload).unwrap();
+        // The first relay cannot see past its own layer.
+        assert!(open(&keys[0], &envelope).is_err());
+        assert!(open(&keys[2], &envelope).is_err());
+
+        let layer = peel(&keys[1], &envelope);
+        assert_eq!(layer.next_hop.as_deref(), Some("recipient"));
+        let envelope: SealedEnvelope = bincode::deserialize(&layer.payload).unwrap();
+
+        let layer = peel(&keys[2], &envelope);
+        assert_eq!(layer.next_hop, None);
+        assert_eq!(layer.payload, b"inner packet");
+    }
+
+    #[test]
+    fn skipping_a_hop_does_not_work() {
+        let keys: Vec<SecretKey> = (0..2).map(|_| new_key()).collect();
+        let path = vec![("relay".to_string(), keys[0].public_key()), ("recipient".to_string(), keys[1].public_key())];
+        let outer = wrap_onion(&path, b"inner packet").unwrap();
+        assert!(open(&keys[1], &outer).is_err());
+    }
+
+    #[test]
+    fn a_direct_path_is_a_single_layer() {
+        let recipient = new_key();
+        let outer = wrap_onion(&[("recipient".to_string(), recipient.public_key())], b"inner packet").unwrap();
+        let layer = peel(&recipient, &outer);
+        assert_eq!((layer.next_hop, layer.payload), (None, b"inner packet".to_vec()));
+    }
+
+    #[test]
+    fn an_empty_path_is_refused() {
+        assert!(wrap_onion(&[], b"inner packet").is_err());
+    }
+
+    #[test]
+    fn replayed_layers_are_forwarded_once() {
+        let keys: Vec<SecretKey> = (0..2).map(|_| new_key()).collect();
+        let path = vec![("relay".to_string(), keys[0].public_key()), ("recipient".to_string(), keys[1].public_key())];
+        let mut relay_cache = ReplayCache::new(Duration::from_secs(60));
+
+        let outer = wrap_onion(&path, b"inner packet").unwrap();
+        let next: SealedEnvelope = bincode::deserialize(&peel(&keys[0], &outer).payload).unwrap();
+        assert!(relay_cache.check_onion(&next.ephemeral_key));
+        // A recorded copy opens to the same next layer, which is not sent on again.
+        let replayed: SealedEnvelope = bincode::deserialize(&peel(&keys[0], &outer).payload).unwrap();
+        assert!(!relay_cache.check_onion(&replayed.ephemeral_key));
+
+        let fresh = wrap_onion(&path, b"inner packet").unwrap();
+        let next: SealedEnvelope = bincode::deserialize(&peel(&keys[0], &fresh).payload).unwrap();
+        assert!(relay_cache.check_onion(&next.ephemeral_key));
+    }
+}
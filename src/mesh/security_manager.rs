@@ -1,60 +1,945 @@
-use p256::{
-    ecdh::EphemeralSecret,
-    PublicKey,
-};
-use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce,
-};
+use p256::{PublicKey, SecretKey};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use crate::bitchat_packet::SealedEnvelope;
+use super::identity::{fingerprint, normalize_fingerprint};
+use super::noise::{compressed_public_key, HandshakeState, Role, DH_LEN};
+use super::ratchet::RatchetSession;
+use super::sealed_sender;
+use super::storage::{read_private, wipe_private, write_private};
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const MAX_HANDSHAKE_ATTEMPTS: u8 = 3;
+const SESSIONS_FILE: &str = "sessions.bin";
+const SIGNING_KEY_LEN: usize = 32;
+/// Anyone can complete a handshake under someone else's peer ID, so only
+/// this many different keys are held for the user to look at.
+const MAX_HELD_KEY_CHANGES: usize = 16;
+/// How long a session replaced by a rekey still decrypts messages in flight
+/// once the peer is known to have the new one.
+pub const REKEY_GRACE: Duration = Duration::from_secs(2 * 60);
+
+/// When an established session is replaced by a fresh handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    /// Messages sent and received on one session before it is replaced.
+    pub max_messages: u32,
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy { max_messages: 1000, max_age: Duration::from_secs(60 * 60) }
+    }
+}
+
+struct PendingHandshake {
+    state: HandshakeState,
+    /// Our first ratchet key, announced inside the handshake.
+    ratchet_secret: SecretKey,
+    last_message: Vec<u8>,
+    sent_at: Instant,
+    attempts: u8,
+    /// Started to replace a working session rather than to create one.
+    rekey: bool,
+}
+
+/// A session replaced by a rekey, kept until messages sent under it are in.
+struct RetiredSession {
+    session: RatchetSession,
+    retired_at: Instant,
+    /// The rekey initiator keeps sending on the old session until the peer
+    /// shows it has the new one, since the final handshake message may not
+    /// have reached it yet. However long that takes, the old session stays.
+    send_on_old: bool,
+}
+
+impl RetiredSession {
+    fn is_live(&self) -> bool {
+        self.send_on_old || self.retired_at.elapsed() < REKEY_GRACE
+    }
+}
+
+/// How much a session has been used since it was established. Saved with
+/// the sessions, so a restart does not postpone age-based rekeying.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SessionUsage {
+    since: SystemTime,
+    messages: u32,
+}
+
+impl SessionUsage {
+    fn new() -> Self {
+        SessionUsage { since: SystemTime::now(), messages: 0 }
+    }
+
+    fn is_due(&self, policy: RekeyPolicy) -> bool {
+        // A clock set back counts as no time passed.
+        let age = self.since.elapsed().unwrap_or_default();
+        self.messages >= policy.max_messages || age >= policy.max_age
+    }
+}
+
+/// Result of feeding one handshake message into the state machine.
+pub struct HandshakeOutcome {
+    pub reply: Option<Vec<u8>>,
+    pub established: bool,
+    /// The handshake replaced a session we already had with this peer ID.
+    pub rekeyed: bool,
+    /// Another peer ID this identity used before, i.e. the peer rotated its ID.
+    pub previous_peer_id: Option<String>,
+    /// The Ed25519 key the peer signs broadcasts with, authenticated by the handshake.
+    pub peer_signing_key: Option<[u8; 32]>,
+    /// The peer is pinned to another static key; the new one is held until
+    /// the user accepts it, while the pinned session stays in use. Holds the
+    /// pinned key's fingerprint, and is only set when nothing was held yet.
+    pub key_change: Option<String>,
+}
+
+/// A session with a key that differs from the pinned one, kept aside until
+/// the user decides whether to trust it.
+struct HeldKeyChange {
+    public_key: PublicKey,
+    session: RatchetSession,
+    signing_key: [u8; 32],
+    identity_key: Option<[u8; 32]>,
+}
+
+/// Handshakes that need resending and peers we gave up on.
+pub struct HandshakeRetries {
+    pub resend: Vec<(String, Vec<u8>)>,
+    pub failed: Vec<String>,
+}
+
+/// What goes to disk: which static key each peer ID authenticated with, and
+/// the ratchet, its usage and identity signing key for each static key.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedSessions {
+    peer_keys: HashMap<String, Vec<u8>>,
+    sessions: HashMap<String, RatchetSession>,
+    identity_keys: HashMap<String, [u8; 32]>,
+    usage: HashMap<String, SessionUsage>,
+}
+
+/// The format from before handshakes carried identity signing keys and
+/// session usage was saved.
+#[derive(Deserialize)]
+struct LegacySavedSessions {
+    peer_keys: HashMap<String, Vec<u8>>,
+    sessions: HashMap<String, RatchetSession>,
+}
+
+impl HandshakeOutcome {
+    fn pending(reply: Option<Vec<u8>>) -> Self {
+        HandshakeOutcome {
+            reply,
+            established: false,
+            rekeyed: false,
+            previous_peer_id: None,
+            peer_signing_key: None,
+            key_change: None,
+        }
+    }
+}
 
 pub struct SecurityManager {
-    my_secret: EphemeralSecret,
+    my_peer_id: String,
+    static_secret: SecretKey,
+    signing_key: [u8; 32],
+    /// Our long-term Ed25519 key, which channel ownership is tied to.
+    identity_key: [u8; 32],
+    /// The static key each peer ID authenticated with. Peer IDs only last a
+    /// run, so the lasting pin is the contact store's, passed in per handshake.
     peer_public_keys: HashMap<String, PublicKey>,
+    /// Each peer's long-term Ed25519 key, keyed by their fingerprint.
+    peer_identity_keys: HashMap<String, [u8; 32]>,
+    held_key_changes: HashMap<String, HeldKeyChange>,
+    handshakes: HashMap<String, PendingHandshake>,
+    /// Ratchets keyed by the peer's fingerprint, so they outlive peer IDs.
+    sessions: HashMap<String, RatchetSession>,
+    retired_sessions: HashMap<String, RetiredSession>,
+    usage: HashMap<String, SessionUsage>,
+    rekey_policy: RekeyPolicy,
+    sessions_path: Option<PathBuf>,
 }
 
 impl SecurityManager {
-    pub fn new() -> Self {
-        let my_secret = EphemeralSecret::random(&mut rand::thread_rng());
+    pub fn new(
+        my_peer_id: String,
+        static_secret: SecretKey,
+        signing_key: [u8; 32],
+        identity_key: [u8; 32],
+        rekey_policy: RekeyPolicy,
+    ) -> Self {
         SecurityManager {
-            my_secret,
+            my_peer_id,
+            static_secret,
+            signing_key,
+            identity_key,
             peer_public_keys: HashMap::new(),
+            peer_identity_keys: HashMap::new(),
+            held_key_changes: HashMap::new(),
+            handshakes: HashMap::new(),
+            sessions: HashMap::new(),
+            retired_sessions: HashMap::new(),
+            usage: HashMap::new(),
+            rekey_policy,
+            sessions_path: None,
+        }
+    }
+
+    /// Restores sessions saved in `data_dir` and saves there from now on.
+    pub fn load_sessions(&mut self, data_dir: &Path) -> Result<()> {
+        let path = data_dir.join(SESSIONS_FILE);
+        self.sessions_path = Some(path.clone());
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let bytes = read_private(&path)?;
+        let saved = match bincode::deserialize::<SavedSessions>(&bytes) {
+            Ok(saved) => saved,
+            Err(_) => {
+                let legacy: LegacySavedSessions =
+                    bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+                // Sessions of unknown age are due for a rekey.
+                let usage = legacy
+                    .sessions
+                    .keys()
+                    .map(|key| (key.clone(), SessionUsage { since: SystemTime::UNIX_EPOCH, messages: 0 }))
+                    .collect();
+                SavedSessions {
+                    peer_keys: legacy.peer_keys,
+                    sessions: legacy.sessions,
+                    identity_keys: HashMap::new(),
+                    usage,
+                }
+            }
+        };
+        for (peer_id, key) in saved.peer_keys {
+            self.peer_public_keys.insert(peer_id, PublicKey::from_sec1_bytes(&key)?);
         }
+        self.sessions = saved.sessions;
+        self.peer_identity_keys = saved.identity_keys;
+        self.usage = saved.usage;
+        Ok(())
+    }
+
+    fn save_sessions(&self) {
+        let Some(path) = &self.sessions_path else {
+            return;
+        };
+        let saved = SavedSessions {
+            peer_keys: self
+                .peer_public_keys
+                .iter()
+                .filter(|(_, key)| self.sessions.contains_key(&fingerprint(key)))
+                .map(|(peer_id, key)| (peer_id.clone(), compressed_public_key(key)))
+                .collect(),
+            sessions: self.sessions.clone(),
+            identity_keys: self
+                .peer_identity_keys
+                .iter()
+                .filter(|(key, _)| self.sessions.contains_key(*key))
+                .map(|(key, identity_key)| (key.clone(), *identity_key))
+                .collect(),
+            usage: self
+                .usage
+                .iter()
+                .filter(|(key, _)| self.sessions.contains_key(*key))
+                .map(|(key, usage)| (key.clone(), *usage))
+                .collect(),
+        };
+        let result = bincode::serialize(&saved)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| write_private(path, &bytes));
+        if let Err(e) = result {
+            log::warn!("Failed to save sessions: {}", e);
+        }
+    }
+
+    /// Called when we rotate our peer ID, and with it the signing key we
+    /// hand out in handshakes.
+    pub fn set_my_identity(&mut self, my_peer_id: String, signing_key: [u8; 32]) {
+        self.my_peer_id = my_peer_id;
+        self.signing_key = signing_key;
     }
 
     pub fn get_public_key(&self) -> PublicKey {
-        self.my_secret.public_key()
+        self.static_secret.public_key()
     }
 
-    pub fn add_peer_public_key(&mut self, peer_id: &str, public_key: PublicKey) {
-        self.peer_public_keys.insert(peer_id.to_string(), public_key);
+    /// The fingerprint `public_key` would replace, if it differs from the key
+    /// this peer ID already uses or from `pinned`, the key the peer's
+    /// persistent identity is pinned to. Peer IDs change on every launch and
+    /// rotation, so only the latter holds across them. A different key never
+    /// replaces either here; that takes `accept_key_change`.
+    fn changed_key(&self, peer_id: &str, public_key: &PublicKey, pinned: Option<&str>) -> Option<String> {
+        if let Some(current) = self.peer_public_keys.get(peer_id) {
+            return (current != public_key).then(|| fingerprint(current));
+        }
+        let pinned = pinned?;
+        (normalize_fingerprint(pinned) != normalize_fingerprint(&fingerprint(public_key))).then(|| pinned.to_string())
     }
 
-    pub fn encrypt_for_peer(&self, data: &[u8], peer_id: &str) -> Option<Vec<u8>> {
-        if let Some(public_key) = self.peer_public_keys.get(peer_id) {
-            let shared_secret = self.my_secret.diffie_hellman(public_key);
-            let cipher = Aes256Gcm::new(shared_secret.raw_secret_bytes().into());
-            let nonce = Nonce::from_slice(b"unique nonce"); // TODO: use a unique nonce
-            let ciphertext = cipher.encrypt(nonce, data).ok()?;
-            Some(ciphertext)
-        } else {
-            None
+    pub fn has_held_key_change(&self, peer_id: &str) -> bool {
+        self.held_key_changes.contains_key(peer_id)
+    }
+
+    pub fn held_key_fingerprint(&self, peer_id: &str) -> Option<String> {
+        self.held_key_changes.get(peer_id).map(|held| fingerprint(&held.public_key))
+    }
+
+    /// Pins the held key in place of the old one and puts its session to use.
+    /// Returns the peer's signing key so broadcasts can be bound to it.
+    pub fn accept_key_change(&mut self, peer_id: &str) -> Option<[u8; 32]> {
+        let held = self.held_key_changes.remove(peer_id)?;
+        self.peer_public_keys.insert(peer_id.to_string(), held.public_key);
+        let key = fingerprint(&held.public_key);
+        if let Some(identity_key) = held.identity_key {
+            self.peer_identity_keys.insert(key.clone(), identity_key);
         }
+        self.sessions.insert(key.clone(), held.session);
+        self.usage.insert(key, SessionUsage::new());
+        self.save_sessions();
+        Some(held.signing_key)
     }
 
-    pub fn decrypt_from_peer(&self, data: &[u8], peer_id: &str) -> Option<Vec<u8>> {
-        if let Some(public_key) = self.peer_public_keys.get(peer_id) {
-            let shared_secret = self.my_secret.diffie_hellman(public_key);
-            let cipher = Aes256Gcm::new(shared_secret.raw_secret_bytes().into());
-            let nonce = Nonce::from_slice(b"unique nonce"); // TODO: use a unique nonce
-            let plaintext = cipher.decrypt(nonce, data).ok()?;
-            Some(plaintext)
-        } else {
+    pub fn reject_key_change(&mut self, peer_id: &str) -> bool {
+        self.held_key_changes.remove(peer_id).is_some()
+    }
+
+    pub fn get_peer_public_key(&self, peer_id: &str) -> Option<&PublicKey> {
+        self.peer_public_keys.get(peer_id)
+    }
+
+    /// The long-term Ed25519 key the peer sent in our latest handshake with it.
+    pub fn get_peer_identity_key(&self, peer_id: &str) -> Option<[u8; 32]> {
+        self.peer_identity_keys.get(&self.session_key(peer_id)?).copied()
+    }
+
+    /// The peer ID currently authenticated with the given fingerprint.
+    pub fn find_peer_by_fingerprint(&self, peer_fingerprint: &str) -> Option<String> {
+        self.peer_public_keys
+            .iter()
+            .find(|(_, key)| fingerprint(key) == peer_fingerprint)
+            .map(|(peer_id, _)| peer_id.clone())
+    }
+
+    pub fn has_session(&self, peer_id: &str) -> bool {
+        self.session_key(peer_id).is_some_and(|key| self.sessions.contains_key(&key))
+    }
+
+    pub fn is_handshake_pending(&self, peer_id: &str) -> bool {
+        self.handshakes.contains_key(peer_id)
+    }
+
+    /// Starts a Noise XX handshake and returns the first message to send.
+    pub fn initiate_handshake(&mut self, peer_id: &str) -> Result<Vec<u8>> {
+        self.start_handshake(peer_id, false)
+    }
+
+    /// Starts a handshake that replaces the working session with `peer_id`.
+    pub fn initiate_rekey(&mut self, peer_id: &str) -> Result<Vec<u8>> {
+        self.start_handshake(peer_id, true)
+    }
+
+    fn start_handshake(&mut self, peer_id: &str, rekey: bool) -> Result<Vec<u8>> {
+        let mut state = HandshakeState::new(Role::Initiator, self.static_secret.clone());
+        let message = Self::frame(0, &state.write_message(&[])?);
+        self.handshakes.insert(peer_id.to_string(), PendingHandshake {
+            state,
+            ratchet_secret: SecretKey::random(&mut rand::thread_rng()),
+            last_message: message.clone(),
+            sent_at: Instant::now(),
+            attempts: 1,
+            rekey,
+        });
+        Ok(message)
+    }
+
+    /// Peers whose session has carried enough messages or lived long enough
+    /// to be replaced, and that have no handshake under way.
+    pub fn due_rekeys(&mut self) -> Vec<String> {
+        self.retired_sessions.retain(|_, retired| retired.is_live());
+        let policy = self.rekey_policy;
+        self.peer_public_keys
+            .iter()
+            .filter(|(peer_id, _)| !self.handshakes.contains_key(*peer_id))
+            .filter(|(_, key)| self.usage.get(&fingerprint(key)).is_some_and(|usage| usage.is_due(policy)))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Advances the handshake with `peer_id`. A completed handshake replaces
+    /// any existing session, so a peer that restarted can always reconnect;
+    /// the old one stays usable for `REKEY_GRACE`.
+    ///
+    /// The second and third messages carry each side's first ratchet key, so
+    /// both ends can start a Double Ratchet the moment the handshake ends,
+    /// followed by its Ed25519 signing key to bind it to the static key and
+    /// its long-term Ed25519 identity key. Older peers leave out the last.
+    ///
+    /// `pinned` is the fingerprint the peer's nickname is pinned to, if any;
+    /// a handshake with another key is held back like any other key change.
+    pub fn handle_handshake_message(
+        &mut self,
+        peer_id: &str,
+        data: &[u8],
+        pinned: Option<&str>,
+    ) -> Result<HandshakeOutcome> {
+        let Some((&index, message)) = data.split_first() else {
+            bail!("Empty handshake message");
+        };
+
+        if index == 0 {
+            // Both sides may initiate at once; the lower peer ID keeps the initiator role.
+            if let Some(pending) = self.handshakes.get(peer_id)
+                && pending.state.role() == Role::Initiator
+                && self.my_peer_id.as_str() < peer_id
+            {
+                return Ok(HandshakeOutcome::pending(None));
+            }
+            let mut state = HandshakeState::new(Role::Responder, self.static_secret.clone());
+            state.read_message(message)?;
+            let ratchet_secret = SecretKey::random(&mut rand::thread_rng());
+            let reply = Self::frame(1, &state.write_message(&self.handshake_payload(&ratchet_secret))?);
+            self.handshakes.insert(peer_id.to_string(), PendingHandshake {
+                state,
+                ratchet_secret,
+                last_message: reply.clone(),
+                sent_at: Instant::now(),
+                attempts: 1,
+                rekey: false,
+            });
+            return Ok(HandshakeOutcome::pending(Some(reply)));
+        }
+
+        let Some(mut pending) = self.handshakes.remove(peer_id) else {
+            bail!("No handshake in progress with {}", peer_id);
+        };
+        if pending.state.message_index() != index as usize {
+            bail!("Out of order handshake message from {}", peer_id);
+        }
+
+        let payload = pending.state.read_message(message)?;
+        if payload.len() != DH_LEN + SIGNING_KEY_LEN && payload.len() != DH_LEN + 2 * SIGNING_KEY_LEN {
+            bail!("Malformed handshake payload from {}", peer_id);
+        }
+        let (ratchet_key, keys) = payload.split_at(DH_LEN);
+        let (signing_key, identity_key) = keys.split_at(SIGNING_KEY_LEN);
+        let identity_key = (!identity_key.is_empty()).then(|| identity_key.try_into()).transpose()?;
+        let remote_ratchet_key = PublicKey::from_sec1_bytes(ratchet_key)?;
+        let reply = if pending.state.is_complete() {
             None
+        } else {
+            let payload = self.handshake_payload(&pending.ratchet_secret);
+            Some(Self::frame(index + 1, &pending.state.write_message(&payload)?))
+        };
+        let mut outcome = self.establish_session(
+            peer_id,
+            &pending,
+            &remote_ratchet_key,
+            signing_key.try_into()?,
+            identity_key,
+            pinned,
+        )?;
+        outcome.reply = reply;
+        Ok(outcome)
+    }
+
+    /// Resends initiator messages that went unanswered and drops handshakes
+    /// that ran out of attempts.
+    pub fn poll_handshakes(&mut self) -> HandshakeRetries {
+        let mut retries = HandshakeRetries { resend: Vec::new(), failed: Vec::new() };
+        let mut failed_rekeys = Vec::new();
+        self.handshakes.retain(|peer_id, pending| {
+            if pending.sent_at.elapsed() < HANDSHAKE_TIMEOUT {
+                return true;
+            }
+            if pending.state.role() == Role::Initiator && pending.attempts < MAX_HANDSHAKE_ATTEMPTS {
+                pending.attempts += 1;
+                pending.sent_at = Instant::now();
+                retries.resend.push((peer_id.clone(), pending.last_message.clone()));
+                return true;
+            }
+            if pending.rekey {
+                // The old session still works; try again next period.
+                failed_rekeys.push(peer_id.clone());
+            } else {
+                retries.failed.push(peer_id.clone());
+            }
+            false
+        });
+        for peer_id in failed_rekeys {
+            if let Some(key) = self.session_key(&peer_id) {
+                self.usage.insert(key, SessionUsage::new());
+            }
         }
+        retries
+    }
+
+    /// Advances the sending chain and saves it before the ciphertext leaves,
+    /// so a crash can never make us reuse a message key.
+    pub fn encrypt_for_peer(&mut self, data: &[u8], peer_id: &str) -> Option<Vec<u8>> {
+        let key = self.session_key(peer_id)?;
+        let (session, current) = match self.retired_sessions.get_mut(&key) {
+            Some(retired) if retired.send_on_old => {
+                (&mut retired.session, false)
+            }
+            _ => (self.sessions.get_mut(&key)?, true),
+        };
+        let encrypted = match session.encrypt(data) {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                log::warn!("Failed to encrypt for {}: {}", peer_id, e);
+                return None;
+            }
+        };
+        if current {
+            self.count_message(&key);
+        }
+        self.save_sessions();
+        Some(encrypted)
+    }
+
+    /// Tries the current session, then one a rekey replaced moments ago.
+    pub fn decrypt_from_peer(&mut self, data: &[u8], peer_id: &str) -> Option<Vec<u8>> {
+        let key = self.session_key(peer_id)?;
+        let decrypted = match self.sessions.get_mut(&key)?.decrypt(data) {
+            Ok(decrypted) => {
+                // The peer is on the new session, so we can switch over too.
+                // Its messages under the old one may still be on their way.
+                if let Some(retired) = self.retired_sessions.get_mut(&key)
+                    && retired.send_on_old
+                {
+                    retired.send_on_old = false;
+                    retired.retired_at = Instant::now();
+                }
+                self.count_message(&key);
+                decrypted
+            }
+            Err(_) => {
+                let retired = self.retired_sessions.get_mut(&key)?;
+                if !retired.is_live() {
+                    return None;
+                }
+                retired.session.decrypt(data).ok()?
+            }
+        };
+        self.save_sessions();
+        Some(decrypted)
+    }
+
+    fn count_message(&mut self, key: &str) {
+        self.usage.entry(key.to_string()).or_insert_with(SessionUsage::new).messages += 1;
+    }
+
+    /// Seals a packet to the static key `peer_id` authenticated with.
+    pub fn seal_for_peer(&self, inner: &[u8], peer_id: &str) -> Option<SealedEnvelope> {
+        let public_key = self.peer_public_keys.get(peer_id)?;
+        match sealed_sender::seal(public_key, inner) {
+            Ok(envelope) => Some(envelope),
+            Err(e) => {
+                log::warn!("Failed to seal packet for {}: {}", peer_id, e);
+                None
+            }
+        }
+    }
+
+    /// Layers a packet for `path`, which ends with the recipient; every peer
+    /// on it must have authenticated.
+    pub fn wrap_onion(&self, inner: &[u8], path: &[String]) -> Option<SealedEnvelope> {
+        let keyed = path
+            .iter()
+            .map(|peer_id| Some((peer_id.clone(), *self.peer_public_keys.get(peer_id)?)))
+            .collect::<Option<Vec<_>>>()?;
+        match sealed_sender::wrap_onion(&keyed, inner) {
+            Ok(envelope) => Some(envelope),
+            Err(e) => {
+                log::warn!("Failed to build onion: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn open_sealed(&self, envelope: &SealedEnvelope) -> Option<Vec<u8>> {
+        sealed_sender::open(&self.static_secret, envelope).ok()
+    }
+
+    fn session_key(&self, peer_id: &str) -> Option<String> {
+        self.peer_public_keys.get(peer_id).map(fingerprint)
+    }
+
+    /// Reports the peer ID this static key was known under before, if any, or
+    /// holds the session back if the peer is pinned to another key.
+    fn establish_session(
+        &mut self,
+        peer_id: &str,
+        pending: &PendingHandshake,
+        remote_ratchet_key: &PublicKey,
+        signing_key: [u8; 32],
+        identity_key: Option<[u8; 32]>,
+        pinned: Option<&str>,
+    ) -> Result<HandshakeOutcome> {
+        let state = &pending.state;
+        let Some(remote_static) = state.remote_static() else {
+            bail!("Handshake finished without the peer's static key");
+        };
+        let shared_secret = state.session_secret()?;
+        let session = match state.role() {
+            Role::Initiator => RatchetSession::new_initiator(
+                shared_secret,
+                state.handshake_hash(),
+                &pending.ratchet_secret,
+                remote_ratchet_key,
+            ),
+            Role::Responder => RatchetSession::new_responder(
+                shared_secret,
+                state.handshake_hash(),
+                &pending.ratchet_secret,
+                remote_ratchet_key,
+            ),
+        };
+        let key = fingerprint(remote_static);
+        let rekeyed = self.peer_public_keys.get(peer_id) == Some(remote_static) && self.sessions.contains_key(&key);
+        if let Some(previous_fingerprint) = self.changed_key(peer_id, remote_static, pinned) {
+            // The key the user was warned about is the one /accept pins, so a
+            // third key never swaps in behind it.
+            let first = match self.held_key_changes.get(peer_id) {
+                Some(held) if held.public_key != *remote_static => return Ok(HandshakeOutcome::pending(None)),
+                Some(_) => false,
+                None => true,
+            };
+            if first && self.held_key_changes.len() >= MAX_HELD_KEY_CHANGES {
+                return Ok(HandshakeOutcome::pending(None));
+            }
+            self.held_key_changes.insert(peer_id.to_string(), HeldKeyChange {
+                public_key: *remote_static,
+                session,
+                signing_key,
+                identity_key,
+            });
+            let key_change = first.then_some(previous_fingerprint);
+            return Ok(HandshakeOutcome { key_change, ..HandshakeOutcome::pending(None) });
+        }
+        self.held_key_changes.remove(peer_id);
+        self.peer_public_keys.insert(peer_id.to_string(), *remote_static);
+        // Messages sent under the replaced session may still be on their way.
+        // If the peer never confirmed the last rekey, the session before that
+        // is the one it still uses, so that one is kept instead.
+        let unconfirmed = self.retired_sessions.get(&key).is_some_and(|retired| retired.send_on_old);
+        if unconfirmed
+            && !pending.rekey
+            && let Some(retired) = self.retired_sessions.get_mut(&key)
+        {
+            // The peer finished this handshake itself, so it has the new session.
+            retired.send_on_old = false;
+            retired.retired_at = Instant::now();
+        }
+        if let Some(session) = self.sessions.remove(&key)
+            && !unconfirmed
+        {
+            self.retired_sessions.insert(key.clone(), RetiredSession {
+                session,
+                retired_at: Instant::now(),
+                send_on_old: pending.rekey,
+            });
+        }
+        let previous_peer_id = self
+            .peer_public_keys
+            .iter()
+            .find(|(id, key)| id.as_str() != peer_id && *key == remote_static)
+            .map(|(id, _)| id.clone());
+        if let Some(previous_peer_id) = &previous_peer_id {
+            self.peer_public_keys.remove(previous_peer_id);
+        }
+        match identity_key {
+            Some(identity_key) => self.peer_identity_keys.insert(key.clone(), identity_key),
+            None => self.peer_identity_keys.remove(&key),
+        };
+        self.sessions.insert(key.clone(), session);
+        self.usage.insert(key, SessionUsage::new());
+        self.save_sessions();
+        Ok(HandshakeOutcome {
+            reply: None,
+            established: true,
+            rekeyed,
+            previous_peer_id,
+            peer_signing_key: Some(signing_key),
+            key_change: None,
+        })
+    }
+
+    fn handshake_payload(&self, ratchet_secret: &SecretKey) -> Vec<u8> {
+        let mut payload = compressed_public_key(&ratchet_secret.public_key());
+        payload.extend_from_slice(&self.signing_key);
+        payload.extend_from_slice(&self.identity_key);
+        payload
+    }
+
+    fn frame(index: u8, message: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(message.len() + 1);
+        framed.push(index);
+        framed.extend_from_slice(message);
+        framed
     }
 
     pub fn shutdown(&mut self) {
+        self.save_sessions();
+        self.peer_public_keys.clear();
+        self.peer_identity_keys.clear();
+        self.held_key_changes.clear();
+        self.handshakes.clear();
+        self.sessions.clear();
+        self.retired_sessions.clear();
+        self.usage.clear();
+    }
+
+    /// Forgets every peer key and session, on disk as well.
+    pub fn wipe(&mut self) -> Result<()> {
         self.peer_public_keys.clear();
+        self.peer_identity_keys.clear();
+        self.held_key_changes.clear();
+        self.handshakes.clear();
+        self.sessions.clear();
+        self.retired_sessions.clear();
+        self.usage.clear();
+        match self.sessions_path.take() {
+            Some(path) => wipe_private(&path),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::storage::test_support::{take_turn, TestDir};
+    use super::super::storage::unlock;
+
+    const FEW_MESSAGES: RekeyPolicy = RekeyPolicy { max_messages: 3, max_age: Duration::from_secs(60 * 60) };
+
+    fn manager(peer_id: &str) -> SecurityManager {
+        manager_with(peer_id, RekeyPolicy::default())
+    }
+
+    fn manager_with(peer_id: &str, rekey_policy: RekeyPolicy) -> SecurityManager {
+        SecurityManager::new(
+            peer_id.to_string(),
+            SecretKey::random(&mut rand::thread_rng()),
+            [1; 32],
+            [2; 32],
+            rekey_policy,
+        )
+    }
+
+    /// Runs a handshake `initiator` starts with `responder`, which is known
+    /// to it as `responder_id`, and returns what the initiator made of it.
+    fn handshake(
+        initiator: &mut SecurityManager,
+        responder: &mut SecurityManager,
+        responder_id: &str,
+        pinned: Option<&str>,
+    ) -> HandshakeOutcome {
+        let initiator_id = initiator.my_peer_id.clone();
+        let first = initiator.initiate_handshake(responder_id).unwrap();
+        let second = responder.handle_handshake_message(&initiator_id, &first, None).unwrap().reply.unwrap();
+        let outcome = initiator.handle_handshake_message(responder_id, &second, pinned).unwrap();
+        if let Some(third) = &outcome.reply {
+            responder.handle_handshake_message(&initiator_id, third, None).unwrap();
+        }
+        outcome
+    }
+
+    fn delivers(from: &mut SecurityManager, to: &mut SecurityManager, to_id: &str) -> bool {
+        let from_id = from.my_peer_id.clone();
+        let Some(encrypted) = from.encrypt_for_peer(b"hello", to_id) else {
+            return false;
+        };
+        to.decrypt_from_peer(&encrypted, &from_id).as_deref() == Some(b"hello".as_slice())
+    }
+
+    #[test]
+    fn the_first_key_for_a_peer_is_used() {
+        let (mut alice, mut bob) = (manager("alice"), manager("bob"));
+        let outcome = handshake(&mut alice, &mut bob, "bob", None);
+        assert!(outcome.established && outcome.key_change.is_none());
+        assert_eq!(alice.get_peer_public_key("bob"), Some(&bob.get_public_key()));
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        assert!(delivers(&mut bob, &mut alice, "alice"));
+    }
+
+    #[test]
+    fn another_key_under_a_known_peer_id_is_held() {
+        let (mut alice, mut bob, mut mallory) = (manager("alice"), manager("bob"), manager("bob"));
+        handshake(&mut alice, &mut bob, "bob", None);
+
+        let outcome = handshake(&mut alice, &mut mallory, "bob", None);
+        assert!(!outcome.established);
+        assert_eq!(outcome.key_change, Some(fingerprint(&bob.get_public_key())));
+        assert!(alice.has_held_key_change("bob"));
+        assert_eq!(alice.held_key_fingerprint("bob"), Some(fingerprint(&mallory.get_public_key())));
+        // The pinned session stays in use.
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+    }
+
+    #[test]
+    fn another_key_under_a_pinned_nickname_is_held_across_peer_ids() {
+        let bob_key = SecretKey::random(&mut rand::thread_rng());
+        let pinned = fingerprint(&bob_key.public_key());
+        // A new run: nothing is known about this peer ID, only the pin.
+        let (mut alice, mut mallory) = (manager("alice"), manager("bob-2"));
+        let outcome = handshake(&mut alice, &mut mallory, "bob-2", Some(&pinned));
+        assert!(!outcome.established);
+        assert_eq!(outcome.key_change.as_deref(), Some(pinned.as_str()));
+        assert!(!alice.has_session("bob-2"));
+
+        let mut bob = SecurityManager::new("bob-3".to_string(), bob_key, [1; 32], [2; 32], RekeyPolicy::default());
+        let outcome = handshake(&mut alice, &mut bob, "bob-3", Some(&normalize_fingerprint(&pinned)));
+        assert!(outcome.established && outcome.key_change.is_none());
+    }
+
+    #[test]
+    fn a_further_key_does_not_replace_the_held_one() {
+        let (mut alice, mut bob) = (manager("alice"), manager("bob"));
+        let (mut mallory, mut eve) = (manager("bob"), manager("bob"));
+        handshake(&mut alice, &mut bob, "bob", None);
+        handshake(&mut alice, &mut mallory, "bob", None);
+
+        let outcome = handshake(&mut alice, &mut eve, "bob", None);
+        assert!(outcome.key_change.is_none() && !outcome.established);
+        assert_eq!(alice.held_key_fingerprint("bob"), Some(fingerprint(&mallory.get_public_key())));
+        // The held key completing again is not reported a second time.
+        let outcome = handshake(&mut alice, &mut mallory, "bob", None);
+        assert!(outcome.key_change.is_none());
+    }
+
+    #[test]
+    fn accepting_a_held_key_switches_to_it() {
+        let (mut alice, mut bob, mut new_bob) = (manager("alice"), manager("bob"), manager("bob"));
+        handshake(&mut alice, &mut bob, "bob", None);
+        handshake(&mut alice, &mut new_bob, "bob", None);
+
+        assert_eq!(alice.accept_key_change("bob"), Some([1; 32]));
+        assert!(!alice.has_held_key_change("bob"));
+        assert_eq!(alice.get_peer_public_key("bob"), Some(&new_bob.get_public_key()));
+        assert!(delivers(&mut alice, &mut new_bob, "bob"));
+        assert!(alice.accept_key_change("bob").is_none());
+    }
+
+    #[test]
+    fn rejecting_a_held_key_keeps_the_pinned_one() {
+        let (mut alice, mut bob, mut mallory) = (manager("alice"), manager("bob"), manager("bob"));
+        handshake(&mut alice, &mut bob, "bob", None);
+        handshake(&mut alice, &mut mallory, "bob", None);
+
+        assert!(alice.reject_key_change("bob"));
+        assert!(!alice.reject_key_change("bob"));
+        assert!(!alice.has_held_key_change("bob"));
+        assert_eq!(alice.get_peer_public_key("bob"), Some(&bob.get_public_key()));
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+    }
+
+    /// Runs a rekey `initiator` starts, up to the last handshake message,
+    /// which is returned instead of being delivered.
+    fn rekey_until_last_message(
+        initiator: &mut SecurityManager,
+        responder: &mut SecurityManager,
+        responder_id: &str,
+    ) -> Vec<u8> {
+        let initiator_id = initiator.my_peer_id.clone();
+        let first = initiator.initiate_rekey(responder_id).unwrap();
+        let second = responder.handle_handshake_message(&initiator_id, &first, None).unwrap().reply.unwrap();
+        let outcome = initiator.handle_handshake_message(responder_id, &second, None).unwrap();
+        assert!(outcome.established && outcome.rekeyed);
+        outcome.reply.unwrap()
+    }
+
+    fn expire_grace(manager: &mut SecurityManager) {
+        for retired in manager.retired_sessions.values_mut() {
+            retired.retired_at = Instant::now() - REKEY_GRACE;
+        }
+    }
+
+    #[test]
+    fn a_rekey_is_due_once_the_message_limit_is_reached() {
+        let (mut alice, mut bob) = (manager_with("alice", FEW_MESSAGES), manager_with("bob", FEW_MESSAGES));
+        handshake(&mut alice, &mut bob, "bob", None);
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        assert!(delivers(&mut bob, &mut alice, "alice"));
+        assert!(alice.due_rekeys().is_empty());
+
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        assert_eq!(alice.due_rekeys(), vec!["bob".to_string()]);
+        alice.initiate_rekey("bob").unwrap();
+        assert!(alice.due_rekeys().is_empty());
+    }
+
+    #[test]
+    fn messages_cross_the_rekey_on_both_sessions() {
+        let (mut alice, mut bob) = (manager_with("alice", FEW_MESSAGES), manager_with("bob", FEW_MESSAGES));
+        handshake(&mut alice, &mut bob, "bob", None);
+        for _ in 0..3 {
+            assert!(delivers(&mut alice, &mut bob, "bob"));
+        }
+        let last = rekey_until_last_message(&mut alice, &mut bob, "bob");
+
+        // Bob has not finished the handshake, so Alice stays on the old session.
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        let in_flight = alice.encrypt_for_peer(b"late", "bob").unwrap();
+        bob.handle_handshake_message("alice", &last, None).unwrap();
+        assert_eq!(bob.decrypt_from_peer(&in_flight, "alice").as_deref(), Some(b"late".as_slice()));
+
+        // Bob's first message on the new session moves Alice over as well.
+        assert!(delivers(&mut bob, &mut alice, "alice"));
+        assert!(alice.retired_sessions.values().all(|retired| !retired.send_on_old));
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        assert!(alice.due_rekeys().is_empty());
+    }
+
+    #[test]
+    fn the_old_session_is_dropped_after_the_grace_period() {
+        let (mut alice, mut bob) = (manager("alice"), manager("bob"));
+        handshake(&mut alice, &mut bob, "bob", None);
+        let last = rekey_until_last_message(&mut alice, &mut bob, "bob");
+        let in_flight = alice.encrypt_for_peer(b"late", "bob").unwrap();
+
+        // Until Bob confirms, Alice keeps the old session however long it takes.
+        expire_grace(&mut alice);
+        alice.due_rekeys();
+        assert_eq!(alice.retired_sessions.len(), 1);
+
+        bob.handle_handshake_message("alice", &last, None).unwrap();
+        assert!(delivers(&mut bob, &mut alice, "alice"));
+        expire_grace(&mut alice);
+        expire_grace(&mut bob);
+        alice.due_rekeys();
+        bob.due_rekeys();
+        assert!(alice.retired_sessions.is_empty());
+        assert!(bob.retired_sessions.is_empty());
+        assert!(bob.decrypt_from_peer(&in_flight, "alice").is_none());
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+    }
+
+    #[test]
+    fn session_usage_survives_a_restart() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+        let (mut alice, mut bob) = (manager_with("alice", FEW_MESSAGES), manager_with("bob", FEW_MESSAGES));
+        alice.load_sessions(&dir.0).unwrap();
+        handshake(&mut alice, &mut bob, "bob", None);
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+        assert!(delivers(&mut alice, &mut bob, "bob"));
+
+        let mut restarted = manager_with("alice", FEW_MESSAGES);
+        restarted.load_sessions(&dir.0).unwrap();
+        assert!(restarted.due_rekeys().is_empty());
+        assert!(delivers(&mut restarted, &mut bob, "bob"));
+        assert_eq!(restarted.due_rekeys(), vec!["bob".to_string()]);
     }
 }
@@ -1,20 +1,63 @@
 use anyhow::Result;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::seq::SliceRandom;
 use uuid::Uuid;
 use super::peer_manager::PeerManager;
 use super::fragment_manager::FragmentManager;
-use super::security_manager::SecurityManager;
+use super::security_manager::{RekeyPolicy, SecurityManager};
 use super::message_handler::MessageHandler;
 use super::connection_manager::BluetoothConnectionManager;
-use super::message_handler::MessageHandlerDelegate;
-use super::peer_manager::PeerManagerDelegate;
-use super::connection_manager::BluetoothConnectionManagerDelegate;
 use super::packet_processor::{PacketProcessor, PacketProcessorDelegate};
-use crate::bitchat_packet::{BitchatMessage, DeliveryAck, ReadReceipt};
+use super::diagnostics_manager::{DiagnosticsManager, PING_TIMEOUT};
+use super::protocol::MessageType;
+use super::config::MeshConfig;
+use super::backup;
+use super::block_list::BlockList;
+use super::channel_manager::ChannelManager;
+use super::contact_store::ContactStore;
+use super::group_manager::{GroupManager, MembershipChange};
+use super::identity::{fingerprint, group_fingerprint, normalize_fingerprint, signing_key_fingerprint, Identity};
+use super::invite;
+use super::relay_manager::{RelayKind, RelayManager};
+use super::replay_cache::ReplayCache;
+use super::signature_manager::{SignatureManager, SignatureStatus};
+use super::storage;
+use super::sync_manager::{SyncManager, SyncRequest};
+use crate::bitchat_packet::{
+    BitchatMessage, BitchatPacket, ChannelAnnounce, ChannelMember, DeliveryAck, DeliveryStatus, GroupCiphertext, GroupControl, GroupMember,
+    ModerationAction, PeerAnnounce, PingReply, PingRequest, SealedEnvelope, EMERGENCY_TTL,
+};
+use crate::commands::Command;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything the mesh reports back to the UI.
+#[derive(Debug, Clone)]
+pub enum MeshEvent {
+    Message(Box<BitchatMessage>),
+    DeliveryAck(DeliveryAck),
+    Notice(String),
+    /// Something the user must not miss, such as a verified peer's key changing.
+    SecurityWarning(String),
+    /// All local state was destroyed; the UI should drop its history too.
+    Wiped,
+}
+
 pub struct BluetoothMeshService {
     my_peer_id: String,
+    /// Kept for one rotation so packets addressed to it are still ours.
+    previous_peer_id: Option<String>,
+    id_rotation: Option<Duration>,
+    last_rotation: Instant,
+    rekey_policy: RekeyPolicy,
+    sealed_sender: bool,
+    onion_hops: usize,
+    my_nickname: String,
+    data_dir: PathBuf,
     is_active: bool,
     peer_manager: Arc<Mutex<PeerManager>>,
     fragment_manager: Arc<Mutex<FragmentManager>>,
@@ -22,46 +65,142 @@ pub struct BluetoothMeshService {
     message_handler: Arc<Mutex<MessageHandler>>,
     connection_manager: Arc<Mutex<BluetoothConnectionManager>>,
     packet_processor: Arc<Mutex<PacketProcessor>>,
-    message_tx: mpsc::Sender<BitchatMessage>,
+    sync_manager: Arc<Mutex<SyncManager>>,
+    relay_manager: Arc<Mutex<RelayManager>>,
+    diagnostics_manager: Arc<Mutex<DiagnosticsManager>>,
+    contact_store: Arc<Mutex<ContactStore>>,
+    channel_manager: Arc<Mutex<ChannelManager>>,
+    signature_manager: Arc<Mutex<SignatureManager>>,
+    group_manager: Arc<Mutex<GroupManager>>,
+    block_list: Arc<Mutex<BlockList>>,
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    event_tx: mpsc::Sender<MeshEvent>,
 }
 
 impl BluetoothMeshService {
-    pub fn new(message_tx: mpsc::Sender<BitchatMessage>) -> Arc<Mutex<Self>> {
+    pub fn new(
+        config: MeshConfig,
+        identity: Identity,
+        contact_store: ContactStore,
+        event_tx: mpsc::Sender<MeshEvent>,
+    ) -> Arc<Mutex<Self>> {
         let my_peer_id = Uuid::new_v4().to_string();
+        // With rotation on, even the first epoch gets a throwaway signing key so
+        // it cannot be linked to earlier runs.
+        let signing_key = match config.id_rotation {
+            Some(_) => SigningKey::generate(&mut rand::thread_rng()),
+            None => identity.signing_key().clone(),
+        };
+        let mut security_manager = SecurityManager::new(
+            my_peer_id.clone(),
+            identity.static_secret().clone(),
+            signing_key.verifying_key().to_bytes(),
+            identity.signing_key().verifying_key().to_bytes(),
+            config.rekey_policy,
+        );
+        let signature_manager = Arc::new(Mutex::new(SignatureManager::new(signing_key, config.signature_policy)));
+        if let Err(e) = security_manager.load_sessions(&config.data_dir) {
+            log::warn!("Failed to load saved sessions: {:#}", e);
+        }
+        let mut channel_manager = ChannelManager::new(identity.signing_key().clone(), &fingerprint(&identity.public_key()));
+        if let Err(e) = channel_manager.load(&config.data_dir) {
+            log::warn!("Failed to load channels: {:#}", e);
+        }
+        let mut group_manager = GroupManager::new(fingerprint(&identity.public_key()));
+        if let Err(e) = group_manager.load(&config.data_dir) {
+            log::warn!("Failed to load groups: {:#}", e);
+        }
+        let mut block_list = BlockList::new(config.relay_blocked);
+        if let Err(e) = block_list.load(&config.data_dir) {
+            log::warn!("Failed to load blocklist: {:#}", e);
+        }
+        let block_list = Arc::new(Mutex::new(block_list));
+        let mut replay_cache = ReplayCache::new(config.max_clock_skew);
+        if let Err(e) = replay_cache.load(&config.data_dir) {
+            log::warn!("Failed to load replay cache: {:#}", e);
+        }
         let service = Arc::new(Mutex::new(BluetoothMeshService {
             my_peer_id: my_peer_id.clone(),
+            previous_peer_id: None,
+            id_rotation: config.id_rotation,
+            rekey_policy: config.rekey_policy,
+            sealed_sender: config.sealed_sender,
+            onion_hops: config.onion_hops,
+            last_rotation: Instant::now(),
+            my_nickname: config.nickname,
+            data_dir: config.data_dir,
             is_active: false,
             peer_manager: Arc::new(Mutex::new(PeerManager::new())),
             fragment_manager: Arc::new(Mutex::new(FragmentManager::new())),
-            security_manager: Arc::new(Mutex::new(SecurityManager::new())),
+            security_manager: Arc::new(Mutex::new(security_manager)),
             message_handler: Arc::new(Mutex::new(MessageHandler::new(my_peer_id.clone()))),
             connection_manager: Arc::new(Mutex::new(BluetoothConnectionManager::new())),
-            packet_processor: Arc::new(Mutex::new(PacketProcessor::new(my_peer_id))),
-            message_tx,
+            packet_processor: Arc::new(Mutex::new(PacketProcessor::new(
+                signature_manager.clone(),
+                block_list.clone(),
+            ))),
+            sync_manager: Arc::new(Mutex::new(SyncManager::new())),
+            relay_manager: Arc::new(Mutex::new(RelayManager::new(config.relay_policy))),
+            diagnostics_manager: Arc::new(Mutex::new(DiagnosticsManager::new())),
+            contact_store: Arc::new(Mutex::new(contact_store)),
+            channel_manager: Arc::new(Mutex::new(channel_manager)),
+            signature_manager,
+            group_manager: Arc::new(Mutex::new(group_manager)),
+            block_list,
+            replay_cache: Arc::new(Mutex::new(replay_cache)),
+            event_tx,
         }));
 
-        let s = service.clone() as Arc<Mutex<dyn PeerManagerDelegate>>;
-        service.lock().unwrap().peer_manager.lock().unwrap().set_delegate(s);
-        let s = service.clone() as Arc<Mutex<dyn MessageHandlerDelegate>>;
-        service.lock().unwrap().message_handler.lock().unwrap().set_delegate(s);
-        let s = service.clone() as Arc<Mutex<dyn BluetoothConnectionManagerDelegate>>;
-        service.lock().unwrap().connection_manager.lock().unwrap().set_delegate(s);
-        let s = service.clone() as Arc<Mutex<dyn PacketProcessorDelegate>>;
-        service.lock().unwrap().packet_processor.lock().unwrap().set_delegate(s);
+        // Incoming packets go straight to the processor, which takes the
+        // service lock itself; the service never locks the processor back.
+        {
+            let s = service.lock().unwrap();
+            s.packet_processor.lock().unwrap().set_delegate(service.clone());
+            s.connection_manager.lock().unwrap().set_delegate(s.packet_processor.clone());
+        }
 
         service
     }
 
     pub async fn start(service: Arc<Mutex<Self>>) -> Result<()> {
-        let mut s = service.lock().unwrap();
-        if s.is_active {
-            return Ok(());
-        }
-        s.is_active = true;
-        let connection_manager = s.connection_manager.clone();
-        drop(s);
+        let connection_manager = {
+            let mut s = service.lock().unwrap();
+            if s.is_active {
+                return Ok(());
+            }
+            s.is_active = true;
+            s.send_announce();
+            if s.id_rotation.is_some() {
+                s.emit(MeshEvent::Notice(format!(
+                    "Your peer ID changes every few minutes, but your nickname ({}) is announced each time \
+                     and links them; pick a common one if that matters. Channels you own are signed \
+                     with your identity key and link them too.",
+                    s.my_nickname
+                )));
+            }
+            s.connection_manager.clone()
+        };
+
+        let maintenance_service = service.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut s = maintenance_service.lock().unwrap();
+                if !s.is_active {
+                    break;
+                }
+                if s.id_rotation.is_some_and(|interval| s.last_rotation.elapsed() >= interval) {
+                    s.rotate_peer_id();
+                }
+                s.flush_relay_queue();
+                s.retry_handshakes();
+                s.rekey_sessions();
+                s.replay_cache.lock().unwrap().save();
+            }
+        });
 
-        connection_manager.lock().unwrap().start_services().await?;
+        BluetoothConnectionManager::start_services(connection_manager).await?;
         Ok(())
     }
 
@@ -76,47 +215,1475 @@ impl BluetoothMeshService {
         s.security_manager.lock().unwrap().shutdown();
         s.message_handler.lock().unwrap().shutdown();
         s.connection_manager.lock().unwrap().stop_services();
-        s.packet_processor.lock().unwrap().shutdown();
+        s.sync_manager.lock().unwrap().shutdown();
+        s.relay_manager.lock().unwrap().shutdown();
+        s.diagnostics_manager.lock().unwrap().shutdown();
+        s.contact_store.lock().unwrap().shutdown();
+        s.channel_manager.lock().unwrap().shutdown();
+        s.signature_manager.lock().unwrap().shutdown();
+        s.group_manager.lock().unwrap().shutdown();
+        s.block_list.lock().unwrap().shutdown();
+        s.replay_cache.lock().unwrap().shutdown();
+        storage::lock();
         Ok(())
     }
-}
 
-impl MessageHandlerDelegate for BluetoothMeshService {
-    fn on_message_received(&self, message: &BitchatMessage) {
-        self.message_tx.blocking_send(message.clone()).unwrap();
+    pub fn handle_command(service: Arc<Mutex<Self>>, command: Command) {
+        let mut s = service.lock().unwrap();
+        match command {
+            Command::SendMessage(content) => s.send_public_message(content, false),
+            Command::JoinChannel { channel, password } => s.join_channel(&channel, password.as_deref()),
+            Command::Invite { channel, hours } => s.invite_to_channel(&channel, hours),
+            Command::RedeemInvite(token) => s.redeem_invite(&token),
+            Command::SetChannelPassword(password) => s.set_channel_password(&password),
+            Command::SetTopic(topic) => s.set_channel_topic(&topic),
+            Command::Kick(nickname) => s.moderate_channel_member(&nickname, ChannelManager::kick),
+            Command::Ban(nickname) => s.moderate_channel_member(&nickname, ChannelManager::ban),
+            Command::Unban(nickname) => s.unban_channel_member(&nickname),
+            Command::TransferChannel(nickname) => s.transfer_channel(&nickname),
+            Command::PrivateMessage { nickname, content } => s.send_private_message(&nickname, content),
+            Command::Emergency(content) => s.send_public_message(content, true),
+            Command::Ping(nickname) => s.send_ping(&service, &nickname, false),
+            Command::Trace(nickname) => s.send_ping(&service, &nickname, true),
+            Command::Verify { nickname, fingerprint } => s.verify_peer(&nickname, fingerprint.as_deref()),
+            Command::CreateGroup(group) => s.create_group(&group),
+            Command::AddGroupMember { group, nickname } => s.add_group_member(&group, &nickname),
+            Command::RemoveGroupMember { group, nickname } => s.remove_group_member(&group, &nickname),
+            Command::GroupMessage { group, content } => s.send_group_message(&group, content),
+            Command::Panic => s.panic_wipe(),
+            Command::Block(nickname) => s.block_peer(&nickname),
+            Command::Unblock(nickname) => s.unblock_peer(&nickname),
+            Command::AcceptKey(nickname) => s.accept_key_change(&nickname),
+            Command::RejectKey(nickname) => s.reject_key_change(&nickname),
+            Command::ExportBackup { path, passphrase } => s.export_backup(&path, &passphrase),
+            Command::ImportBackup { path, passphrase } => s.import_backup(&path, &passphrase),
+            Command::ShowLog => {}
+        }
     }
 
-    fn on_delivery_ack_received(&self, ack: &DeliveryAck) {
-        // TODO: Handle delivery ack
+    /// Switches to a fresh peer ID and signing key so the device cannot be
+    /// followed by its advertisements. Verified contacts get a new handshake,
+    /// which tells them who the new ID belongs to.
+    ///
+    /// A handshake shows our static key to whoever is on the other end, so
+    /// while rotating we never start one with an ID the user has not acted
+    /// on. Answering one still shows the key to the peer who asked. The
+    /// nickname is announced unchanged, which links IDs unless it is common.
+    fn rotate_peer_id(&mut self) {
+        let new_peer_id = Uuid::new_v4().to_string();
+        let signing_key = SigningKey::generate(&mut rand::thread_rng());
+        let signing_public = signing_key.verifying_key().to_bytes();
+
+        self.previous_peer_id = Some(std::mem::replace(&mut self.my_peer_id, new_peer_id.clone()));
+        self.last_rotation = Instant::now();
+        self.signature_manager.lock().unwrap().set_signing_key(signing_key);
+        self.security_manager.lock().unwrap().set_my_identity(new_peer_id.clone(), signing_public);
+        self.message_handler.lock().unwrap().set_my_peer_id(new_peer_id);
+        self.send_announce();
+
+        let peer_ids = self.peer_manager.lock().unwrap().get_all_peer_ids();
+        for peer_id in peer_ids {
+            if self.is_peer_verified(&peer_id) {
+                self.start_handshake(&peer_id);
+            }
+        }
     }
 
-    fn on_read_receipt_received(&self, receipt: &ReadReceipt) {
-        // TODO: Handle read receipt
+    /// Destroys every key, session, contact, channel, group and cached message,
+    /// in memory and on disk, then carries on as a brand new identity.
+    fn panic_wipe(&mut self) {
+        self.peer_manager.lock().unwrap().shutdown();
+        self.fragment_manager.lock().unwrap().shutdown();
+        self.message_handler.lock().unwrap().shutdown();
+        self.sync_manager.lock().unwrap().shutdown();
+        self.relay_manager.lock().unwrap().shutdown();
+        self.diagnostics_manager.lock().unwrap().shutdown();
+        self.signature_manager.lock().unwrap().shutdown();
+        let results = [
+            self.security_manager.lock().unwrap().wipe(),
+            self.contact_store.lock().unwrap().wipe(),
+            self.channel_manager.lock().unwrap().wipe(),
+            self.group_manager.lock().unwrap().wipe(),
+            self.block_list.lock().unwrap().wipe(),
+            self.replay_cache.lock().unwrap().wipe(),
+            Identity::wipe(&self.data_dir),
+        ];
+        self.emit(MeshEvent::Wiped);
+        for e in results.into_iter().filter_map(Result::err) {
+            self.emit(MeshEvent::SecurityWarning(format!("Wipe incomplete: {:#}", e)));
+        }
+
+        if let Some(new_fingerprint) = self.reload_identity() {
+            self.emit(MeshEvent::Notice(format!("All local data erased. New fingerprint: {}", new_fingerprint)));
+        }
     }
-}
 
-impl PeerManagerDelegate for BluetoothMeshService {
-    fn on_peer_connected(&self, nickname: &str) {
-        // TODO: Handle peer connected
+    fn export_backup(&self, path: &Path, passphrase: &str) {
+        let identity = match Identity::load_or_create(&self.data_dir) {
+            Ok(identity) => identity,
+            Err(e) => {
+                self.emit(MeshEvent::Notice(format!("Backup failed: {:#}", e)));
+                return;
+            }
+        };
+        let contacts = self.contact_store.lock().unwrap().verified_contacts();
+        let count = contacts.len();
+        match backup::export(path, passphrase, &identity, contacts) {
+            Ok(()) => self.emit(MeshEvent::Notice(format!(
+                "Saved your identity and {} verified contact(s) to {}. Anyone with this file and its passphrase can pose as you.",
+                count,
+                path.display()
+            ))),
+            Err(e) => self.emit(MeshEvent::Notice(format!("Backup failed: {:#}", e))),
+        }
     }
 
-    fn on_peer_disconnected(&self, nickname: &str) {
-        // TODO: Handle peer disconnected
+    /// Replaces our identity with the one in a backup and adds its verified
+    /// contacts. Sessions and groups belonged to the old identity and go.
+    fn import_backup(&mut self, path: &Path, passphrase: &str) {
+        let backup = match backup::import(path, passphrase) {
+            Ok(backup) => backup,
+            Err(e) => {
+                self.emit(MeshEvent::Notice(format!("Import failed: {:#}", e)));
+                return;
+            }
+        };
+        if let Err(e) = Identity::restore(&self.data_dir, &backup.static_secret, &backup.signing_key) {
+            self.emit(MeshEvent::Notice(format!("Import failed: {:#}", e)));
+            return;
+        }
+        for result in [
+            self.security_manager.lock().unwrap().wipe(),
+            self.group_manager.lock().unwrap().wipe(),
+        ] {
+            if let Err(e) = result {
+                log::warn!("Failed to clear state of the replaced identity: {:#}", e);
+            }
+        }
+        let count = backup.contacts.len();
+        {
+            let mut contact_store = self.contact_store.lock().unwrap();
+            contact_store.merge_verified(backup.contacts);
+            if let Err(e) = contact_store.save() {
+                log::warn!("Failed to save contacts: {}", e);
+            }
+        }
+
+        if let Some(restored) = self.reload_identity() {
+            self.emit(MeshEvent::Notice(format!(
+                "Restored identity {} from a backup made {} with {} verified contact(s)",
+                restored,
+                backup.created_at.format("%Y-%m-%d"),
+                count
+            )));
+        }
     }
 
-    fn on_peer_list_updated(&self, peer_ids: &[String]) {
-        // TODO: Handle peer list updated
+    /// Rebuilds the persistent managers from whatever identity and state is
+    /// on disk now, under a fresh peer ID, and reconnects securely to the
+    /// peers still around unless the peer ID rotates. Returns the identity's
+    /// fingerprint.
+    fn reload_identity(&mut self) -> Option<String> {
+        let identity = match Identity::load_or_create(&self.data_dir) {
+            Ok(identity) => identity,
+            Err(e) => {
+                self.emit(MeshEvent::SecurityWarning(format!("Could not load an identity: {:#}", e)));
+                return None;
+            }
+        };
+        let signing_key = match self.id_rotation {
+            Some(_) => SigningKey::generate(&mut rand::thread_rng()),
+            None => identity.signing_key().clone(),
+        };
+        let new_peer_id = Uuid::new_v4().to_string();
+        self.my_peer_id = new_peer_id.clone();
+        self.previous_peer_id = None;
+        self.last_rotation = Instant::now();
+
+        let mut security_manager = SecurityManager::new(
+            new_peer_id.clone(),
+            identity.static_secret().clone(),
+            signing_key.verifying_key().to_bytes(),
+            identity.signing_key().verifying_key().to_bytes(),
+            self.rekey_policy,
+        );
+        if let Err(e) = security_manager.load_sessions(&self.data_dir) {
+            log::warn!("Failed to load saved sessions: {:#}", e);
+        }
+        let mut channel_manager = ChannelManager::new(identity.signing_key().clone(), &fingerprint(&identity.public_key()));
+        if let Err(e) = channel_manager.load(&self.data_dir) {
+            log::warn!("Failed to load channels: {:#}", e);
+        }
+        let mut group_manager = GroupManager::new(fingerprint(&identity.public_key()));
+        if let Err(e) = group_manager.load(&self.data_dir) {
+            log::warn!("Failed to load groups: {:#}", e);
+        }
+        match ContactStore::load(&self.data_dir) {
+            Ok(contact_store) => *self.contact_store.lock().unwrap() = contact_store,
+            Err(e) => log::warn!("Failed to load contacts: {:#}", e),
+        }
+        if let Err(e) = self.block_list.lock().unwrap().load(&self.data_dir) {
+            log::warn!("Failed to load blocklist: {:#}", e);
+        }
+        {
+            let mut replay_cache = self.replay_cache.lock().unwrap();
+            replay_cache.save();
+            if let Err(e) = replay_cache.load(&self.data_dir) {
+                log::warn!("Failed to load replay cache: {:#}", e);
+            }
+        }
+        *self.security_manager.lock().unwrap() = security_manager;
+        *self.channel_manager.lock().unwrap() = channel_manager;
+        *self.group_manager.lock().unwrap() = group_manager;
+        self.signature_manager.lock().unwrap().set_signing_key(signing_key);
+        self.message_handler.lock().unwrap().set_my_peer_id(new_peer_id);
+
+        self.send_announce();
+        // While rotating, the new identity is shown to nobody unprompted.
+        if self.id_rotation.is_none() {
+            let peer_ids = self.peer_manager.lock().unwrap().get_all_peer_ids();
+            for peer_id in peer_ids {
+                self.start_handshake(&peer_id);
+            }
+        }
+        Some(fingerprint(&identity.public_key()))
+    }
+
+    fn is_my_peer_id(&self, peer_id: &str) -> bool {
+        peer_id == self.my_peer_id || self.previous_peer_id.as_deref() == Some(peer_id)
+    }
+
+    fn send_public_message(&self, content: String, emergency: bool) {
+        let mut message = BitchatMessage::new(self.my_nickname.clone(), content);
+        message.sender_peer_id = Some(self.my_peer_id.clone());
+        // Emergency broadcasts always go to everyone, whatever channel we are in.
+        if !emergency {
+            message.channel = self.channel_manager.lock().unwrap().current_channel().map(str::to_string);
+        }
+
+        let mut wire = message.clone();
+        if let Some(channel) = &message.channel {
+            match self.channel_manager.lock().unwrap().encrypt(channel, message.content.as_bytes()) {
+                Ok(Some(encrypted)) => {
+                    wire.content = String::new();
+                    wire.encrypted_content = Some(encrypted);
+                    wire.is_encrypted = true;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.emit(MeshEvent::Notice(e.to_string()));
+                    return;
+                }
+            }
+        }
+        let payload = match wire.to_binary_payload() {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to encode message: {}", e);
+                return;
+            }
+        };
+
+        let mut packet = if emergency {
+            BitchatPacket::new(MessageType::Emergency as u8, self.my_peer_id.clone(), payload)
+                .with_ttl(EMERGENCY_TTL)
+        } else {
+            BitchatPacket::new(MessageType::Message as u8, self.my_peer_id.clone(), payload)
+        };
+        // Signed before it is stored, since sync replays this exact packet.
+        self.signature_manager.lock().unwrap().sign(&mut packet);
+        self.sync_manager.lock().unwrap().record_message(&wire, &packet, true);
+        self.replay_cache.lock().unwrap().record_own(&wire);
+        self.flood(&packet);
+
+        message.is_emergency = emergency;
+        message.delivery_status = Some(DeliveryStatus::Sent);
+        self.emit(MeshEvent::Message(Box::new(message)));
+    }
+
+    fn join_channel(&self, channel: &str, password: Option<&str>) {
+        let (result, topic) = {
+            let mut channel_manager = self.channel_manager.lock().unwrap();
+            let result = channel_manager.join(channel, password);
+            (result, channel_manager.topic(channel).map(str::to_string))
+        };
+        match result {
+            Ok(None) => {
+                self.emit(MeshEvent::Notice(format!("Joined {}", channel)));
+                if let Some(topic) = topic {
+                    self.emit(MeshEvent::Notice(format!("Topic: {}", topic)));
+                }
+            }
+            Ok(Some(claim)) => {
+                self.publish_channel_announce(Ok(claim));
+                self.emit(MeshEvent::Notice(format!("Joined {}; nobody had claimed it, so it is yours", channel)));
+            }
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
     }
-}
 
-impl BluetoothConnectionManagerDelegate for BluetoothMeshService {
-    fn on_packet_received(&self, packet: &[u8], peer_id: &str) {
-        self.packet_processor.lock().unwrap().process_packet(packet, peer_id).unwrap();
+    fn invite_to_channel(&self, channel: &str, hours: i64) {
+        let result = self.channel_manager.lock().unwrap().invite(channel, &self.my_nickname, hours);
+        match result {
+            Ok(token) => self.emit(MeshEvent::Notice(format!(
+                "Invite to {} for the next {} hours: /join {} (anyone holding it gets the channel key, \
+                 which stays valid until you change the password)",
+                channel, hours, token
+            ))),
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
+    }
+
+    fn redeem_invite(&self, token: &str) {
+        let invite = match invite::redeem(token) {
+            Ok(invite) => invite,
+            Err(e) => {
+                self.emit(MeshEvent::SecurityWarning(e.to_string()));
+                return;
+            }
+        };
+        let result = self.channel_manager.lock().unwrap().join_with_invite(&invite);
+        match result {
+            Ok(owner_known) => self.emit(MeshEvent::Notice(format!(
+                "Joined {} with an invite from {} ({}), redeemable until {}{}",
+                invite.channel,
+                invite.issuer_nickname,
+                group_fingerprint(&invite.issuer_fingerprint),
+                invite.expires_at.format("%Y-%m-%d %H:%M UTC"),
+                if owner_known { "" } else { "; its owner is not known here yet, so that is unchecked" }
+            ))),
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
+    }
+
+    fn set_channel_password(&self, password: &str) {
+        let result = self.channel_manager.lock().unwrap().set_password(password);
+        if let Some(announce) = self.publish_channel_announce(result) {
+            self.emit(MeshEvent::Notice(format!("Password set for {}", announce.channel)));
+        }
+    }
+
+    fn set_channel_topic(&self, topic: &str) {
+        let result = self.channel_manager.lock().unwrap().set_topic(topic);
+        if let Some(announce) = self.publish_channel_announce(result) {
+            self.emit(MeshEvent::Notice(format!("Topic of {} set to: {}", announce.channel, topic)));
+        }
+    }
+
+    /// Kicks or bans a peer from the current channel. Bans follow the identity
+    /// key, so the peer needs a secure session with us first.
+    fn moderate_channel_member(
+        &self,
+        nickname: &str,
+        action: fn(&mut ChannelManager, ChannelMember) -> Result<ChannelAnnounce>,
+    ) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        let Some(their_fingerprint) = self.require_session(nickname, &peer_id) else {
+            return;
+        };
+        let member = ChannelMember { fingerprint: normalize_fingerprint(&their_fingerprint), nickname: nickname.to_string() };
+        let result = action(&mut self.channel_manager.lock().unwrap(), member);
+        let Some(announce) = self.publish_channel_announce(result) else {
+            return;
+        };
+        let verb = if matches!(announce.action, ModerationAction::Kick(_)) { "Kicked" } else { "Banned" };
+        self.emit(MeshEvent::Notice(format!("{} {} from {}", verb, nickname, announce.channel)));
+    }
+
+    /// Hands the current channel to a peer, named by the identity key they
+    /// sent in our handshake.
+    fn transfer_channel(&self, nickname: &str) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        if self.require_session(nickname, &peer_id).is_none() {
+            return;
+        }
+        let Some(identity_key) = self.security_manager.lock().unwrap().get_peer_identity_key(&peer_id) else {
+            self.emit(MeshEvent::Notice(format!("{} runs a version that cannot own channels", nickname)));
+            return;
+        };
+        let member = ChannelMember {
+            fingerprint: normalize_fingerprint(&signing_key_fingerprint(&identity_key)),
+            nickname: nickname.to_string(),
+        };
+        let result = self.channel_manager.lock().unwrap().transfer_ownership(member);
+        if let Some(announce) = self.publish_channel_announce(result) {
+            self.emit(MeshEvent::Notice(format!("{} now owns {}", nickname, announce.channel)));
+        }
+    }
+
+    fn unban_channel_member(&self, nickname: &str) {
+        let result = self.channel_manager.lock().unwrap().unban(nickname);
+        if let Some(announce) = self.publish_channel_announce(result) {
+            self.emit(MeshEvent::Notice(format!("Unbanned {} from {}", nickname, announce.channel)));
+        }
+    }
+
+    fn publish_channel_announce(&self, result: Result<ChannelAnnounce>) -> Option<ChannelAnnounce> {
+        let announce = match result {
+            Ok(announce) => announce,
+            Err(e) => {
+                self.emit(MeshEvent::Notice(e.to_string()));
+                return None;
+            }
+        };
+        match bincode::serialize(&announce) {
+            Ok(payload) => self.broadcast(MessageType::ChannelAnnounce, payload),
+            Err(e) => log::warn!("Failed to encode channel announce: {}", e),
+        }
+        Some(announce)
+    }
+
+    fn send_private_message(&self, nickname: &str, content: String) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+
+        let mut message = BitchatMessage::new(self.my_nickname.clone(), content);
+        message.sender_peer_id = Some(self.my_peer_id.clone());
+        message.is_private = true;
+        message.recipient_nickname = Some(nickname.to_string());
+
+        let has_session = self.security_manager.lock().unwrap().has_session(&peer_id);
+        if has_session {
+            message.delivery_status = Some(self.send_encrypted_message(&message, &peer_id));
+        } else {
+            self.message_handler.lock().unwrap().queue_outgoing(&peer_id, message.clone());
+            if !self.security_manager.lock().unwrap().is_handshake_pending(&peer_id) {
+                self.start_handshake(&peer_id);
+            }
+            message.delivery_status = Some(DeliveryStatus::Sending);
+        }
+        self.emit(MeshEvent::Message(Box::new(message)));
+    }
+
+    fn send_encrypted_message(&self, message: &BitchatMessage, peer_id: &str) -> DeliveryStatus {
+        // Picked first: a message encrypted and then not sent would leave a
+        // gap in the peer's ratchet.
+        let path = match (self.onion_hops > 0).then(|| self.onion_path(peer_id)).transpose() {
+            Ok(path) => path,
+            Err(reason) => {
+                self.emit(MeshEvent::Notice(format!("Private message not sent: {}", reason)));
+                return DeliveryStatus::Failed { reason };
+            }
+        };
+        let encrypted = self.security_manager.lock().unwrap().encrypt_for_peer(message.content.as_bytes(), peer_id);
+        let Some(encrypted) = encrypted else {
+            log::debug!("No session to encrypt message {} for {}", message.id, peer_id);
+            return DeliveryStatus::Failed { reason: "no secure session".to_string() };
+        };
+        let mut wire = message.clone();
+        wire.content = String::new();
+        wire.encrypted_content = Some(encrypted);
+        wire.is_encrypted = true;
+        let payload = match wire.to_binary_payload() {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to encode message: {}", e);
+                return DeliveryStatus::Failed { reason: "could not encode".to_string() };
+            }
+        };
+        let packet = BitchatPacket::new(MessageType::Message as u8, self.my_peer_id.clone(), payload)
+            .with_recipient(peer_id.to_string());
+        if let Some(path) = path {
+            if let Err(reason) = self.send_onion(packet, &path) {
+                self.emit(MeshEvent::Notice(format!("Private message not sent: {}", reason)));
+                return DeliveryStatus::Failed { reason };
+            }
+        } else if self.sealed_sender {
+            self.send_sealed(packet, peer_id);
+        } else {
+            self.send_packet(&packet);
+        }
+        self.message_handler.lock().unwrap().record_sent(&message.id);
+        DeliveryStatus::Sent
+    }
+
+    /// Signs `packet`, then layers it along `path`, which ends with the
+    /// recipient.
+    fn send_onion(&self, mut packet: BitchatPacket, path: &[String]) -> Result<(), String> {
+        self.signature_manager.lock().unwrap().sign(&mut packet);
+        let inner = packet.to_bytes().map_err(|e| format!("could not encode: {}", e))?;
+        let envelope = self
+            .security_manager
+            .lock()
+            .unwrap()
+            .wrap_onion(&inner, path)
+            .ok_or_else(|| "could not build onion".to_string())?;
+        let payload = bincode::serialize(&envelope).map_err(|e| format!("could not encode: {}", e))?;
+        let onion = BitchatPacket::new(MessageType::OnionMessage as u8, Uuid::new_v4().to_string(), payload)
+            .with_recipient(path[0].clone());
+        self.flood(&onion);
+        Ok(())
+    }
+
+    /// A random path through `onion_hops` relays picked from the peers we
+    /// hold keys for, ending with `peer_id`. There is none if there are too
+    /// few; falling back to a direct send would defeat the point.
+    fn onion_path(&self, peer_id: &str) -> Result<Vec<String>, String> {
+        let peer_ids = self.peer_manager.lock().unwrap().get_all_peer_ids();
+        let security_manager = self.security_manager.lock().unwrap();
+        let mut relays: Vec<String> = peer_ids
+            .into_iter()
+            .filter(|id| id != peer_id)
+            .filter(|id| security_manager.get_peer_public_key(id).is_some() && !security_manager.has_held_key_change(id))
+            .collect();
+        if relays.len() < self.onion_hops {
+            return Err(format!("only {} peer(s) to route through, {} needed", relays.len(), self.onion_hops));
+        }
+        relays.shuffle(&mut rand::thread_rng());
+        relays.truncate(self.onion_hops);
+        relays.push(peer_id.to_string());
+        Ok(relays)
+    }
+
+    /// Signs `packet` as usual, then seals it to the recipient so relays see
+    /// only a throwaway sender ID and the recipient's peer ID.
+    fn send_sealed(&self, mut packet: BitchatPacket, peer_id: &str) {
+        self.signature_manager.lock().unwrap().sign(&mut packet);
+        let inner = match packet.to_bytes() {
+            Ok(inner) => inner,
+            Err(e) => {
+                log::warn!("Failed to encode packet: {}", e);
+                return;
+            }
+        };
+        let Some(envelope) = self.security_manager.lock().unwrap().seal_for_peer(&inner, peer_id) else {
+            return;
+        };
+        match bincode::serialize(&envelope) {
+            Ok(payload) => {
+                let sealed = BitchatPacket::new(MessageType::SealedMessage as u8, Uuid::new_v4().to_string(), payload)
+                    .with_recipient(peer_id.to_string());
+                self.flood(&sealed);
+            }
+            Err(e) => log::warn!("Failed to encode sealed envelope: {}", e),
+        }
+    }
+
+    fn start_handshake(&self, peer_id: &str) {
+        let message = self.security_manager.lock().unwrap().initiate_handshake(peer_id);
+        match message {
+            Ok(message) => self.send_key_exchange(message, peer_id),
+            Err(e) => log::warn!("Failed to start handshake with {}: {}", peer_id, e),
+        }
+    }
+
+    fn send_key_exchange(&self, message: Vec<u8>, peer_id: &str) {
+        let packet = BitchatPacket::new(MessageType::KeyExchange as u8, self.my_peer_id.clone(), message)
+            .with_recipient(peer_id.to_string());
+        self.send_packet(&packet);
+    }
+
+    /// Replaces sessions that are due; the old ones keep working meanwhile.
+    fn rekey_sessions(&self) {
+        let due = self.security_manager.lock().unwrap().due_rekeys();
+        for peer_id in due {
+            let message = self.security_manager.lock().unwrap().initiate_rekey(&peer_id);
+            match message {
+                Ok(message) => self.send_key_exchange(message, &peer_id),
+                Err(e) => log::warn!("Failed to start rekey with {}: {}", peer_id, e),
+            }
+        }
+    }
+
+    fn retry_handshakes(&self) {
+        let retries = self.security_manager.lock().unwrap().poll_handshakes();
+        for (peer_id, message) in retries.resend {
+            self.send_key_exchange(message, &peer_id);
+        }
+        for peer_id in retries.failed {
+            let dropped = self.message_handler.lock().unwrap().take_outgoing(&peer_id);
+            let nickname = self.peer_nickname(&peer_id);
+            self.emit(MeshEvent::Notice(format!(
+                "Could not establish a secure session with {}; {} message(s) not sent",
+                nickname,
+                dropped.len()
+            )));
+        }
+    }
+
+    fn flush_outbox(&self, peer_id: &str) {
+        let queued = self.message_handler.lock().unwrap().take_outgoing(peer_id);
+        for mut message in queued {
+            message.delivery_status = Some(self.send_encrypted_message(&message, peer_id));
+            self.emit(MeshEvent::Message(Box::new(message)));
+        }
+    }
+
+    fn create_group(&self, name: &str) {
+        let result = self.group_manager.lock().unwrap().create(name, &self.my_nickname);
+        match result {
+            Ok(_) => self.emit(MeshEvent::Notice(format!(
+                "Created group {}; add members with /group add {} <nick>",
+                name, name
+            ))),
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
+    }
+
+    fn add_group_member(&self, name: &str, nickname: &str) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        let Some(their_fingerprint) = self.require_session(nickname, &peer_id) else {
+            return;
+        };
+        let member = GroupMember { fingerprint: their_fingerprint, nickname: nickname.to_string() };
+        let result = self.group_manager.lock().unwrap().add_member(name, member);
+        match result {
+            Ok(group_id) => {
+                self.announce_membership(&group_id, &[]);
+                self.rekey_group(&group_id);
+                self.emit(MeshEvent::Notice(format!("Added {} to {}", nickname, name)));
+            }
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
+    }
+
+    fn remove_group_member(&self, name: &str, nickname: &str) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        let Some(their_fingerprint) = self.require_session(nickname, &peer_id) else {
+            return;
+        };
+        let result = self.group_manager.lock().unwrap().remove_member(name, &their_fingerprint);
+        match result {
+            Ok(group_id) => {
+                // The removed member learns they are out; everyone else rekeys.
+                self.announce_membership(&group_id, &[their_fingerprint]);
+                self.rekey_group(&group_id);
+                self.emit(MeshEvent::Notice(format!("Removed {} from {}", nickname, name)));
+            }
+            Err(e) => self.emit(MeshEvent::Notice(e.to_string())),
+        }
+    }
+
+    /// Sends the member list to every member, plus anyone in `removed`.
+    fn announce_membership(&self, group_id: &str, removed: &[String]) {
+        let Some(membership) = self.group_manager.lock().unwrap().membership(group_id) else {
+            return;
+        };
+        let mut recipients = self.group_manager.lock().unwrap().other_members(group_id);
+        recipients.extend_from_slice(removed);
+        for member in recipients {
+            self.send_group_control(&membership, &member);
+        }
+    }
+
+    /// Replaces our sender key for the group and hands it to every member.
+    fn rekey_group(&self, group_id: &str) {
+        let Some(sender_key) = self.group_manager.lock().unwrap().rotate_sender_key(group_id) else {
+            return;
+        };
+        let members = self.group_manager.lock().unwrap().other_members(group_id);
+        for member in members {
+            self.send_group_control(&sender_key, &member);
+        }
+    }
+
+    /// Brings a member who just (re)connected up to date on every group we share.
+    fn sync_groups(&self, peer_id: &str) {
+        let Some(their_fingerprint) = self.peer_fingerprint(peer_id) else {
+            return;
+        };
+        let group_ids = self.group_manager.lock().unwrap().groups_with_member(&their_fingerprint);
+        for group_id in group_ids {
+            let (membership, sender_key) = {
+                let group_manager = self.group_manager.lock().unwrap();
+                let membership = group_manager.is_owner(&group_id).then(|| group_manager.membership(&group_id));
+                (membership.flatten(), group_manager.sender_key(&group_id))
+            };
+            for control in membership.iter().chain(sender_key.iter()) {
+                self.send_group_control(control, &their_fingerprint);
+            }
+        }
+    }
+
+    /// Sends a group control message over the pairwise session with the
+    /// member, if they are around and we have one.
+    fn send_group_control(&self, control: &GroupControl, member_fingerprint: &str) {
+        let encrypted = {
+            let mut security_manager = self.security_manager.lock().unwrap();
+            let Some(peer_id) = security_manager.find_peer_by_fingerprint(member_fingerprint) else {
+                return;
+            };
+            let payload = match bincode::serialize(control) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::warn!("Failed to encode group control message: {}", e);
+                    return;
+                }
+            };
+            security_manager.encrypt_for_peer(&payload, &peer_id).map(|encrypted| (peer_id, encrypted))
+        };
+        if let Some((peer_id, encrypted)) = encrypted {
+            let packet = BitchatPacket::new(MessageType::GroupControl as u8, self.my_peer_id.clone(), encrypted)
+                .with_recipient(peer_id);
+            self.send_packet(&packet);
+        }
+    }
+
+    fn send_group_message(&self, name: &str, content: String) {
+        let mut message = BitchatMessage::new(self.my_nickname.clone(), content);
+        let plaintext = match message.to_binary_payload() {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                log::warn!("Failed to encode message: {}", e);
+                return;
+            }
+        };
+        let encrypted = self.group_manager.lock().unwrap().encrypt(name, &plaintext);
+        let encrypted = match encrypted {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                self.emit(MeshEvent::Notice(e.to_string()));
+                return;
+            }
+        };
+        match bincode::serialize(&encrypted) {
+            Ok(payload) => self.broadcast(MessageType::GroupMessage, payload),
+            Err(e) => {
+                log::warn!("Failed to encode group message: {}", e);
+                return;
+            }
+        }
+        message.group = Some(name.to_string());
+        message.delivery_status = Some(DeliveryStatus::Sent);
+        self.emit(MeshEvent::Message(Box::new(message)));
+    }
+
+    fn block_peer(&self, nickname: &str) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        match self.peer_fingerprint(&peer_id) {
+            Some(their_fingerprint) => {
+                self.block_list.lock().unwrap().block(&their_fingerprint, nickname, &peer_id);
+                self.emit(MeshEvent::Notice(format!(
+                    "Blocked {}; the block follows their key through new nicknames and IDs",
+                    nickname
+                )));
+            }
+            None => {
+                self.block_list.lock().unwrap().block_peer_id(&peer_id);
+                self.emit(MeshEvent::Notice(format!(
+                    "Blocked {} for now; without a secure session the block cannot follow them to a new ID",
+                    nickname
+                )));
+            }
+        }
+    }
+
+    fn unblock_peer(&self, nickname: &str) {
+        let peer_id = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname);
+        let unblocked = self.block_list.lock().unwrap().unblock(nickname, peer_id.as_deref());
+        if unblocked {
+            self.emit(MeshEvent::Notice(format!("Unblocked {}", nickname)));
+        } else {
+            self.emit(MeshEvent::Notice(format!("{} is not blocked", nickname)));
+        }
+    }
+
+    fn verify_peer(&self, nickname: &str, claimed: Option<&str>) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+        let Some(their_fingerprint) = self.require_session(nickname, &peer_id) else {
+            return;
+        };
+
+        let Some(claimed) = claimed else {
+            let my_fingerprint = fingerprint(&self.security_manager.lock().unwrap().get_public_key());
+            self.emit(MeshEvent::Notice(format!("{}'s fingerprint: {}", nickname, their_fingerprint)));
+            self.emit(MeshEvent::Notice(format!("Your fingerprint: {}", my_fingerprint)));
+            self.emit(MeshEvent::Notice(format!(
+                "Compare in person, then run /verify {} <their fingerprint>",
+                nickname
+            )));
+            return;
+        };
+
+        if normalize_fingerprint(claimed) != normalize_fingerprint(&their_fingerprint) {
+            self.emit(MeshEvent::SecurityWarning(format!(
+                "Fingerprint mismatch for {}! The key in use is {}. Do not trust this peer.",
+                nickname, their_fingerprint
+            )));
+            return;
+        }
+
+        let mut contact_store = self.contact_store.lock().unwrap();
+        contact_store.set_verified(&their_fingerprint, nickname);
+        if let Err(e) = contact_store.save() {
+            log::warn!("Failed to save contacts: {}", e);
+        }
+        self.emit(MeshEvent::Notice(format!("{} is now verified", nickname)));
+    }
+
+    /// The fingerprint behind our session with a peer. Without a session,
+    /// starts the handshake that was not done unprompted on connect.
+    fn require_session(&self, nickname: &str, peer_id: &str) -> Option<String> {
+        if let Some(their_fingerprint) = self.peer_fingerprint(peer_id) {
+            return Some(their_fingerprint);
+        }
+        if !self.security_manager.lock().unwrap().is_handshake_pending(peer_id) {
+            self.start_handshake(peer_id);
+        }
+        self.emit(MeshEvent::Notice(format!(
+            "No secure session with {} yet; setting one up, try again in a moment",
+            nickname
+        )));
+        None
+    }
+
+    fn peer_fingerprint(&self, peer_id: &str) -> Option<String> {
+        self.security_manager.lock().unwrap().get_peer_public_key(peer_id).map(fingerprint)
+    }
+
+    fn is_peer_verified(&self, peer_id: &str) -> bool {
+        self.peer_fingerprint(peer_id)
+            .is_some_and(|fp| self.contact_store.lock().unwrap().is_verified(&fp))
+    }
+
+    /// Records the static key a peer just authenticated with, pinning its
+    /// nickname to it if nothing is pinned there yet.
+    fn record_peer_key(&self, peer_id: &str) {
+        let Some(nickname) = self.peer_manager.lock().unwrap().get_peer_nickname(peer_id) else {
+            return;
+        };
+        let Some(their_fingerprint) = self.peer_fingerprint(peer_id) else {
+            return;
+        };
+        let mut contact_store = self.contact_store.lock().unwrap();
+        contact_store.record_key(&nickname, &their_fingerprint);
+        if let Err(e) = contact_store.save() {
+            log::warn!("Failed to save contacts: {}", e);
+        }
+    }
+
+    fn report_key_change(&self, peer_id: &str, previous_fingerprint: &str) {
+        let nickname = self.peer_nickname(peer_id);
+        let Some(new_fingerprint) = self.security_manager.lock().unwrap().held_key_fingerprint(peer_id) else {
+            return;
+        };
+        let was_verified = self.contact_store.lock().unwrap().is_verified(previous_fingerprint);
+        let warning = if was_verified {
+            format!(
+                "{}'s key has CHANGED since you verified it! Was {}, now {}. Someone may be impersonating them.",
+                nickname,
+                group_fingerprint(previous_fingerprint),
+                new_fingerprint
+            )
+        } else {
+            format!(
+                "{} is using a different key than the one first seen. Was {}, now {}.",
+                nickname,
+                group_fingerprint(previous_fingerprint),
+                new_fingerprint
+            )
+        };
+        self.emit(MeshEvent::SecurityWarning(warning));
+        self.emit(MeshEvent::Notice(format!(
+            "{} stays on the old key; /accept {} switches to the new one, /reject {} drops it",
+            nickname, nickname, nickname
+        )));
+    }
+
+    /// The peer ID holding a key change under `nickname`. Another peer may
+    /// well be using the same nickname with the pinned key.
+    fn held_peer_id(&self, nickname: &str) -> Option<String> {
+        let peer_ids = self.peer_manager.lock().unwrap().find_peer_ids_by_nickname(nickname);
+        let security_manager = self.security_manager.lock().unwrap();
+        peer_ids.into_iter().find(|peer_id| security_manager.has_held_key_change(peer_id))
+    }
+
+    fn accept_key_change(&self, nickname: &str) {
+        let Some(peer_id) = self.held_peer_id(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No key change from {} is waiting", nickname)));
+            return;
+        };
+        let Some(signing_key) = self.security_manager.lock().unwrap().accept_key_change(&peer_id) else {
+            self.emit(MeshEvent::Notice(format!("No key change from {} is waiting", nickname)));
+            return;
+        };
+        if let Ok(key) = VerifyingKey::from_bytes(&signing_key) {
+            self.signature_manager.lock().unwrap().bind_key(&peer_id, key);
+        }
+        if let Some(their_fingerprint) = self.peer_fingerprint(&peer_id) {
+            let mut contact_store = self.contact_store.lock().unwrap();
+            contact_store.pin(nickname, &their_fingerprint);
+            if let Err(e) = contact_store.save() {
+                log::warn!("Failed to save contacts: {}", e);
+            }
+        }
+        self.emit(MeshEvent::Notice(format!("Accepted the new key for {}; run /verify {} to check it", nickname, nickname)));
+        self.flush_outbox(&peer_id);
+        self.sync_groups(&peer_id);
+    }
+
+    fn reject_key_change(&self, nickname: &str) {
+        let Some(peer_id) = self.held_peer_id(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No key change from {} is waiting", nickname)));
+            return;
+        };
+        if self.security_manager.lock().unwrap().reject_key_change(&peer_id) {
+            self.emit(MeshEvent::Notice(format!("Rejected the new key for {}", nickname)));
+        } else {
+            self.emit(MeshEvent::Notice(format!("No key change from {} is waiting", nickname)));
+        }
+    }
+
+    fn decrypt_private_message(&self, message: &BitchatMessage, packet: &BitchatPacket) -> Option<String> {
+        if !packet.recipient_id.as_deref().is_some_and(|id| self.is_my_peer_id(id)) {
+            return None;
+        }
+        let encrypted = message.encrypted_content.as_deref().unwrap_or_default();
+        let decrypted = self.security_manager.lock().unwrap().decrypt_from_peer(encrypted, &packet.sender_id);
+        let content = decrypted.and_then(|bytes| String::from_utf8(bytes).ok());
+        if content.is_none() {
+            self.emit(MeshEvent::Notice(format!(
+                "Could not decrypt private message from {}",
+                self.peer_nickname(&packet.sender_id)
+            )));
+            // Our ratchets have drifted apart, e.g. one side lost its saved state.
+            if !self.security_manager.lock().unwrap().is_handshake_pending(&packet.sender_id) {
+                self.start_handshake(&packet.sender_id);
+            }
+        }
+        content
+    }
+
+    fn decrypt_channel_message(&self, message: &BitchatMessage, channel: &str) -> Option<String> {
+        let mut channel_manager = self.channel_manager.lock().unwrap();
+        if !channel_manager.has_key(channel) {
+            if channel_manager.note_locked(channel) {
+                self.emit(MeshEvent::Notice(format!(
+                    "{} is password protected; join with /j {} <password> to read it",
+                    channel, channel
+                )));
+            }
+            return None;
+        }
+        let encrypted = message.encrypted_content.as_deref().unwrap_or_default();
+        match channel_manager.decrypt(channel, encrypted) {
+            Ok(bytes) => String::from_utf8(bytes).ok(),
+            Err(e) => {
+                self.emit(MeshEvent::Notice(e.to_string()));
+                None
+            }
+        }
+    }
+
+    fn peer_nickname(&self, peer_id: &str) -> String {
+        self.peer_manager
+            .lock()
+            .unwrap()
+            .get_peer_nickname(peer_id)
+            .unwrap_or_else(|| peer_id.to_string())
+    }
+
+    fn send_ping(&self, service: &Arc<Mutex<Self>>, nickname: &str, trace: bool) {
+        let Some(peer_id) = self.peer_manager.lock().unwrap().find_peer_id_by_nickname(nickname) else {
+            self.emit(MeshEvent::Notice(format!("No peer named {}", nickname)));
+            return;
+        };
+
+        let request = self.diagnostics_manager.lock().unwrap().start_ping(nickname);
+        let payload = match bincode::serialize(&request) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to encode ping: {}", e);
+                return;
+            }
+        };
+        let mut packet = BitchatPacket::new(MessageType::Ping as u8, self.my_peer_id.clone(), payload)
+            .with_recipient(peer_id);
+        if trace {
+            packet = packet.with_route_tracing();
+        }
+        self.send_packet(&packet);
+
+        let service = service.clone();
+        let ping_id = request.ping_id;
+        tokio::spawn(async move {
+            tokio::time::sleep(PING_TIMEOUT).await;
+            let s = service.lock().unwrap();
+            let expired = s.diagnostics_manager.lock().unwrap().expire_ping(&ping_id);
+            if let Some(nickname) = expired {
+                s.emit(MeshEvent::Notice(format!("{}: no reply within {}s", nickname, PING_TIMEOUT.as_secs())));
+            }
+        });
+    }
+
+    fn emit(&self, event: MeshEvent) {
+        // A full queue means the UI is behind, and there is nowhere else to say so.
+        let _ = self.event_tx.try_send(event);
+    }
+
+    fn send_sync_request(&self, peer_id: &str) {
+        let request = self.sync_manager.lock().unwrap().build_sync_request();
+        match bincode::serialize(&request) {
+            Ok(payload) => self.send_to_peer(MessageType::SyncRequest, payload, peer_id),
+            Err(e) => log::warn!("Failed to encode sync request: {}", e),
+        }
+    }
+
+    fn send_delivery_ack(&self, message: &BitchatMessage, hop_count: u8) {
+        let ack = DeliveryAck::new(
+            message.id.clone(),
+            self.my_peer_id.clone(),
+            self.my_nickname.clone(),
+            hop_count,
+        );
+        let payload = match bincode::serialize(&ack) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to encode delivery ack: {}", e);
+                return;
+            }
+        };
+        // A broadcast ack would tell every relay who the sealed message was from.
+        match message.sender_peer_id.as_deref() {
+            Some(sender_peer_id) if message.sealed || ((self.sealed_sender || self.onion_hops > 0) && message.is_private) => {
+                let packet = BitchatPacket::new(MessageType::DeliveryAck as u8, self.my_peer_id.clone(), payload)
+                    .with_recipient(sender_peer_id.to_string());
+                if self.onion_hops > 0 {
+                    let sent = self.onion_path(sender_peer_id).and_then(|path| self.send_onion(packet, &path));
+                    if let Err(reason) = sent {
+                        log::warn!("Delivery ack for {} not sent: {}", message.sender, reason);
+                    }
+                } else {
+                    self.send_sealed(packet, sender_peer_id);
+                }
+            }
+            _ => self.broadcast(MessageType::DeliveryAck, payload),
+        }
+    }
+
+    /// Sends a single-hop packet to a directly connected peer.
+    fn send_to_peer(&self, message_type: MessageType, payload: Vec<u8>, peer_id: &str) {
+        let mut packet = BitchatPacket::new(message_type as u8, self.my_peer_id.clone(), payload).with_ttl(1);
+        self.signature_manager.lock().unwrap().sign(&mut packet);
+        match packet.to_bytes() {
+            Ok(bytes) => self.connection_manager.lock().unwrap().send_packet(&bytes, peer_id),
+            Err(e) => log::warn!("Failed to encode packet: {}", e),
+        }
+    }
+
+    fn send_announce(&self) {
+        let announce = PeerAnnounce {
+            nickname: self.my_nickname.clone(),
+            signing_key: self.signature_manager.lock().unwrap().verifying_key().to_bytes().to_vec(),
+        };
+        match bincode::serialize(&announce) {
+            Ok(payload) => self.broadcast(MessageType::Announce, payload),
+            Err(e) => log::warn!("Failed to encode announce: {}", e),
+        }
+    }
+
+    fn broadcast(&self, message_type: MessageType, payload: Vec<u8>) {
+        let packet = BitchatPacket::new(message_type as u8, self.my_peer_id.clone(), payload);
+        self.send_packet(&packet);
+    }
+
+    /// Floods a packet we originated into the mesh.
+    fn send_packet(&self, packet: &BitchatPacket) {
+        let mut packet = packet.clone();
+        self.signature_manager.lock().unwrap().sign(&mut packet);
+        self.flood(&packet);
+    }
+
+    /// Floods a packet as it is, without signing it.
+    fn flood(&self, packet: &BitchatPacket) {
+        self.relay_manager.lock().unwrap().mark_seen(packet);
+        match packet.to_bytes() {
+            Ok(bytes) => self.connection_manager.lock().unwrap().broadcast_packet(&bytes),
+            Err(e) => log::warn!("Failed to encode packet: {}", e),
+        }
+    }
+
+    fn relay_packet(&self, packet: &BitchatPacket, kind: RelayKind) {
+        self.relay_manager.lock().unwrap().queue_relay(packet, kind, &self.my_peer_id);
+        self.flush_relay_queue();
+    }
+
+    fn flush_relay_queue(&self) {
+        let ready = self.relay_manager.lock().unwrap().take_ready_packets();
+        let connection_manager = self.connection_manager.lock().unwrap();
+        for packet in ready {
+            match packet.to_bytes() {
+                Ok(bytes) => connection_manager.broadcast_packet(&bytes),
+                Err(e) => log::warn!("Failed to encode relayed packet: {}", e),
+            }
+        }
+    }
+
+    /// Greets a peer seen for the first time.
+    fn on_peer_connected(&self, peer_id: &str) {
+        // Introduce ourselves in return so the new peer can check our signatures.
+        self.send_announce();
+        // Ask the returning peer for anything broadcast while we were apart.
+        self.send_sync_request(peer_id);
+        // Channel state is only ever published once, so newcomers need ours.
+        let announces = self.channel_manager.lock().unwrap().latest_announces();
+        for announce in announces {
+            match bincode::serialize(&announce) {
+                Ok(payload) => self.send_to_peer(MessageType::ChannelAnnounce, payload, peer_id),
+                Err(e) => log::warn!("Failed to encode channel announce: {}", e),
+            }
+        }
+        // A handshake would show a stranger the key behind every ID we rotate
+        // through. Someone using a blocked identity's name has seen it already,
+        // and the handshake is how a rotated blocked peer gets recognised.
+        let unmasks_blocked = self.block_list.lock().unwrap().is_blocked_nickname(&self.peer_nickname(peer_id));
+        let needs_handshake = (self.id_rotation.is_none() || unmasks_blocked) && {
+            let security_manager = self.security_manager.lock().unwrap();
+            !security_manager.has_session(peer_id) && !security_manager.is_handshake_pending(peer_id)
+        };
+        if needs_handshake {
+            self.start_handshake(peer_id);
+        }
+    }
+
+    fn is_addressed_to_me(&self, message: &BitchatMessage) -> bool {
+        message.is_private && message.recipient_nickname.as_deref() == Some(self.my_nickname.as_str())
     }
 }
 
 impl PacketProcessorDelegate for BluetoothMeshService {
-    fn handle_message(&self, message: &BitchatMessage) {
-        self.message_handler.lock().unwrap().handle_message(message);
+    fn is_my_peer_id(&self, peer_id: &str) -> bool {
+        BluetoothMeshService::is_my_peer_id(self, peer_id)
+    }
+
+    fn handle_message(&self, message: &BitchatMessage, packet: &BitchatPacket) {
+        // Checked before relaying so a recorded packet cannot be pumped back
+        // into the mesh either. Only a signed copy claims the ID for good.
+        let from_sync = message.synced;
+        let verified = message.signature_status == Some(SignatureStatus::Valid);
+        if self.replay_cache.lock().unwrap().check(message, from_sync, verified).is_err() {
+            return;
+        }
+        // Sync responses are point-to-point catch-up and are never flooded further.
+        if !from_sync && !self.is_addressed_to_me(message) {
+            // An unsigned or forged emergency gets no priority over anything else.
+            let kind = if message.is_emergency && message.signature_status == Some(SignatureStatus::Valid) {
+                RelayKind::Emergency
+            } else if message.is_private {
+                RelayKind::Direct
+            } else {
+                RelayKind::Public { channel: message.channel.as_deref() }
+            };
+            self.relay_packet(packet, kind);
+        }
+
+        if !self.sync_manager.lock().unwrap().record_message(message, packet, verified) {
+            return;
+        }
+
+        let mut message = message.clone();
+        if message.is_encrypted {
+            let content = match message.channel.clone() {
+                Some(channel) => self.decrypt_channel_message(&message, &channel),
+                None => self.decrypt_private_message(&message, packet),
+            };
+            let Some(content) = content else {
+                return;
+            };
+            message.content = content;
+            message.encrypted_content = None;
+        }
+        // A validly signed message still lies if it claims another peer's nickname.
+        let announced = self.peer_manager.lock().unwrap().get_peer_nickname(&packet.sender_id);
+        if message.signature_status == Some(SignatureStatus::Valid)
+            && announced.is_some_and(|nickname| nickname != message.sender)
+        {
+            message.signature_status = Some(SignatureStatus::Invalid);
+            let policy = self.signature_manager.lock().unwrap().policy();
+            if SignatureStatus::Invalid.is_rejected_by(policy) {
+                return;
+            }
+        }
+        // Private messages are authenticated by the ratchet; everything else needs
+        // a signature from a key the handshake tied to this peer.
+        let authenticated = if message.is_private {
+            message.is_encrypted
+        } else {
+            message.signature_status == Some(SignatureStatus::Valid)
+                && self.signature_manager.lock().unwrap().is_authenticated(&packet.sender_id)
+        };
+        message.sender_verified = authenticated && self.is_peer_verified(&packet.sender_id);
+        // Kicks and bans hold because every member stops listening.
+        if let Some(channel) = &message.channel
+            && let Some(their_fingerprint) = self.peer_fingerprint(&packet.sender_id)
+            && self.channel_manager.lock().unwrap().is_excluded(channel, &their_fingerprint)
+        {
+            return;
+        }
+
+        if self.is_addressed_to_me(&message) {
+            self.send_delivery_ack(&message, packet.hop_count);
+        }
+        self.emit(MeshEvent::Message(Box::new(message)));
+    }
+
+    /// Only announces we accept travel on, so a forgery dies at the first
+    /// hop. Forgeries cost an attacker nothing, so they are only logged.
+    fn handle_channel_announce(&self, announce: &ChannelAnnounce, packet: &BitchatPacket) {
+        let result = self.channel_manager.lock().unwrap().handle_announce(announce);
+        let outcome = match result {
+            Ok(Some(outcome)) => outcome,
+            Ok(None) => return,
+            Err(e) => {
+                log::debug!("Rejected channel announce from {}: {}", packet.sender_id, e);
+                return;
+            }
+        };
+        self.relay_packet(packet, RelayKind::Public { channel: Some(&announce.channel) });
+        let channel = &announce.channel;
+        if outcome.transferred_to_me {
+            let claim = self.channel_manager.lock().unwrap().claim(channel);
+            self.publish_channel_announce(claim);
+            self.emit(MeshEvent::Notice(format!("You now own {}", channel)));
+            return;
+        }
+        let notice = match &announce.action {
+            ModerationAction::SetPassword if outcome.stale_key => {
+                format!("The password for {} changed; rejoin with /j {} <password>", channel, channel)
+            }
+            ModerationAction::SetPassword => return,
+            ModerationAction::SetTopic => {
+                format!("Topic of {}: {}", channel, announce.state.topic.as_deref().unwrap_or_default())
+            }
+            ModerationAction::Kick(_) if outcome.removed_me => {
+                format!("You were kicked from {}", channel)
+            }
+            ModerationAction::Ban(_) if outcome.removed_me => format!("You were banned from {}", channel),
+            ModerationAction::Kick(member) => format!("{} was kicked from {}", member.nickname, channel),
+            ModerationAction::Ban(member) => format!("{} was banned from {}", member.nickname, channel),
+            ModerationAction::Unban(member) => format!("{} was unbanned from {}", member.nickname, channel),
+            ModerationAction::TransferOwnership(member) => {
+                format!("{} now owns {}", member.nickname, channel)
+            }
+            ModerationAction::Claim => return,
+        };
+        self.emit(MeshEvent::Notice(notice));
+    }
+
+    fn handle_announce(&self, nickname: &str, peer_id: &str) {
+        let is_new = self.peer_manager.lock().unwrap().add_or_update_peer(peer_id, nickname);
+        if is_new {
+            self.on_peer_connected(peer_id);
+        }
+    }
+
+    fn handle_sync_request(&self, request: &SyncRequest, peer_id: &str) {
+        let missing = self.sync_manager.lock().unwrap().get_missing_messages(request);
+        for packet in missing {
+            match packet.to_bytes() {
+                Ok(payload) => self.send_to_peer(MessageType::SyncResponse, payload, peer_id),
+                Err(e) => log::warn!("Failed to encode packet from {} for sync: {}", packet.sender_id, e),
+            }
+        }
+    }
+
+    fn handle_delivery_ack(&self, ack: &DeliveryAck, packet: &BitchatPacket) {
+        // Acks for other peers' messages are only passed along.
+        if !self.message_handler.lock().unwrap().is_awaiting_ack(&ack.original_message_id) {
+            self.relay_packet(packet, RelayKind::Direct);
+            return;
+        }
+        self.emit(MeshEvent::DeliveryAck(ack.clone()));
+    }
+
+    fn handle_ping(&self, request: &PingRequest, packet: &BitchatPacket) {
+        let reply = PingReply::new(request.ping_id.clone(), packet.hop_count, packet.route.clone());
+        let payload = match bincode::serialize(&reply) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Failed to encode pong: {}", e);
+                return;
+            }
+        };
+        let mut response = BitchatPacket::new(MessageType::Pong as u8, self.my_peer_id.clone(), payload)
+            .with_recipient(packet.sender_id.clone());
+        if packet.route.is_some() {
+            response = response.with_route_tracing();
+        }
+        self.send_packet(&response);
+    }
+
+    fn handle_pong(&self, reply: &PingReply, packet: &BitchatPacket) {
+        let result = self.diagnostics_manager.lock().unwrap().complete_ping(reply, packet);
+        if let Some(result) = result {
+            self.emit(MeshEvent::Notice(result.get_display_text()));
+        }
+    }
+
+    fn handle_key_exchange(&self, packet: &BitchatPacket) {
+        let peer_id = packet.sender_id.as_str();
+        let nickname = self.peer_manager.lock().unwrap().get_peer_nickname(peer_id);
+        let pinned = nickname
+            .and_then(|nickname| self.contact_store.lock().unwrap().pinned_fingerprint(&nickname).map(str::to_string));
+        let outcome =
+            self.security_manager.lock().unwrap().handle_handshake_message(peer_id, &packet.payload, pinned.as_deref());
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                log::debug!("Handshake with {} failed: {}", peer_id, e);
+                return;
+            }
+        };
+        if let Some(reply) = outcome.reply {
+            self.send_key_exchange(reply, peer_id);
+        }
+        if let Some(previous_fingerprint) = &outcome.key_change {
+            self.report_key_change(peer_id, previous_fingerprint);
+            return;
+        }
+        if !outcome.established {
+            return;
+        }
+        let Some(signing_key) = outcome.peer_signing_key else {
+            return;
+        };
+        let blocked = self
+            .peer_fingerprint(peer_id)
+            .is_some_and(|fp| self.block_list.lock().unwrap().bind_peer(peer_id, &fp));
+        if blocked {
+            return;
+        }
+        match &outcome.previous_peer_id {
+            // A known identity behind a rotated ID: carry everything over quietly.
+            Some(previous_peer_id) => {
+                self.peer_manager.lock().unwrap().merge_peer(previous_peer_id, peer_id);
+                let queued = self.message_handler.lock().unwrap().take_outgoing(previous_peer_id);
+                for message in queued {
+                    self.message_handler.lock().unwrap().queue_outgoing(peer_id, message);
+                }
+            }
+            // A rekey of a session we already had; nothing to tell anyone.
+            None if outcome.rekeyed => {}
+            None => {
+                self.record_peer_key(peer_id);
+                self.emit(MeshEvent::Notice(format!("Secure session established with {}", self.peer_nickname(peer_id))));
+            }
+        }
+        if let Ok(key) = VerifyingKey::from_bytes(&signing_key) {
+            self.signature_manager.lock().unwrap().bind_key(peer_id, key);
+        }
+        if outcome.rekeyed {
+            return;
+        }
+        self.flush_outbox(peer_id);
+        self.sync_groups(peer_id);
+    }
+
+    fn handle_group_control(&self, packet: &BitchatPacket) {
+        let peer_id = packet.sender_id.as_str();
+        let Some(their_fingerprint) = self.peer_fingerprint(peer_id) else {
+            return;
+        };
+        let decrypted = self.security_manager.lock().unwrap().decrypt_from_peer(&packet.payload, peer_id);
+        let Some(control) = decrypted.and_then(|bytes| bincode::deserialize::<GroupControl>(&bytes).ok()) else {
+            log::debug!("Could not decrypt group control message from {}", peer_id);
+            return;
+        };
+
+        match control {
+            GroupControl::Membership { group_id, name, owner, members, epoch } => {
+                let change = self.group_manager.lock().unwrap().apply_membership(
+                    &their_fingerprint,
+                    &group_id,
+                    &name,
+                    &owner,
+                    members,
+                    epoch,
+                );
+                match change {
+                    MembershipChange::Joined(name) => {
+                        self.rekey_group(&group_id);
+                        self.emit(MeshEvent::Notice(format!(
+                            "{} added you to group {}; talk there with /g {} <message>",
+                            self.peer_nickname(peer_id),
+                            name,
+                            name
+                        )));
+                    }
+                    MembershipChange::Updated(name) => {
+                        self.rekey_group(&group_id);
+                        self.emit(MeshEvent::Notice(format!("Members of {} changed", name)));
+                    }
+                    MembershipChange::Removed(name) => {
+                        self.emit(MeshEvent::Notice(format!("You were removed from group {}", name)));
+                    }
+                    MembershipChange::Ignored => {}
+                }
+            }
+            GroupControl::SenderKey(distribution) => {
+                self.group_manager.lock().unwrap().add_sender_key(&their_fingerprint, distribution);
+            }
+        }
+    }
+
+    fn handle_group_message(&self, message: &GroupCiphertext, packet: &BitchatPacket) {
+        self.relay_packet(packet, RelayKind::Direct);
+        let decrypted = self.group_manager.lock().unwrap().decrypt(message);
+        // Groups we are not in are expected noise; there is nothing to report.
+        let Ok((group, sender, plaintext)) = decrypted else {
+            return;
+        };
+        if self.block_list.lock().unwrap().is_blocked_fingerprint(&sender.fingerprint) {
+            return;
+        }
+        let mut message = match BitchatMessage::from_binary_payload(&plaintext) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Malformed group message in {}: {}", group, e);
+                return;
+            }
+        };
+        // The sender key proves which member wrote it, whatever name they typed in.
+        message.sender = sender.nickname;
+        message.group = Some(group);
+        message.hop_count = Some(packet.hop_count);
+        message.sender_verified = self.contact_store.lock().unwrap().is_verified(&sender.fingerprint);
+        self.emit(MeshEvent::Message(Box::new(message)));
+    }
+
+    fn handle_relay(&self, packet: &BitchatPacket) {
+        self.relay_packet(packet, RelayKind::Direct);
+    }
+
+    fn open_sealed(&self, envelope: &SealedEnvelope) -> Option<Vec<u8>> {
+        self.security_manager.lock().unwrap().open_sealed(envelope)
+    }
+
+    /// Sent as a fresh packet from a throwaway ID, so it shares nothing with
+    /// the one it arrived in. The relay policy still decides whether we carry
+    /// it, and a layer is never forwarded twice.
+    fn forward_onion(&self, next_hop: &str, envelope: &SealedEnvelope) {
+        if !self.replay_cache.lock().unwrap().check_onion(&envelope.ephemeral_key) {
+            log::debug!("Dropped a replayed onion layer for {}", next_hop);
+            return;
+        }
+        match bincode::serialize(envelope) {
+            Ok(payload) => {
+                let packet = BitchatPacket::new(MessageType::OnionMessage as u8, Uuid::new_v4().to_string(), payload)
+                    .with_recipient(next_hop.to_string());
+                self.relay_packet(&packet, RelayKind::Direct);
+            }
+            Err(e) => log::warn!("Failed to encode onion layer: {}", e),
+        }
     }
 }
@@ -0,0 +1,204 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::bitchat_packet::BitchatPacket;
+
+/// What to do with broadcasts whose signature is missing or wrong.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignaturePolicy {
+    /// Deliver them with a warning next to the sender.
+    Flag,
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SignatureStatus {
+    Valid,
+    Unsigned,
+    Invalid,
+    /// Signed, but we have no key for the sender yet.
+    UnknownSigner,
+}
+
+impl SignatureStatus {
+    pub fn get_display_text(&self) -> &'static str {
+        match self {
+            SignatureStatus::Valid => "signed",
+            SignatureStatus::Unsigned => "UNSIGNED",
+            SignatureStatus::Invalid => "INVALID SIGNATURE",
+            SignatureStatus::UnknownSigner => "unknown signer",
+        }
+    }
+
+    pub fn is_rejected_by(&self, policy: SignaturePolicy) -> bool {
+        policy == SignaturePolicy::Drop && matches!(self, SignatureStatus::Unsigned | SignatureStatus::Invalid)
+    }
+}
+
+struct PeerSigningKey {
+    key: VerifyingKey,
+    /// Learned inside a Noise handshake rather than from an announce.
+    authenticated: bool,
+}
+
+/// Signs the packets we originate and checks everyone else's against the
+/// keys peers announced or proved during the handshake.
+pub struct SignatureManager {
+    signing_key: SigningKey,
+    policy: SignaturePolicy,
+    peer_keys: HashMap<String, PeerSigningKey>,
+}
+
+impl SignatureManager {
+    pub fn new(signing_key: SigningKey, policy: SignaturePolicy) -> Self {
+        SignatureManager {
+            signing_key,
+            policy,
+            peer_keys: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> SignaturePolicy {
+        self.policy
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = signing_key;
+    }
+
+    pub fn sign(&self, packet: &mut BitchatPacket) {
+        let signature = self.signing_key.sign(&packet.signable_bytes());
+        packet.signature = Some(signature.to_bytes().to_vec());
+    }
+
+    pub fn verify(&self, packet: &BitchatPacket) -> SignatureStatus {
+        let Some(peer_key) = self.peer_keys.get(&packet.sender_id) else {
+            return match packet.signature {
+                Some(_) => SignatureStatus::UnknownSigner,
+                None => SignatureStatus::Unsigned,
+            };
+        };
+        Self::verify_with(&peer_key.key, packet)
+    }
+
+    pub fn verify_with(key: &VerifyingKey, packet: &BitchatPacket) -> SignatureStatus {
+        let Some(signature) = &packet.signature else {
+            return SignatureStatus::Unsigned;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return SignatureStatus::Invalid;
+        };
+        match key.verify(&packet.signable_bytes(), &signature) {
+            Ok(()) => SignatureStatus::Valid,
+            Err(_) => SignatureStatus::Invalid,
+        }
+    }
+
+    /// Trusts the first key announced for a peer ID. Returns `false` when the
+    /// peer ID is already tied to a different key.
+    pub fn learn_announced_key(&mut self, peer_id: &str, key: VerifyingKey) -> bool {
+        match self.peer_keys.get(peer_id) {
+            Some(existing) => existing.key == key,
+            None => {
+                self.peer_keys.insert(peer_id.to_string(), PeerSigningKey { key, authenticated: false });
+                true
+            }
+        }
+    }
+
+    /// Records a key the peer proved inside a handshake; it overrides whatever
+    /// was announced for that peer ID.
+    pub fn bind_key(&mut self, peer_id: &str, key: VerifyingKey) {
+        self.peer_keys.insert(peer_id.to_string(), PeerSigningKey { key, authenticated: true });
+    }
+
+    pub fn is_authenticated(&self, peer_id: &str) -> bool {
+        self.peer_keys.get(peer_id).is_some_and(|peer_key| peer_key.authenticated)
+    }
+
+    pub fn shutdown(&mut self) {
+        self.peer_keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::protocol::MessageType;
+
+    fn signed_packet(key: &SigningKey, sender_id: &str) -> BitchatPacket {
+        let mut packet = BitchatPacket::new(MessageType::Message as u8, sender_id.to_string(), b"hello".to_vec());
+        let signature = key.sign(&packet.signable_bytes());
+        packet.signature = Some(signature.to_bytes().to_vec());
+        packet
+    }
+
+    fn manager(policy: SignaturePolicy) -> SignatureManager {
+        SignatureManager::new(SigningKey::generate(&mut rand::thread_rng()), policy)
+    }
+
+    #[test]
+    fn packets_signed_by_the_known_key_are_valid() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut manager = manager(SignaturePolicy::Drop);
+        manager.learn_announced_key("alice", key.verifying_key());
+        assert_eq!(manager.verify(&signed_packet(&key, "alice")), SignatureStatus::Valid);
+    }
+
+    #[test]
+    fn tampered_or_foreign_signatures_are_invalid() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut manager = manager(SignaturePolicy::Drop);
+        manager.learn_announced_key("alice", key.verifying_key());
+
+        let mut tampered = signed_packet(&key, "alice");
+        tampered.payload = b"goodbye".to_vec();
+        assert_eq!(manager.verify(&tampered), SignatureStatus::Invalid);
+
+        let mallory = SigningKey::generate(&mut rand::thread_rng());
+        assert_eq!(manager.verify(&signed_packet(&mallory, "alice")), SignatureStatus::Invalid);
+    }
+
+    #[test]
+    fn missing_signatures_and_keys_are_told_apart() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut manager = manager(SignaturePolicy::Drop);
+        assert_eq!(manager.verify(&signed_packet(&key, "alice")), SignatureStatus::UnknownSigner);
+
+        let unsigned = BitchatPacket::new(MessageType::Message as u8, "alice".to_string(), b"hello".to_vec());
+        assert_eq!(manager.verify(&unsigned), SignatureStatus::Unsigned);
+        manager.learn_announced_key("alice", key.verifying_key());
+        assert_eq!(manager.verify(&unsigned), SignatureStatus::Unsigned);
+    }
+
+    #[test]
+    fn drop_policy_rejects_unsigned_and_invalid_only() {
+        for status in [SignatureStatus::Unsigned, SignatureStatus::Invalid] {
+            assert!(status.is_rejected_by(SignaturePolicy::Drop));
+            assert!(!status.is_rejected_by(SignaturePolicy::Flag));
+        }
+        for status in [SignatureStatus::Valid, SignatureStatus::UnknownSigner] {
+            assert!(!status.is_rejected_by(SignaturePolicy::Drop));
+            assert!(!status.is_rejected_by(SignaturePolicy::Flag));
+        }
+    }
+
+    #[test]
+    fn announced_keys_do_not_replace_each_other() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let other = SigningKey::generate(&mut rand::thread_rng());
+        let mut manager = manager(SignaturePolicy::Flag);
+        assert!(manager.learn_announced_key("alice", key.verifying_key()));
+        assert!(manager.learn_announced_key("alice", key.verifying_key()));
+        assert!(!manager.learn_announced_key("alice", other.verifying_key()));
+        assert!(!manager.is_authenticated("alice"));
+
+        manager.bind_key("alice", other.verifying_key());
+        assert!(manager.is_authenticated("alice"));
+        assert_eq!(manager.verify(&signed_packet(&other, "alice")), SignatureStatus::Valid);
+    }
+}
@@ -0,0 +1,469 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const VAULT_FILE: &str = "vault.bin";
+const PENDING_VAULT_FILE: &str = "vault.new";
+const RECORD_MAGIC: &[u8; 4] = b"BCV1";
+/// Files earlier versions wrote in plaintext, encrypted when the vault is created.
+const LEGACY_FILES: &[&str] = &["identity.key", "signing.key", "contacts.bin", "sessions.bin", "channels.bin", "groups.bin"];
+/// Argon2id well above the channel password settings: this guards every key we hold.
+const KDF_MEMORY_KIB: u32 = 64 * 1024;
+const KDF_ITERATIONS: u32 = 3;
+const MIN_KEY_FILE_LEN: usize = 32;
+
+/// The key every private file is encrypted with, present only while unlocked.
+static STORAGE_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+/// What the storage key is derived from.
+pub enum Credential {
+    Passphrase(String),
+    KeyFile(PathBuf),
+}
+
+#[derive(Serialize, Deserialize)]
+enum KeySource {
+    Passphrase { memory_kib: u32, iterations: u32 },
+    KeyFile,
+}
+
+/// Stored in plaintext next to the data: enough to rederive the key and to
+/// tell a wrong passphrase from corrupt files.
+#[derive(Serialize, Deserialize)]
+struct VaultHeader {
+    salt: [u8; 16],
+    source: KeySource,
+    commitment: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedRecord {
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+pub fn vault_exists(data_dir: &Path) -> bool {
+    data_dir.join(VAULT_FILE).exists() || data_dir.join(PENDING_VAULT_FILE).exists()
+}
+
+/// Derives the storage key and keeps it in memory until `lock`. The first
+/// unlock creates the vault and encrypts any state left from before it existed.
+///
+/// The header is kept under a pending name until every old file is
+/// encrypted, so an interrupted first unlock resumes with the same key
+/// instead of leaving files no key can open.
+pub fn unlock(data_dir: &Path, credential: &Credential) -> Result<()> {
+    let path = data_dir.join(VAULT_FILE);
+    let pending_path = data_dir.join(PENDING_VAULT_FILE);
+    let key = if path.exists() {
+        open_vault(&path, credential)?
+    } else if pending_path.exists() {
+        open_vault(&pending_path, credential)?
+    } else {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let source = match credential {
+            Credential::Passphrase(_) => KeySource::Passphrase {
+                memory_kib: KDF_MEMORY_KIB,
+                iterations: KDF_ITERATIONS,
+            },
+            Credential::KeyFile(_) => KeySource::KeyFile,
+        };
+        let key = derive_key(credential, &salt, &source)?;
+        let header = VaultHeader { salt, source, commitment: key_commitment(&key) };
+        write_atomic(&pending_path, &bincode::serialize(&header)?)?;
+        key
+    };
+    *STORAGE_KEY.lock().unwrap() = Some(key);
+
+    if pending_path.exists() {
+        for name in LEGACY_FILES {
+            let path = data_dir.join(name);
+            // Files that already decrypt were done by an earlier, interrupted run.
+            if path.exists() && read_private(&path).is_err() {
+                check_private(&path)?;
+                let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+                write_private(&path, &data)?;
+            }
+        }
+        fs::rename(&pending_path, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Reads a vault header and derives the key it was made with.
+fn open_vault(path: &Path, credential: &Credential) -> Result<[u8; 32]> {
+    check_private(path)?;
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let header: VaultHeader =
+        bincode::deserialize(&bytes).with_context(|| format!("{} is corrupt", path.display()))?;
+    let key = derive_key(credential, &header.salt, &header.source)?;
+    if key_commitment(&key) != header.commitment {
+        match credential {
+            Credential::Passphrase(_) => bail!("Wrong passphrase"),
+            Credential::KeyFile(path) => bail!("{} is not the key file for this data directory", path.display()),
+        }
+    }
+    Ok(key)
+}
+
+/// Forgets the storage key; reads and writes fail until the next unlock.
+pub fn lock() {
+    let mut key = STORAGE_KEY.lock().unwrap();
+    if let Some(key) = key.as_mut() {
+        key.fill(0);
+    }
+    *key = None;
+}
+
+/// Reads and decrypts a file that must only be accessible to the current user.
+pub fn read_private(path: &Path) -> Result<Vec<u8>> {
+    check_private(path)?;
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let Some(record) = bytes.strip_prefix(RECORD_MAGIC) else {
+        bail!("{} is not encrypted; refusing to trust it", path.display());
+    };
+    let record: EncryptedRecord =
+        bincode::deserialize(record).with_context(|| format!("{} is corrupt", path.display()))?;
+    cipher()?
+        .decrypt(Nonce::from_slice(&record.nonce), Payload { msg: &record.ciphertext, aad: &record_aad(path) })
+        .map_err(|_| anyhow!("{} failed authentication; it is corrupt or was tampered with", path.display()))
+}
+
+/// Encrypts a file under the storage key, bound to its file name so records
+/// cannot be swapped for one another.
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher()?
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &record_aad(path) })
+        .map_err(|_| anyhow!("Failed to encrypt {}", path.display()))?;
+    let mut bytes = RECORD_MAGIC.to_vec();
+    bytes.extend(bincode::serialize(&EncryptedRecord { nonce, ciphertext })?);
+    write_atomic(path, &bytes)
+}
+
+fn cipher() -> Result<Aes256Gcm> {
+    let key = STORAGE_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or_else(|| anyhow!("Storage is locked"))?;
+    Ok(Aes256Gcm::new(key.into()))
+}
+
+fn record_aad(path: &Path) -> Vec<u8> {
+    path.file_name().map(|name| name.as_encoded_bytes().to_vec()).unwrap_or_default()
+}
+
+fn derive_key(credential: &Credential, salt: &[u8; 16], source: &KeySource) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    match (credential, source) {
+        (Credential::Passphrase(passphrase), KeySource::Passphrase { memory_kib, iterations }) => {
+            let params = Params::new(*memory_kib, *iterations, 1, Some(key.len()))
+                .map_err(|e| anyhow!("Bad key derivation parameters: {}", e))?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+        }
+        (Credential::KeyFile(path), KeySource::KeyFile) => {
+            let material = fs::read(path).with_context(|| format!("Failed to read key file {}", path.display()))?;
+            if material.len() < MIN_KEY_FILE_LEN {
+                bail!("Key file {} is too short; use at least {} random bytes", path.display(), MIN_KEY_FILE_LEN);
+            }
+            Hkdf::<Sha256>::new(Some(salt), &material)
+                .expand(b"bitchat storage key", &mut key)
+                .map_err(|_| anyhow!("Key derivation failed"))?;
+        }
+        (Credential::Passphrase(_), KeySource::KeyFile) => bail!("This data directory is unlocked with --key-file"),
+        (Credential::KeyFile(_), KeySource::Passphrase { .. }) => {
+            bail!("This data directory is unlocked with a passphrase, not a key file")
+        }
+    }
+    Ok(key)
+}
+
+fn key_commitment(key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bitchat storage key commitment");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// Writes to a temporary file first so a crash never leaves a truncated file
+/// behind. Not encrypted; for data that carries its own protection.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = private_file_options()
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Overwrites a file with zeros before deleting it, along with any temporary
+/// file a write left behind. Flash storage may still keep old blocks around,
+/// but nothing is left for a casual look at the disk.
+pub fn wipe_private(path: &Path) -> Result<()> {
+    for path in [path.with_extension("tmp"), path.to_path_buf()] {
+        if !path.exists() {
+            continue;
+        }
+        let len = fs::metadata(&path)?.len() as usize;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.write_all(&vec![0u8; len])?;
+        file.sync_all()?;
+        fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+#[cfg(unix)]
+fn private_file_options() -> OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true).mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+fn private_file_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    options
+}
+
+/// Refuses files other users can read, the same way ssh does for keys.
+#[cfg(unix)]
+fn check_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        bail!("{} is accessible by other users (mode {:o}); run chmod 600 on it", path.display(), mode & 0o777);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Helpers for tests of anything that reads or writes private files.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// The storage key is global, so tests that unlock take turns.
+    static UNLOCKED: Mutex<()> = Mutex::new(());
+
+    pub(crate) struct TestDir(pub(crate) PathBuf);
+
+    impl TestDir {
+        pub(crate) fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("bitchat-storage-{}", uuid::Uuid::new_v4()));
+            create_private_dir(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        pub(crate) fn key_file(&self, name: &str) -> Credential {
+            let path = self.0.join(name);
+            let mut material = [0u8; MIN_KEY_FILE_LEN];
+            rand::thread_rng().fill_bytes(&mut material);
+            write_atomic(&path, &material).unwrap();
+            Credential::KeyFile(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            lock();
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn take_turn() -> std::sync::MutexGuard<'static, ()> {
+        UNLOCKED.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::{take_turn, TestDir};
+
+    #[test]
+    fn private_files_round_trip_encrypted() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+
+        let path = dir.0.join("contacts.bin");
+        write_private(&path, b"alice and bob").unwrap();
+        assert_eq!(read_private(&path).unwrap(), b"alice and bob");
+        let on_disk = fs::read(&path).unwrap();
+        assert!(on_disk.starts_with(RECORD_MAGIC));
+        assert!(!on_disk.windows(5).any(|window| window == b"alice"));
+    }
+
+    #[test]
+    fn tampered_and_swapped_files_are_rejected() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+
+        let path = dir.0.join("sessions.bin");
+        write_private(&path, b"ratchet state").unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        write_atomic(&path, &bytes).unwrap();
+        assert!(read_private(&path).is_err());
+
+        // A valid record under another name does not decrypt either.
+        let groups = dir.0.join("groups.bin");
+        write_private(&groups, b"group keys").unwrap();
+        fs::copy(&groups, &path).unwrap();
+        assert!(read_private(&path).is_err());
+    }
+
+    #[test]
+    fn plaintext_files_are_not_trusted() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+
+        let path = dir.0.join("channels.bin");
+        write_atomic(&path, b"planted").unwrap();
+        assert!(read_private(&path).is_err());
+    }
+
+    #[test]
+    fn locked_storage_reads_and_writes_nothing() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+        let path = dir.0.join("contacts.bin");
+        write_private(&path, b"contacts").unwrap();
+
+        lock();
+        assert!(read_private(&path).is_err());
+        assert!(write_private(&path, b"other").is_err());
+    }
+
+    #[test]
+    fn the_wrong_key_file_does_not_unlock() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        let right = dir.key_file("right");
+        unlock(&dir.0, &right).unwrap();
+        let path = dir.0.join("contacts.bin");
+        write_private(&path, b"contacts").unwrap();
+        lock();
+
+        assert!(unlock(&dir.0, &dir.key_file("wrong")).is_err());
+        assert!(read_private(&path).is_err());
+        unlock(&dir.0, &right).unwrap();
+        assert_eq!(read_private(&path).unwrap(), b"contacts");
+    }
+
+    #[test]
+    fn the_wrong_passphrase_does_not_unlock() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &Credential::Passphrase("correct horse".to_string())).unwrap();
+        lock();
+
+        let error = unlock(&dir.0, &Credential::Passphrase("battery staple".to_string())).unwrap_err();
+        assert_eq!(error.to_string(), "Wrong passphrase");
+        assert!(unlock(&dir.0, &dir.key_file("key")).is_err());
+    }
+
+    #[test]
+    fn legacy_files_are_encrypted_on_first_unlock() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        let identity = dir.0.join("identity.key");
+        write_atomic(&identity, b"old identity").unwrap();
+
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+        assert!(dir.0.join(VAULT_FILE).exists());
+        assert!(!dir.0.join(PENDING_VAULT_FILE).exists());
+        assert_eq!(read_private(&identity).unwrap(), b"old identity");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_interrupted_migration_resumes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        let credential = dir.key_file("key");
+        let identity = dir.0.join("identity.key");
+        let contacts = dir.0.join("contacts.bin");
+        write_atomic(&identity, b"old identity").unwrap();
+        write_atomic(&contacts, b"old contacts").unwrap();
+
+        // An unreadable file stops the first run after the identity is done.
+        fs::set_permissions(&contacts, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(unlock(&dir.0, &credential).is_err());
+        assert!(dir.0.join(PENDING_VAULT_FILE).exists());
+        assert!(!dir.0.join(VAULT_FILE).exists());
+        assert!(vault_exists(&dir.0));
+        lock();
+
+        fs::set_permissions(&contacts, fs::Permissions::from_mode(0o600)).unwrap();
+        unlock(&dir.0, &credential).unwrap();
+        assert!(dir.0.join(VAULT_FILE).exists());
+        assert!(!dir.0.join(PENDING_VAULT_FILE).exists());
+        // The identity was not encrypted a second time.
+        assert_eq!(read_private(&identity).unwrap(), b"old identity");
+        assert_eq!(read_private(&contacts).unwrap(), b"old contacts");
+    }
+
+    #[test]
+    fn wiped_files_are_gone() {
+        let _turn = take_turn();
+        let dir = TestDir::new();
+        unlock(&dir.0, &dir.key_file("key")).unwrap();
+        let path = dir.0.join("replay.bin");
+        write_private(&path, b"ids").unwrap();
+        wipe_private(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, VecDeque};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::bitchat_packet::{BitchatMessage, BitchatPacket};
+
+const MAX_STORED_MESSAGES: usize = 1000;
+pub const SYNC_WINDOW_HOURS: i64 = 12;
+const BLOOM_BITS_PER_ITEM: usize = 10;
+const BLOOM_HASH_COUNT: u8 = 7;
+
+/// Compact summary of the message IDs a peer already has. Each request uses a
+/// fresh seed so a false positive in one round is unlikely to repeat in the next.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BloomFilter {
+    seed: u32,
+    hash_count: u8,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: usize, seed: u32) -> Self {
+        let bit_count = (expected_items.max(1) * BLOOM_BITS_PER_ITEM).next_multiple_of(8);
+        BloomFilter {
+            seed,
+            hash_count: BLOOM_HASH_COUNT,
+            bits: vec![0; bit_count / 8],
+        }
+    }
+
+    pub fn insert(&mut self, id: &str) {
+        for index in self.bit_indexes(id) {
+            self.bits[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        if self.bits.is_empty() {
+            return false;
+        }
+        self.bit_indexes(id)
+            .all(|index| self.bits[index / 8] & (1 << (index % 8)) != 0)
+    }
+
+    fn bit_indexes(&self, id: &str) -> impl Iterator<Item = usize> + use<> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_be_bytes());
+        hasher.update(id.as_bytes());
+        let digest = hasher.finalize();
+        let h1 = u64::from_be_bytes(digest[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(digest[8..16].try_into().unwrap());
+        let bit_count = (self.bits.len() * 8).max(1) as u64;
+        (0..self.hash_count as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncRequest {
+    pub since: i64,
+    pub filter: BloomFilter,
+}
+
+/// A message kept for sync, along with the packet its author signed.
+struct StoredMessage {
+    timestamp: DateTime<Utc>,
+    packet: BitchatPacket,
+}
+
+/// Keeps a bounded window of recent public messages so that peers coming back
+/// into range can fetch whatever was broadcast while they were apart. The
+/// author's packet is replayed as is, so the receiver can check its signature.
+pub struct SyncManager {
+    /// Whether the copy kept for each ID had a valid signature.
+    seen_ids: HashMap<String, bool>,
+    messages: HashMap<String, StoredMessage>,
+    order: VecDeque<String>,
+}
+
+impl SyncManager {
+    pub fn new() -> Self {
+        SyncManager {
+            seen_ids: HashMap::new(),
+            messages: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a message and returns `false` if it has been seen before. A
+    /// copy with a valid signature still replaces one without, so a forgery
+    /// sent first does not keep the genuine message out.
+    pub fn record_message(&mut self, message: &BitchatMessage, packet: &BitchatPacket, verified: bool) -> bool {
+        let previous = self.seen_ids.get(&message.id).copied();
+        if previous.is_some_and(|was_verified| was_verified || !verified) {
+            return false;
+        }
+        self.seen_ids.insert(message.id.clone(), verified);
+
+        // Private messages are only remembered for de-duplication, never re-sent.
+        if !message.is_private {
+            let stored = StoredMessage { timestamp: message.timestamp, packet: packet.clone() };
+            self.messages.insert(message.id.clone(), stored);
+        }
+        if previous.is_some() {
+            return true;
+        }
+        self.order.push_back(message.id.clone());
+        while self.order.len() > MAX_STORED_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen_ids.remove(&oldest);
+                self.messages.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn build_sync_request(&mut self) -> SyncRequest {
+        self.prune_expired();
+        let since = (Utc::now() - Duration::hours(SYNC_WINDOW_HOURS)).timestamp_millis();
+        let mut filter = BloomFilter::new(self.messages.len(), rand::random());
+        for id in self.messages.keys() {
+            filter.insert(id);
+        }
+        SyncRequest { since, filter }
+    }
+
+    /// The signed packets of the messages the requester's filter lacks.
+    pub fn get_missing_messages(&self, request: &SyncRequest) -> Vec<BitchatPacket> {
+        self.order
+            .iter()
+            .filter(|id| !request.filter.contains(id))
+            .filter_map(|id| self.messages.get(id))
+            .filter(|m| m.timestamp.timestamp_millis() >= request.since)
+            .map(|m| m.packet.clone())
+            .collect()
+    }
+
+    fn prune_expired(&mut self) {
+        let cutoff = Utc::now() - Duration::hours(SYNC_WINDOW_HOURS);
+        self.messages.retain(|_, stored| stored.timestamp >= cutoff);
+    }
+
+    pub fn shutdown(&mut self) {
+        self.seen_ids.clear();
+        self.messages.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::protocol::MessageType;
+
+    fn message(hours_ago: i64) -> (BitchatMessage, BitchatPacket) {
+        let mut message = BitchatMessage::new("alice".to_string(), "hi".to_string());
+        message.timestamp = Utc::now() - Duration::hours(hours_ago);
+        let packet = BitchatPacket::new(
+            MessageType::Message as u8,
+            "alice".to_string(),
+            message.to_binary_payload().unwrap(),
+        );
+        (message, packet)
+    }
+
+    fn ids(packets: &[BitchatPacket]) -> Vec<String> {
+        packets.iter().map(|packet| BitchatMessage::from_binary_payload(&packet.payload).unwrap().id).collect()
+    }
+
+    /// A request for what `ids` lack, with a filter roomy enough that a
+    /// false positive would take astronomical luck.
+    fn request_lacking(ids: &[&str]) -> SyncRequest {
+        let mut filter = BloomFilter::new(10_000, rand::random());
+        for id in ids {
+            filter.insert(id);
+        }
+        SyncRequest { since: (Utc::now() - Duration::hours(SYNC_WINDOW_HOURS)).timestamp_millis(), filter }
+    }
+
+    #[test]
+    fn only_messages_missing_from_the_filter_are_sent() {
+        let mut ours = SyncManager::new();
+        let messages: Vec<_> = (0..20).map(|_| message(0)).collect();
+        for (message, packet) in &messages {
+            ours.record_message(message, packet, true);
+        }
+
+        let theirs: Vec<&str> = messages.iter().step_by(2).map(|(message, _)| message.id.as_str()).collect();
+        let missing = ours.get_missing_messages(&request_lacking(&theirs));
+        let expected: Vec<String> = messages.iter().skip(1).step_by(2).map(|(message, _)| message.id.clone()).collect();
+        assert_eq!(ids(&missing), expected);
+        assert_eq!(missing[0].to_bytes().unwrap(), messages[1].1.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn our_own_request_asks_for_nothing_we_have() {
+        let mut ours = SyncManager::new();
+        for (message, packet) in (0..50).map(|_| message(0)) {
+            ours.record_message(&message, &packet, true);
+        }
+        let request = ours.build_sync_request();
+        assert!(ours.get_missing_messages(&request).is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_not_sent() {
+        let mut ours = SyncManager::new();
+        let (old, old_packet) = message(SYNC_WINDOW_HOURS + 1);
+        let (recent, recent_packet) = message(1);
+        ours.record_message(&old, &old_packet, true);
+        ours.record_message(&recent, &recent_packet, true);
+
+        assert_eq!(ids(&ours.get_missing_messages(&request_lacking(&[]))), vec![recent.id.clone()]);
+        ours.build_sync_request();
+        assert!(!ours.messages.contains_key(&old.id));
+    }
+
+    #[test]
+    fn private_messages_are_never_sent() {
+        let mut ours = SyncManager::new();
+        let (mut private, packet) = message(0);
+        private.is_private = true;
+        assert!(ours.record_message(&private, &packet, true));
+        assert!(!ours.record_message(&private, &packet, true));
+        assert!(ours.get_missing_messages(&request_lacking(&[])).is_empty());
+    }
+
+    #[test]
+    fn a_verified_copy_replaces_an_unverified_one() {
+        let mut ours = SyncManager::new();
+        let (message, genuine) = message(0);
+        let mut forged = genuine.clone();
+        forged.sender_id = "mallory".to_string();
+        assert!(ours.record_message(&message, &forged, false));
+        assert!(!ours.record_message(&message, &forged, false));
+        assert!(ours.record_message(&message, &genuine, true));
+        assert!(!ours.record_message(&message, &forged, false));
+
+        let missing = ours.get_missing_messages(&request_lacking(&[]));
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].sender_id, genuine.sender_id);
+    }
+
+    #[test]
+    fn bloom_filters_hold_what_was_inserted() {
+        let mut filter = BloomFilter::new(100, 7);
+        let ids: Vec<String> = (0..100).map(|i| format!("message-{}", i)).collect();
+        for id in &ids {
+            filter.insert(id);
+        }
+        assert!(ids.iter().all(|id| filter.contains(id)));
+        let false_positives = (0..1000).filter(|i| filter.contains(&format!("other-{}", i))).count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+        assert!(!BloomFilter::new(0, 7).contains("anything"));
+    }
+}
@@ -1,23 +1,43 @@
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
-    text::Text,
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Terminal,
 };
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::io;
-use crate::bitchat_packet::BitchatMessage;
+use std::io::{self, Write};
+use crate::bitchat_packet::{BitchatMessage, DeliveryStatus};
+use crate::commands::Command;
+use crate::mesh::logger;
+use crate::mesh::service::MeshEvent;
+use crate::mesh::signature_manager::SignatureStatus;
 use tokio::sync::mpsc;
 
+enum ChatLine {
+    Message(Box<BitchatMessage>),
+    Notice(String),
+    Warning(String),
+}
+
+/// A command waiting for a passphrase typed into the masked input box.
+struct PassphrasePrompt {
+    command: Command,
+    /// Exports ask twice, since a typo would make the backup useless.
+    first_entry: Option<String>,
+}
+
 struct AppState {
     input: String,
-    messages: Vec<BitchatMessage>,
+    messages: Vec<ChatLine>,
+    emergency_banner: Option<String>,
+    pending_emergency: Option<String>,
+    pending_passphrase: Option<PassphrasePrompt>,
 }
 
 impl AppState {
@@ -25,11 +45,188 @@ impl AppState {
         AppState {
             input: String::new(),
             messages: vec![],
+            emergency_banner: None,
+            pending_emergency: None,
+            pending_passphrase: None,
+        }
+    }
+
+    fn handle_event(&mut self, event: MeshEvent) {
+        match event {
+            MeshEvent::Message(message) => {
+                if message.is_emergency {
+                    self.emergency_banner = Some(format!("{}: {}", message.sender, message.content));
+                }
+                // A queued private message comes back once more when it is actually sent.
+                let existing = self.messages.iter_mut().find_map(|line| match line {
+                    ChatLine::Message(m) if m.id == message.id => Some(m),
+                    _ => None,
+                });
+                match existing {
+                    Some(existing) => *existing = message,
+                    None => self.messages.push(ChatLine::Message(message)),
+                }
+            }
+            MeshEvent::DeliveryAck(ack) => {
+                let message = self.messages.iter_mut().find_map(|line| match line {
+                    ChatLine::Message(m) if m.id == ack.original_message_id => Some(m),
+                    _ => None,
+                });
+                if let Some(message) = message {
+                    message.delivery_status = Some(DeliveryStatus::Delivered {
+                        to: ack.recipient_nickname,
+                        at: ack.timestamp,
+                        hop_count: ack.hop_count,
+                    });
+                }
+            }
+            MeshEvent::Notice(text) => self.messages.push(ChatLine::Notice(text)),
+            MeshEvent::Wiped => {
+                logger::clear();
+                *self = AppState::new();
+            }
+            MeshEvent::SecurityWarning(text) => self.messages.push(ChatLine::Warning(text)),
+        }
+    }
+
+    fn submit_input(&mut self, command_tx: &mpsc::Sender<Command>) {
+        let input = std::mem::take(&mut self.input);
+        if input.trim().is_empty() {
+            return;
+        }
+        if !input.starts_with('/') {
+            self.send_command(Command::SendMessage(input), command_tx);
+            return;
+        }
+
+        match Command::parse(&input) {
+            // Emergency broadcasts reach everyone in range, so ask before sending.
+            Ok(Command::Emergency(content)) => self.pending_emergency = Some(content),
+            Ok(command) if command.needs_passphrase() => {
+                self.pending_passphrase = Some(PassphrasePrompt { command, first_entry: None });
+            }
+            Ok(Command::ShowLog) => {
+                let log = logger::recent();
+                if log.is_empty() {
+                    self.messages.push(ChatLine::Notice("Log is empty".to_string()));
+                }
+                self.messages.extend(log.into_iter().map(|line| ChatLine::Notice(format!("log: {}", line))));
+            }
+            Ok(command) => self.send_command(command, command_tx),
+            Err(e) => self.messages.push(ChatLine::Notice(e)),
+        }
+    }
+
+    fn confirm_emergency(&mut self, confirmed: bool, command_tx: &mpsc::Sender<Command>) {
+        let Some(content) = self.pending_emergency.take() else {
+            return;
+        };
+        if confirmed {
+            self.send_command(Command::Emergency(content), command_tx);
+        } else {
+            self.messages.push(ChatLine::Notice("Emergency broadcast cancelled".to_string()));
+        }
+    }
+
+    fn submit_passphrase(&mut self, command_tx: &mpsc::Sender<Command>) {
+        let passphrase = std::mem::take(&mut self.input);
+        let Some(mut prompt) = self.pending_passphrase.take() else {
+            return;
+        };
+        if matches!(prompt.command, Command::ExportBackup { .. }) {
+            match prompt.first_entry.take() {
+                None => {
+                    prompt.first_entry = Some(passphrase);
+                    self.pending_passphrase = Some(prompt);
+                    return;
+                }
+                Some(first) if first != passphrase => {
+                    self.messages.push(ChatLine::Notice("Passphrases do not match; backup cancelled".to_string()));
+                    return;
+                }
+                Some(_) => {}
+            }
+        }
+        self.send_command(prompt.command.with_passphrase(passphrase), command_tx);
+    }
+
+    fn send_command(&mut self, command: Command, command_tx: &mpsc::Sender<Command>) {
+        if command_tx.try_send(command).is_err() {
+            self.messages.push(ChatLine::Notice("Mesh service is busy, try again".to_string()));
         }
     }
 }
 
-pub async fn run_ui(mut rx: mpsc::Receiver<BitchatMessage>) -> Result<(), io::Error> {
+fn short_peer_id(peer_id: &str) -> String {
+    peer_id.chars().take(8).collect()
+}
+
+/// Verified senders get a check mark so an impersonator with the same nickname stands out.
+fn sender_label(message: &BitchatMessage) -> String {
+    if message.sender_verified {
+        format!("{} \u{2713}", message.sender)
+    } else {
+        message.sender.clone()
+    }
+}
+
+fn message_details(message: &BitchatMessage) -> String {
+    let mut details = Vec::new();
+    if let Some(status) = message.signature_status.filter(|s| *s != SignatureStatus::Valid) {
+        details.push(status.get_display_text().to_string());
+    }
+    if let Some(hop_count) = message.hop_count {
+        details.push(format!("{} hops", hop_count));
+    }
+    if let Some(route) = message.route.as_ref().filter(|r| !r.is_empty()) {
+        let route: Vec<String> = route.iter().map(|hop| short_peer_id(hop)).collect();
+        details.push(format!("via {}", route.join(" > ")));
+    }
+    match &message.delivery_status {
+        Some(DeliveryStatus::Sending) | None => {}
+        Some(status) => details.push(status.get_display_text()),
+    }
+
+    if details.is_empty() {
+        String::new()
+    } else {
+        format!("  [{}]", details.join(", "))
+    }
+}
+
+/// Reads a line from the terminal without echoing it. Esc or Ctrl-C aborts.
+pub fn read_passphrase(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    enable_raw_mode()?;
+    let mut passphrase = String::new();
+    let result = loop {
+        match event::read() {
+            Ok(Event::Key(key)) => match key.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Esc => break Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+                }
+                KeyCode::Char(c) => passphrase.push(c),
+                KeyCode::Backspace => {
+                    passphrase.pop();
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    disable_raw_mode()?;
+    println!();
+    result.map(|_| passphrase)
+}
+
+pub async fn run_ui(
+    mut rx: mpsc::Receiver<MeshEvent>,
+    command_tx: mpsc::Sender<Command>,
+) -> Result<(), io::Error> {
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -40,58 +237,139 @@ pub async fn run_ui(mut rx: mpsc::Receiver<BitchatMessage>) -> Result<(), io::Er
     let mut app_state = AppState::new();
 
     loop {
-        if let Ok(message) = rx.try_recv() {
-            app_state.messages.push(message);
+        // Everything queued since the last frame, so a busy mesh cannot outpace the UI.
+        while let Ok(event) = rx.try_recv() {
+            app_state.handle_event(event);
         }
         terminal.draw(|f| {
+            let mut constraints = Vec::new();
+            if app_state.emergency_banner.is_some() {
+                constraints.push(Constraint::Length(3));
+            }
+            constraints.extend([Constraint::Percentage(80), Constraint::Percentage(10)]);
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .margin(1)
-                .constraints(
-                    [
-                        Constraint::Percentage(80),
-                        Constraint::Percentage(10),
-                    ]
-                    .as_ref(),
-                )
+                .constraints(constraints)
                 .split(f.size());
+            let (messages_area, input_area) = (chunks[chunks.len() - 2], chunks[chunks.len() - 1]);
+
+            let emergency_style = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+            if let Some(banner) = &app_state.emergency_banner {
+                let banner = Paragraph::new(banner.as_str())
+                    .style(emergency_style)
+                    .block(Block::default().borders(Borders::ALL).title("!! EMERGENCY !!"));
+                f.render_widget(banner, chunks[0]);
+            }
 
             let messages: Vec<ListItem> = app_state
                 .messages
                 .iter()
-                .map(|m| {
-                    let content = Text::from(format!("{}: {}", m.sender, m.content));
+                .map(|line| {
+                    let content = match line {
+                        ChatLine::Message(m) if m.is_emergency => Spans::from(vec![
+                            Span::styled(format!("!! EMERGENCY !! {}: {}", sender_label(m), m.content), emergency_style),
+                            Span::styled(message_details(m), Style::default().fg(Color::DarkGray)),
+                        ]),
+                        ChatLine::Message(m) if m.is_private => Spans::from(vec![
+                            Span::styled(
+                                format!(
+                                    "[DM] {} -> {}: {}",
+                                    sender_label(m),
+                                    m.recipient_nickname.as_deref().unwrap_or("?"),
+                                    m.content
+                                ),
+                                Style::default().fg(Color::Magenta),
+                            ),
+                            Span::styled(message_details(m), Style::default().fg(Color::DarkGray)),
+                        ]),
+                        ChatLine::Message(m) if m.group.is_some() => Spans::from(vec![
+                            Span::styled(
+                                format!("[{}] {}: {}", m.group.as_deref().unwrap_or_default(), sender_label(m), m.content),
+                                Style::default().fg(Color::Green),
+                            ),
+                            Span::styled(message_details(m), Style::default().fg(Color::DarkGray)),
+                        ]),
+                        ChatLine::Message(m) => Spans::from(vec![
+                            Span::raw(match &m.channel {
+                                Some(channel) => format!("[{}] {}: {}", channel, sender_label(m), m.content),
+                                None => format!("{}: {}", sender_label(m), m.content),
+                            }),
+                            Span::styled(message_details(m), Style::default().fg(Color::DarkGray)),
+                        ]),
+                        ChatLine::Warning(text) => Spans::from(Span::styled(
+                            format!("!! {}", text),
+                            Style::default().fg(Color::White).bg(Color::Red).add_modifier(Modifier::BOLD),
+                        )),
+                        ChatLine::Notice(text) => Spans::from(Span::styled(
+                            format!("* {}", text),
+                            Style::default().fg(Color::Cyan),
+                        )),
+                    };
                     ListItem::new(content)
                 })
                 .collect();
 
             let messages =
                 List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-            f.render_widget(messages, chunks[0]);
+            f.render_widget(messages, messages_area);
 
-            let input = Paragraph::new(app_state.input.as_ref())
-                .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::ALL).title("Input"));
-            f.render_widget(input, chunks[1]);
+            let input = match (&app_state.pending_emergency, &app_state.pending_passphrase) {
+                (Some(content), _) => Paragraph::new(content.as_str())
+                    .style(emergency_style)
+                    .block(Block::default().borders(Borders::ALL).title("Send EMERGENCY broadcast to everyone? (y/n)")),
+                (None, Some(prompt)) => Paragraph::new("*".repeat(app_state.input.chars().count()))
+                    .style(Style::default().fg(Color::Yellow))
+                    .block(Block::default().borders(Borders::ALL).title(match prompt.first_entry {
+                        Some(_) => "Repeat the backup passphrase (Esc to cancel)",
+                        None => "Backup passphrase (Esc to cancel)",
+                    })),
+                (None, None) => Paragraph::new(app_state.input.as_ref())
+                    .style(Style::default().fg(Color::Yellow))
+                    .block(Block::default().borders(Borders::ALL).title("Input")),
+            };
+            f.render_widget(input, input_area);
         })?;
 
-        if event::poll(std::time::Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
+        if event::poll(std::time::Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+        {
+            if app_state.pending_emergency.is_some() {
+                let confirmed = matches!(key.code, KeyCode::Char('y') | KeyCode::Char('Y'));
+                app_state.confirm_emergency(confirmed, &command_tx);
+                continue;
+            }
+            if app_state.pending_passphrase.is_some() {
                 match key.code {
-                    KeyCode::Char('q') => break,
-                    KeyCode::Char(c) => {
-                        app_state.input.push(c);
+                    KeyCode::Enter => app_state.submit_passphrase(&command_tx),
+                    KeyCode::Esc => {
+                        app_state.pending_passphrase = None;
+                        app_state.input.clear();
                     }
+                    KeyCode::Char(c) => app_state.input.push(c),
                     KeyCode::Backspace => {
                         app_state.input.pop();
                     }
-                    KeyCode::Enter => {
-                        let message = BitchatMessage::new("Me".to_string(), app_state.input.clone());
-                        app_state.messages.push(message);
-                        app_state.input.clear();
-                    }
                     _ => {}
                 }
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => break,
+                // Panic button: wipes everything at once, no questions asked.
+                KeyCode::F(12) => {
+                    app_state.send_command(Command::Panic, &command_tx);
+                }
+                KeyCode::Char(c) => {
+                    app_state.input.push(c);
+                }
+                KeyCode::Backspace => {
+                    app_state.input.pop();
+                }
+                KeyCode::Enter => {
+                    app_state.submit_input(&command_tx);
+                }
+                _ => {}
             }
         }
     }